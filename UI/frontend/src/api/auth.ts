@@ -9,6 +9,54 @@ export interface LoginResponse {
 }
 
 
+interface RegisterChallenge {
+  challenge: string
+  difficulty: number
+  algorithm: string
+  expires_at: string
+}
+
+const leadingZeroBits = (bytes: Uint8Array): number => {
+  let bits = 0
+  for (const byte of bytes) {
+    if (byte === 0) {
+      bits += 8
+      continue
+    }
+    return bits + Math.clz32(byte) - 24
+  }
+  return bits
+}
+
+// Finds a nonce so that SHA-256 of "<challenge>:<nonce>" starts with `difficulty` zero bits
+const solveChallenge = async ({ challenge, difficulty }: RegisterChallenge): Promise<string> => {
+  const encoder = new TextEncoder()
+  const batch = 1000
+  for (let start = 0; ; start += batch) {
+    const nonces = Array.from({ length: batch }, (_, i) => String(start + i))
+    const digests = await Promise.all(
+      nonces.map((nonce) => crypto.subtle.digest('SHA-256', encoder.encode(`${challenge}:${nonce}`)))
+    )
+    const found = digests.findIndex((digest) => leadingZeroBits(new Uint8Array(digest)) >= difficulty)
+    if (found !== -1) {
+      return nonces[found]
+    }
+  }
+}
+
+// The server answers 404 when registration needs no proof-of-work
+const proofOfWork = async (): Promise<{ pow_challenge?: string, pow_nonce?: string }> => {
+  try {
+    const { data } = await axios.get<RegisterChallenge>('http://192.168.1.45:3000/register/challenge')
+    return { pow_challenge: data.challenge, pow_nonce: await solveChallenge(data) }
+  } catch (err) {
+    if ((err as AxiosError).response?.status === 404) {
+      return {}
+    }
+    throw err
+  }
+}
+
 export const register = async (credentials: {
   username: string
   password: string
@@ -16,7 +64,7 @@ export const register = async (credentials: {
   try {
     const response = await axios.post(
       'http://192.168.1.45:3000/register',
-      credentials,
+      { ...credentials, ...(await proofOfWork()) },
       {
         headers: {
           'Content-Type' : 'application/json',
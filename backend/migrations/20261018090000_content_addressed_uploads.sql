@@ -0,0 +1,21 @@
+-- Uploaded files are stored once per distinct content, keyed by SHA-256
+CREATE TABLE blobs (
+    sha256 TEXT PRIMARY KEY,
+    size_bytes BIGINT NOT NULL,
+    extension TEXT NOT NULL DEFAULT '',
+    ref_count INTEGER NOT NULL DEFAULT 0 CHECK (ref_count >= 0),
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+-- Every upload creates an attachment pointing at a (possibly shared) blob
+CREATE TABLE attachments (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    blob_sha256 TEXT NOT NULL REFERENCES blobs(sha256),
+    uploader_id UUID REFERENCES users(id) ON DELETE SET NULL,
+    original_name TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX attachments_blob_sha256_idx ON attachments (blob_sha256);
+CREATE INDEX blobs_unreferenced_idx ON blobs (updated_at) WHERE ref_count = 0;
@@ -0,0 +1,13 @@
+-- Server side state for resumable (chunked) uploads
+CREATE TABLE upload_sessions (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    file_name TEXT NOT NULL,
+    total_size BIGINT NOT NULL CHECK (total_size >= 0),
+    received_bytes BIGINT NOT NULL DEFAULT 0 CHECK (received_bytes >= 0),
+    expected_sha256 TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    expires_at TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX upload_sessions_expires_at_idx ON upload_sessions (expires_at);
@@ -0,0 +1,8 @@
+-- Malware scan state of each stored blob; downloads are only served once 'clean'.
+-- Blobs stored before scanning existed are treated as clean.
+ALTER TABLE blobs ADD COLUMN scan_status TEXT NOT NULL DEFAULT 'clean'
+    CHECK (scan_status IN ('pending', 'clean', 'infected'));
+ALTER TABLE blobs ADD COLUMN scan_signature TEXT;
+ALTER TABLE blobs ADD COLUMN scanned_at TIMESTAMPTZ;
+
+CREATE INDEX blobs_pending_scan_idx ON blobs (created_at) WHERE scan_status = 'pending';
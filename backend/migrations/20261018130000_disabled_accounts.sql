@@ -0,0 +1,2 @@
+-- Disabled accounts keep their data but can no longer log in or use existing tokens
+ALTER TABLE users ADD COLUMN disabled_at TIMESTAMPTZ;
@@ -0,0 +1,12 @@
+-- Accounts locked after too many failed logins, kept for administrators to review.
+-- Not a foreign key: unknown usernames are locked the same way as real ones
+CREATE TABLE login_lockouts (
+    id UUID PRIMARY KEY,
+    username TEXT NOT NULL,
+    ip_address TEXT,
+    failures INTEGER NOT NULL,
+    locked_until TIMESTAMPTZ NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX login_lockouts_created_at_idx ON login_lockouts (created_at DESC);
@@ -0,0 +1,40 @@
+-- Messages point at their sender and recipient by id instead of by name, so users
+-- can be renamed. Deleting a user takes the messages they sent along, direct
+-- messages they received stay with their sender
+ALTER TABLE messages ADD COLUMN sender_id UUID REFERENCES users(id) ON DELETE CASCADE;
+ALTER TABLE messages ADD COLUMN target_user_id UUID REFERENCES users(id) ON DELETE SET NULL;
+
+-- Names were stored as typed. The exact spelling wins, then a case-insensitive
+-- match. Of several of those the first by name wins, the same account that keeps
+-- the canonical name when usernames are normalized
+UPDATE messages SET sender_id = COALESCE(
+    (SELECT users.id FROM users WHERE users.username = messages.sender),
+    (
+        SELECT users.id FROM users
+        WHERE LOWER(users.username) = LOWER(messages.sender)
+        ORDER BY users.username, users.id
+        LIMIT 1
+    )
+);
+UPDATE messages SET target_user_id = COALESCE(
+    (SELECT users.id FROM users WHERE users.username = messages.target_username),
+    (
+        SELECT users.id FROM users
+        WHERE LOWER(users.username) = LOWER(messages.target_username)
+        ORDER BY users.username, users.id
+        LIMIT 1
+    )
+)
+WHERE message_type = 'dm';
+
+-- Messages sent by or to names without an account keep the name as it was stored
+-- and no id. No client showed them before and none does now, but they are kept and
+-- exported
+ALTER TABLE messages RENAME COLUMN sender TO legacy_sender;
+ALTER TABLE messages RENAME COLUMN target_username TO legacy_target_username;
+ALTER TABLE messages ALTER COLUMN legacy_sender DROP NOT NULL;
+UPDATE messages SET legacy_sender = NULL WHERE sender_id IS NOT NULL;
+UPDATE messages SET legacy_target_username = NULL WHERE target_user_id IS NOT NULL;
+
+CREATE INDEX messages_sender_id_idx ON messages (sender_id);
+CREATE INDEX messages_target_user_id_idx ON messages (target_user_id);
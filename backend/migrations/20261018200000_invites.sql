@@ -0,0 +1,18 @@
+-- Codes admins hand out for registering while registration is invite-only. Only a
+-- SHA-256 of the code is stored, it is shown once when the invite is created
+CREATE TABLE invites (
+    id UUID PRIMARY KEY,
+    code_hash TEXT NOT NULL UNIQUE,
+    created_by UUID REFERENCES users(id) ON DELETE SET NULL,
+    max_uses INTEGER NOT NULL CHECK (max_uses > 0),
+    uses INTEGER NOT NULL DEFAULT 0 CHECK (uses >= 0),
+    expires_at TIMESTAMPTZ,
+    revoked_at TIMESTAMPTZ,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+-- Who invited whom, NULL for users who registered without an invite
+ALTER TABLE users ADD COLUMN invited_by UUID REFERENCES users(id) ON DELETE SET NULL;
+ALTER TABLE users ADD COLUMN invite_id UUID REFERENCES invites(id) ON DELETE SET NULL;
+
+CREATE INDEX users_invite_id_idx ON users (invite_id);
@@ -0,0 +1,8 @@
+-- The blob a message's upload points at, so the janitor finds attachments that were
+-- never sent with an index lookup instead of matching every URL
+ALTER TABLE messages ADD COLUMN upload_sha256 TEXT;
+
+UPDATE messages SET upload_sha256 = substring(upload_url from '/uploads/([0-9a-f]{64})(\.[^/]*)?$')
+WHERE upload_url LIKE '%/uploads/%';
+
+CREATE INDEX messages_upload_sha256_idx ON messages (upload_sha256, sender_id) WHERE upload_sha256 IS NOT NULL;
@@ -0,0 +1,18 @@
+-- SQLite version of ../20250502140530_init.sql. UUIDs are stored as 16 byte blobs
+-- and timestamps as RFC 3339 text, both written by the server.
+CREATE TABLE users (
+    id BLOB PRIMARY KEY NOT NULL,
+    username TEXT NOT NULL UNIQUE,
+    password_hash TEXT NOT NULL,
+    avatar_url TEXT DEFAULT '/images/default-avatar.png'
+);
+
+CREATE TABLE messages (
+    id BLOB PRIMARY KEY NOT NULL,
+    sender TEXT NOT NULL,
+    target_username TEXT, -- NULL for public messages
+    message_type TEXT NOT NULL CHECK (message_type IN ('chat', 'dm')),
+    message TEXT NOT NULL,
+    upload_url TEXT,
+    timestamp TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%f+00:00', 'now'))
+);
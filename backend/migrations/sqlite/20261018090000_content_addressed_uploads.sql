@@ -0,0 +1,20 @@
+-- Uploaded files are stored once per distinct content, keyed by SHA-256
+CREATE TABLE blobs (
+    sha256 TEXT PRIMARY KEY NOT NULL,
+    size_bytes INTEGER NOT NULL,
+    extension TEXT NOT NULL DEFAULT '',
+    ref_count INTEGER NOT NULL DEFAULT 0 CHECK (ref_count >= 0),
+    created_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%f+00:00', 'now')),
+    updated_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%f+00:00', 'now'))
+);
+
+CREATE TABLE attachments (
+    id BLOB PRIMARY KEY NOT NULL,
+    blob_sha256 TEXT NOT NULL REFERENCES blobs(sha256),
+    uploader_id BLOB REFERENCES users(id) ON DELETE SET NULL,
+    original_name TEXT NOT NULL,
+    created_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%f+00:00', 'now'))
+);
+
+CREATE INDEX attachments_blob_sha256_idx ON attachments (blob_sha256);
+CREATE INDEX blobs_unreferenced_idx ON blobs (updated_at) WHERE ref_count = 0;
@@ -0,0 +1,13 @@
+-- Server side state for resumable (chunked) uploads
+CREATE TABLE upload_sessions (
+    id BLOB PRIMARY KEY NOT NULL,
+    user_id BLOB NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    file_name TEXT NOT NULL,
+    total_size INTEGER NOT NULL CHECK (total_size >= 0),
+    received_bytes INTEGER NOT NULL DEFAULT 0 CHECK (received_bytes >= 0),
+    expected_sha256 TEXT,
+    created_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%f+00:00', 'now')),
+    expires_at TEXT NOT NULL
+);
+
+CREATE INDEX upload_sessions_expires_at_idx ON upload_sessions (expires_at);
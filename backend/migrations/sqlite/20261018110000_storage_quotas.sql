@@ -0,0 +1,15 @@
+-- Roles, so admins can manage other users' settings
+ALTER TABLE users ADD COLUMN role TEXT NOT NULL DEFAULT 'user' CHECK (role IN ('user', 'admin'));
+
+-- Bytes uploaded per user and an optional per user override of the default quota
+ALTER TABLE users ADD COLUMN storage_used_bytes INTEGER NOT NULL DEFAULT 0 CHECK (storage_used_bytes >= 0);
+ALTER TABLE users ADD COLUMN storage_quota_bytes INTEGER CHECK (storage_quota_bytes >= 0);
+
+UPDATE users SET storage_used_bytes = COALESCE((
+    SELECT SUM(blobs.size_bytes)
+    FROM attachments
+    JOIN blobs ON blobs.sha256 = attachments.blob_sha256
+    WHERE attachments.uploader_id = users.id
+), 0);
+
+CREATE INDEX attachments_uploader_id_idx ON attachments (uploader_id);
@@ -0,0 +1,12 @@
+-- Accounts locked after too many failed logins, kept for administrators to review.
+-- Not a foreign key: unknown usernames are locked the same way as real ones
+CREATE TABLE login_lockouts (
+    id BLOB PRIMARY KEY,
+    username TEXT NOT NULL,
+    ip_address TEXT,
+    failures INTEGER NOT NULL,
+    locked_until TEXT NOT NULL,
+    created_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%f+00:00', 'now'))
+);
+
+CREATE INDEX login_lockouts_created_at_idx ON login_lockouts (created_at DESC);
@@ -0,0 +1,17 @@
+-- TOTP two-factor authentication. The secret is stored on enrollment and only
+-- enforced once totp_enabled_at is set. totp_last_step is the newest time step a
+-- code was accepted for, so a code cannot be used twice
+ALTER TABLE users ADD COLUMN totp_secret TEXT;
+ALTER TABLE users ADD COLUMN totp_enabled_at TEXT;
+ALTER TABLE users ADD COLUMN totp_last_step INTEGER;
+
+-- Single use codes for when the authenticator is lost, hashed like passwords
+CREATE TABLE recovery_codes (
+    id BLOB PRIMARY KEY,
+    user_id BLOB NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    code_hash TEXT NOT NULL,
+    used_at TEXT,
+    created_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%f+00:00', 'now'))
+);
+
+CREATE INDEX recovery_codes_user_id_idx ON recovery_codes (user_id);
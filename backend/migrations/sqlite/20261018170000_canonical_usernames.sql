@@ -0,0 +1,9 @@
+-- Usernames are unique after NFKC normalization and case folding, so "Bob" and
+-- "bob" are one account. username keeps the spelling shown to people. Existing
+-- rows are filled in by the server on startup, which has the Unicode tables
+ALTER TABLE users ADD COLUMN username_canonical TEXT;
+-- UTS #39 skeleton of the canonical name, for rejecting lookalikes of existing names
+ALTER TABLE users ADD COLUMN username_skeleton TEXT;
+
+CREATE UNIQUE INDEX users_username_canonical_idx ON users (username_canonical);
+CREATE INDEX users_username_skeleton_idx ON users (username_skeleton);
@@ -0,0 +1,57 @@
+-- SQLite version of ../20261018180000_message_user_ids.sql. Foreign keys can't be
+-- added in place here, so the table is rebuilt.
+CREATE TABLE messages_new (
+    id BLOB PRIMARY KEY NOT NULL,
+    -- NULL only for old messages from a name without an account, see legacy_sender
+    sender_id BLOB REFERENCES users(id) ON DELETE CASCADE,
+    target_user_id BLOB REFERENCES users(id) ON DELETE SET NULL, -- NULL for public messages
+    message_type TEXT NOT NULL CHECK (message_type IN ('chat', 'dm')),
+    message TEXT NOT NULL,
+    upload_url TEXT,
+    timestamp TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%f+00:00', 'now')),
+    legacy_sender TEXT,
+    legacy_target_username TEXT
+);
+
+-- Names were stored as typed. The exact spelling wins, then a case-insensitive
+-- match. Of several of those the first by name wins, the same account that keeps
+-- the canonical name when usernames are normalized. Messages sent by or to names
+-- without an account keep the name as it was stored and no id. No client showed
+-- them before and none does now, but they are kept and exported.
+INSERT INTO messages_new (
+    id, sender_id, target_user_id, message_type, message, upload_url, timestamp,
+    legacy_sender, legacy_target_username
+)
+SELECT
+    id, sender_id, target_user_id, message_type, message, upload_url, timestamp,
+    CASE WHEN sender_id IS NULL THEN sender END,
+    CASE WHEN target_user_id IS NULL THEN target_username END
+FROM (
+    SELECT
+        messages.*,
+        COALESCE(
+            (SELECT users.id FROM users WHERE users.username = messages.sender),
+            (
+                SELECT users.id FROM users
+                WHERE LOWER(users.username) = LOWER(messages.sender)
+                ORDER BY users.username, users.id
+                LIMIT 1
+            )
+        ) AS sender_id,
+        CASE WHEN messages.message_type = 'dm' THEN COALESCE(
+            (SELECT users.id FROM users WHERE users.username = messages.target_username),
+            (
+                SELECT users.id FROM users
+                WHERE LOWER(users.username) = LOWER(messages.target_username)
+                ORDER BY users.username, users.id
+                LIMIT 1
+            )
+        ) END AS target_user_id
+    FROM messages
+);
+
+DROP TABLE messages;
+ALTER TABLE messages_new RENAME TO messages;
+
+CREATE INDEX messages_sender_id_idx ON messages (sender_id);
+CREATE INDEX messages_target_user_id_idx ON messages (target_user_id);
@@ -0,0 +1,10 @@
+-- Profile fields users edit themselves, all optional
+ALTER TABLE users ADD COLUMN display_name TEXT;
+ALTER TABLE users ADD COLUMN bio TEXT;
+ALTER TABLE users ADD COLUMN pronouns TEXT;
+ALTER TABLE users ADD COLUMN status_text TEXT;
+ALTER TABLE users ADD COLUMN status_emoji TEXT;
+-- The status is hidden from then on, NULL keeps it until it's changed
+ALTER TABLE users ADD COLUMN status_expires_at TEXT;
+-- IANA name such as Europe/Berlin
+ALTER TABLE users ADD COLUMN timezone TEXT;
@@ -0,0 +1,20 @@
+-- Accounts run by integrations rather than people. They have no usable password
+-- and sign in with API tokens only
+ALTER TABLE users ADD COLUMN is_bot INTEGER NOT NULL DEFAULT 0;
+
+-- Long lived tokens for integrations, limited to a set of scopes. Only a SHA-256
+-- of the token is stored, it is shown once when the token is created
+CREATE TABLE api_tokens (
+    id BLOB PRIMARY KEY,
+    user_id BLOB NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    name TEXT NOT NULL,
+    token_hash TEXT NOT NULL UNIQUE,
+    -- Space separated, such as "messages:read messages:write"
+    scopes TEXT NOT NULL,
+    expires_at TEXT,
+    last_used_at TEXT,
+    revoked_at TEXT,
+    created_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%f+00:00', 'now'))
+);
+
+CREATE INDEX api_tokens_user_id_idx ON api_tokens (user_id);
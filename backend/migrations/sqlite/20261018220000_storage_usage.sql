@@ -0,0 +1,8 @@
+-- Bytes taken by all blobs together, in a single row so the global quota can be
+-- checked and charged with one UPDATE, the way per user quotas are
+CREATE TABLE storage_usage (
+    id INTEGER PRIMARY KEY DEFAULT 1 CHECK (id = 1),
+    blob_bytes INTEGER NOT NULL DEFAULT 0 CHECK (blob_bytes >= 0)
+);
+
+INSERT INTO storage_usage (id, blob_bytes) SELECT 1, COALESCE(SUM(size_bytes), 0) FROM blobs;
@@ -0,0 +1,11 @@
+-- The blob a message's upload points at, so the janitor finds attachments that were
+-- never sent with an index lookup instead of matching every URL
+ALTER TABLE messages ADD COLUMN upload_sha256 TEXT;
+
+UPDATE messages SET upload_sha256 = substr(upload_url, instr(upload_url, '/uploads/') + 9, 64)
+WHERE instr(upload_url, '/uploads/') > 0
+AND length(substr(upload_url, instr(upload_url, '/uploads/') + 9, 64)) = 64
+AND substr(upload_url, instr(upload_url, '/uploads/') + 9, 64) NOT GLOB '*[^0-9a-f]*'
+AND substr(upload_url, instr(upload_url, '/uploads/') + 73, 1) IN ('', '.');
+
+CREATE INDEX messages_upload_sha256_idx ON messages (upload_sha256, sender_id) WHERE upload_sha256 IS NOT NULL;
@@ -0,0 +1,7 @@
+-- Accounts whose name collided with an older one when usernames were normalized.
+-- They keep their canonical name and skeleton, so lookalike checks still see them,
+-- but are left out of the uniqueness constraint until they are renamed
+ALTER TABLE users ADD COLUMN username_needs_rename BOOLEAN NOT NULL DEFAULT FALSE;
+
+DROP INDEX users_username_canonical_idx;
+CREATE UNIQUE INDEX users_username_canonical_idx ON users (username_canonical) WHERE NOT username_needs_rename;
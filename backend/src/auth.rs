@@ -1,20 +1,27 @@
-use std::env;
+use rand::Rng;
 use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use chrono::{Utc, Duration};
 use uuid::Uuid;
 
+use crate::config::AuthConfig;
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Claims {
     pub sub: Uuid,
     pub exp: usize,
     pub username: String,
-    avatar_url: Option<String>
+    avatar_url: Option<String>,
+    /// The user's token version when this was issued, see [`crate::models::User::token_version`].
+    /// Tokens from before versions existed count as version 0.
+    #[serde(default)]
+    pub ver: i32
 }
 
-pub fn create_jwt(user: &crate::models::User) -> Result<String, jsonwebtoken::errors::Error> {
+pub fn create_jwt(user: &crate::models::User, config: &AuthConfig) -> Result<String, jsonwebtoken::errors::Error> {
     let expiration = Utc::now()
-        .checked_add_signed(Duration::hours(24))
+        .checked_add_signed(Duration::hours(config.token_ttl_hours))
         .expect("valid timestamp")
         .timestamp();
 
@@ -22,36 +29,123 @@ pub fn create_jwt(user: &crate::models::User) -> Result<String, jsonwebtoken::er
         sub: user.id,
         exp: expiration as usize,
         avatar_url: Some(user.avatar_url.clone()),
-        username: user.username.clone()
+        username: user.username.clone(),
+        ver: user.token_version
     };
 
-    let secret = std::env::var("JWT_SECRET").unwrap_or_else(|_| "your-secret-key".to_string());
-
     encode(
         &Header::default(),
         &claims,
-        &EncodingKey::from_secret(secret.as_ref()),
+        &EncodingKey::from_secret(config.jwt_secret.as_ref()),
     )
     
 }
 
 
-pub fn decode_jwt(token: &str) -> Result<Claims, String> {
-    let secret = env::var("JWT_SECRET")
-        .map_err(|_| "JWT_SECRET not set in environment".to_string())?;
-
+pub fn decode_jwt(token: &str, config: &AuthConfig) -> Result<Claims, jsonwebtoken::errors::Error> {
     let mut validation = Validation::new(Algorithm::HS256);
     validation.validate_exp = true;
 
     decode::<Claims>(
         token,
-        &DecodingKey::from_secret(secret.as_ref()),
+        &DecodingKey::from_secret(config.jwt_secret.as_ref()),
         &validation,
     )
     .map(|data| data.claims)
-    .map_err(|e| match e.kind() {
-        jsonwebtoken::errors::ErrorKind::ExpiredSignature => "Token expired".to_string(),
-        jsonwebtoken::errors::ErrorKind::InvalidToken => "Invalid token".to_string(),
-        _ => format!("Token validation failed: {}", e),
-    })
-}
\ No newline at end of file
+}
+
+
+/// Audience of challenge tokens. Regular tokens carry no audience, and `decode_jwt`
+/// rejects any token that has one, so a challenge can't be used as a session.
+const CHALLENGE_AUDIENCE: &str = "brochat-2fa";
+
+/// How long the second login step may take.
+pub const CHALLENGE_TTL_MINUTES: i64 = 5;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ChallengeClaims {
+    sub: Uuid,
+    exp: usize,
+    aud: String,
+}
+
+/// Short lived token proving the password was right, exchanged for a real token
+/// together with a TOTP or recovery code.
+pub fn create_challenge(user_id: Uuid, config: &AuthConfig) -> Result<String, jsonwebtoken::errors::Error> {
+    let claims = ChallengeClaims {
+        sub: user_id,
+        exp: (Utc::now() + Duration::minutes(CHALLENGE_TTL_MINUTES)).timestamp() as usize,
+        aud: CHALLENGE_AUDIENCE.to_string(),
+    };
+
+    encode(&Header::default(), &claims, &EncodingKey::from_secret(config.jwt_secret.as_ref()))
+}
+
+/// The user a challenge token was issued to.
+pub fn decode_challenge(token: &str, config: &AuthConfig) -> Result<Uuid, jsonwebtoken::errors::Error> {
+    let mut validation = Validation::new(Algorithm::HS256);
+    validation.set_audience(&[CHALLENGE_AUDIENCE]);
+    validation.set_required_spec_claims(&["exp", "aud", "sub"]);
+
+    decode::<ChallengeClaims>(token, &DecodingKey::from_secret(config.jwt_secret.as_ref()), &validation)
+        .map(|data| data.claims.sub)
+}
+
+
+/// Random token for a password reset link.
+pub fn generate_reset_token() -> String {
+    let bytes: [u8; 32] = rand::random();
+    hex::encode(bytes)
+}
+
+/// What is stored for a reset token. A plain hash is enough since the token is
+/// random, unlike passwords it can't be guessed from a list.
+pub fn hash_reset_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.trim().as_bytes()))
+}
+
+/// Letters and digits that can't be mistaken for each other when read out or typed
+/// from paper, no `0`/`O` or `1`/`I`.
+const INVITE_ALPHABET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+
+const INVITE_GROUP_LEN: usize = 4;
+const INVITE_GROUPS: usize = 4;
+
+/// Random invite code such as `7KQX-M2PA-HZ4C-RW9D`, 80 bits.
+pub fn generate_invite_code() -> String {
+    let mut rng = rand::thread_rng();
+    let mut group = || -> String {
+        (0..INVITE_GROUP_LEN)
+            .map(|_| INVITE_ALPHABET[rng.gen_range(0..INVITE_ALPHABET.len())] as char)
+            .collect()
+    };
+
+    (0..INVITE_GROUPS).map(|_| group()).collect::<Vec<_>>().join("-")
+}
+
+/// What is stored for an invite code. Case, dashes and spaces don't matter, so codes
+/// can be typed however they were written down.
+pub fn hash_invite_code(code: &str) -> String {
+    let normalized: String = code
+        .chars()
+        .filter(char::is_ascii_alphanumeric)
+        .map(|c| c.to_ascii_uppercase())
+        .collect();
+    hex::encode(Sha256::digest(normalized.as_bytes()))
+}
+
+
+/// Start of every API token, so they can be told apart from session tokens and
+/// found by secret scanners.
+pub const API_TOKEN_PREFIX: &str = "bct_";
+
+/// Random API token, 256 bits after the prefix.
+pub fn generate_api_token() -> String {
+    let bytes: [u8; 32] = rand::random();
+    format!("{}{}", API_TOKEN_PREFIX, hex::encode(bytes))
+}
+
+/// What is stored for an API token, a plain hash like for reset tokens.
+pub fn hash_api_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.trim().as_bytes()))
+}
@@ -0,0 +1,278 @@
+use std::io::{BufRead, IsTerminal, Write};
+use std::path::PathBuf;
+use clap::{Parser, Subcommand};
+
+use backend::config::Config;
+use backend::db::{self, Database};
+use backend::models::{Export, ImportSummary, ServerStats, User};
+use backend::repository::{PgRepository, SqliteRepository};
+use backend::username;
+use backend::utils::hash_password;
+
+/// Operations on a BroChat server's database. Reads the same configuration as the
+/// server (`BROCHAT_CONFIG`, `brochat.toml`, `DATABASE_URL`, ...).
+#[derive(Parser)]
+#[command(name = "brochat-admin", version)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Create a user, asking for the password
+    CreateUser {
+        username: String,
+        /// Give the new user the admin role
+        #[arg(long)]
+        admin: bool,
+        /// Read the password from the first line of stdin instead of prompting
+        #[arg(long)]
+        password_stdin: bool,
+    },
+    /// Set a new password for a user, signing out their sessions
+    ResetPassword {
+        username: String,
+        #[arg(long)]
+        password_stdin: bool,
+    },
+    /// Give a user a new name, their messages move along
+    RenameUser {
+        username: String,
+        new_username: String,
+    },
+    /// List accounts from before usernames were normalized that share a name, such as
+    /// `Bob` and `bob`. Rename the ones marked as needing it with `rename-user`
+    UsernameCollisions,
+    /// Change a user's role
+    SetRole {
+        username: String,
+        #[arg(value_parser = ["user", "admin"])]
+        role: String,
+    },
+    /// Block a user from logging in, their existing tokens stop working too
+    Disable { username: String },
+    /// Undo `disable`
+    Enable { username: String },
+    /// Turn off two-factor authentication for a user who lost their authenticator and recovery codes
+    #[command(name = "disable-2fa")]
+    Disable2fa { username: String },
+    /// Delete every message a user has sent
+    PurgeMessages {
+        username: String,
+        /// Don't ask for confirmation
+        #[arg(long)]
+        yes: bool,
+    },
+    /// Write users and messages as JSON
+    Export {
+        /// File to write, stdout when omitted
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+    },
+    /// Load users and messages written by `export`, skipping ones that already exist
+    Import { file: PathBuf },
+    /// Print row counts and storage usage
+    Stats,
+}
+
+#[tokio::main]
+async fn main() {
+    let cli = Cli::parse();
+
+    if let Err(e) = run(cli.command).await {
+        eprintln!("error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+/// Whole database operations that only the admin tool needs, so they live on the
+/// concrete stores rather than the repository traits.
+enum Store {
+    Postgres(PgRepository),
+    Sqlite(SqliteRepository),
+}
+
+impl Store {
+    fn new(database: &Database) -> Self {
+        match database {
+            Database::Postgres(pool) => Store::Postgres(PgRepository::new(pool.clone())),
+            Database::Sqlite(pool) => Store::Sqlite(SqliteRepository::new(pool.clone())),
+        }
+    }
+
+    async fn stats(&self) -> Result<ServerStats, sqlx::Error> {
+        match self {
+            Store::Postgres(repo) => repo.stats().await,
+            Store::Sqlite(repo) => repo.stats().await,
+        }
+    }
+
+    async fn export(&self, schema_version: i64) -> Result<Export, sqlx::Error> {
+        match self {
+            Store::Postgres(repo) => repo.export(schema_version).await,
+            Store::Sqlite(repo) => repo.export(schema_version).await,
+        }
+    }
+
+    async fn import(&self, export: &Export) -> Result<ImportSummary, sqlx::Error> {
+        match self {
+            Store::Postgres(repo) => repo.import(export).await,
+            Store::Sqlite(repo) => repo.import(export).await,
+        }
+    }
+}
+
+type CliResult = Result<(), Box<dyn std::error::Error>>;
+
+async fn run(command: Command) -> CliResult {
+    let config = Config::read()?;
+    let database = db::connect(&config.database).await?;
+    database.check_schema_version().await?;
+    let repos = database.repositories();
+    let store = Store::new(&database);
+
+    match command {
+        Command::CreateUser { username, admin, password_stdin } => {
+            let username = username::normalize(&username);
+            let password = read_password(password_stdin)?;
+            let user = repos.users.create(&username, &hash(&password)?).await?
+                .ok_or_else(|| format!("{} is already taken", username))?;
+            if admin {
+                repos.users.set_role(&username, "admin").await?;
+            }
+            println!("Created user {} ({})", user.username, user.id);
+        }
+        Command::ResetPassword { username, password_stdin } => {
+            let password = read_password(password_stdin)?;
+            let user = repos.users.set_password(&username, &hash(&password)?).await?;
+            found(user, &username)?;
+            println!("Password for {} has been reset", username);
+        }
+        Command::RenameUser { username, new_username } => {
+            let new_username = username::normalize(&new_username);
+            let user = found(repos.users.find_by_username(&username).await?, &username)?;
+            repos.users.rename(user.id, &new_username).await?
+                .ok_or_else(|| format!("{} is already taken", new_username))?;
+            println!("Renamed {} to {}", user.username, new_username);
+        }
+        Command::UsernameCollisions => {
+            let collisions = username::collisions(repos.users.as_ref()).await?;
+            if collisions.is_empty() {
+                println!("No usernames collide");
+            }
+            for names in collisions {
+                let names: Vec<_> = names
+                    .into_iter()
+                    .map(|(name, needs_rename)| if needs_rename { format!("{} (needs rename)", name) } else { name })
+                    .collect();
+                println!("{}", names.join(", "));
+            }
+        }
+        Command::SetRole { username, role } => {
+            found(repos.users.set_role(&username, &role).await?, &username)?;
+            println!("{} is now {}", username, role);
+        }
+        Command::Disable { username } => {
+            found(repos.users.set_disabled(&username, true).await?, &username)?;
+            println!("Disabled {}", username);
+        }
+        Command::Enable { username } => {
+            found(repos.users.set_disabled(&username, false).await?, &username)?;
+            println!("Enabled {}", username);
+        }
+        Command::Disable2fa { username } => {
+            let user = found(repos.users.find_by_username(&username).await?, &username)?;
+            if repos.two_factor.disable_totp(user.id).await? {
+                println!("Disabled two-factor authentication for {}", username);
+            } else {
+                println!("{} has no two-factor authentication set up", username);
+            }
+        }
+        Command::PurgeMessages { username, yes } => {
+            if !yes && !confirm(&format!("Delete every message sent by {}?", username))? {
+                println!("Aborted");
+                return Ok(());
+            }
+            let user = found(repos.users.find_by_username(&username).await?, &username)?;
+            let deleted = repos.messages.delete_by_sender(user.id).await?;
+            println!("Deleted {} messages", deleted);
+        }
+        Command::Export { output } => export(&store, output).await?,
+        Command::Import { file } => {
+            let export: Export = serde_json::from_str(&std::fs::read_to_string(&file)?)?;
+            if export.schema_version > db::latest_known_version() {
+                return Err(format!(
+                    "{} was exported from a newer schema ({}), upgrade brochat-admin first",
+                    file.display(), export.schema_version
+                ).into());
+            }
+            let summary = store.import(&export).await?;
+            println!(
+                "Imported {} of {} users and {} of {} messages",
+                summary.users, export.users.len(), summary.messages, export.messages.len()
+            );
+        }
+        Command::Stats => {
+            let stats = store.stats().await?;
+            println!("{}", serde_json::to_string_pretty(&stats)?);
+        }
+    }
+
+    Ok(())
+}
+
+async fn export(store: &Store, output: Option<PathBuf>) -> CliResult {
+    let export = store.export(db::latest_known_version()).await?;
+    let json = serde_json::to_string_pretty(&export)?;
+
+    match output {
+        Some(path) => {
+            std::fs::write(&path, json)?;
+            eprintln!(
+                "Exported {} users and {} messages to {}",
+                export.users.len(), export.messages.len(), path.display()
+            );
+        }
+        None => println!("{}", json),
+    }
+
+    Ok(())
+}
+
+fn hash(password: &str) -> Result<String, String> {
+    hash_password(password).map_err(|e| format!("password hashing failed: {}", e))
+}
+
+fn found(user: Option<User>, username: &str) -> Result<User, String> {
+    user.ok_or_else(|| format!("no user named {}", username))
+}
+
+/// Prompts twice without echo, or reads one line from stdin for scripts.
+fn read_password(from_stdin: bool) -> Result<String, Box<dyn std::error::Error>> {
+    let password = if from_stdin || !std::io::stdin().is_terminal() {
+        let mut line = String::new();
+        std::io::stdin().lock().read_line(&mut line)?;
+        line.trim_end_matches(['\r', '\n']).to_string()
+    } else {
+        let password = rpassword::prompt_password("Password: ")?;
+        if rpassword::prompt_password("Repeat password: ")? != password {
+            return Err("passwords do not match".into());
+        }
+        password
+    };
+
+    if password.is_empty() {
+        return Err("password must not be empty".into());
+    }
+    Ok(password)
+}
+
+fn confirm(question: &str) -> std::io::Result<bool> {
+    eprint!("{} [y/N] ", question);
+    std::io::stderr().flush()?;
+
+    let mut answer = String::new();
+    std::io::stdin().lock().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
@@ -0,0 +1,387 @@
+use std::fmt;
+use axum::extract::multipart::MultipartError;
+use axum::extract::rejection::JsonRejection;
+use axum::extract::{FromRequest, Request};
+use axum::http::{header, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use jsonwebtoken::errors::ErrorKind as JwtErrorKind;
+use serde_json::{json, Map, Value};
+
+use crate::models::{ApiScope, ScanStatus};
+use crate::policy::{PasswordProblem, UsernameProblem};
+use crate::pow::PowProblem;
+use crate::storage::StorageError;
+
+/// Every error a handler or the WebSocket can report. HTTP responses carry
+/// `{"status": "error", "code": ..., "message": ...}` plus error specific fields, and
+/// WebSocket error frames carry the same `code` and `message`.
+#[derive(Debug)]
+pub enum AppError {
+    /// The request is malformed, the message says how.
+    BadRequest(String),
+    /// The JSON body is missing, malformed or has the wrong shape.
+    InvalidJson(JsonRejection),
+    /// Reading a multipart body failed.
+    Multipart(MultipartError),
+    /// A required multipart field is missing.
+    MissingField(&'static str),
+    /// No `Authorization: Bearer` header was sent.
+    MissingToken,
+    InvalidToken,
+    ExpiredToken,
+    InvalidCredentials,
+    /// The account was disabled by an administrator.
+    AccountDisabled,
+    /// Too many failed logins or reset requests from this username or address, retry later.
+    TooManyAttempts { retry_after_secs: u64 },
+    /// The username is locked after repeated failed logins.
+    AccountLocked { retry_after_secs: u64 },
+    /// The TOTP or recovery code is wrong or was already used.
+    InvalidTwoFactorCode,
+    TwoFactorAlreadyEnabled,
+    TwoFactorNotEnabled,
+    /// The password reset token is unknown, used or expired.
+    InvalidResetToken,
+    /// `registration.mode` is `closed`.
+    RegistrationClosed,
+    /// `registration.mode` is `invite_only` and no invite code was sent.
+    InviteRequired,
+    /// The invite code is unknown, revoked, expired or used up.
+    InvalidInvite,
+    /// Registration needs a solved challenge from `/register/challenge`.
+    ProofOfWorkRequired,
+    InvalidProofOfWork(PowProblem),
+    /// The API token was not granted the scope this needs.
+    InsufficientScope(ApiScope),
+    /// Only a logged in session may do this, not an API token.
+    SessionRequired,
+    Forbidden,
+    /// The named resource does not exist.
+    NotFound(&'static str),
+    UsernameTaken,
+    InvalidUsername(UsernameProblem),
+    WeakPassword(PasswordProblem),
+    /// A direct message was sent without a recipient.
+    MissingRecipient,
+    /// The size declared for a resumable upload is negative or above the limit.
+    UploadSizeOutOfRange { max_size: i64 },
+    /// The requested upload has not been cleared by the malware scanner yet.
+    FileScanPending,
+    /// The requested upload was found to be malware.
+    FileQuarantined,
+    /// The server is draining connections before it exits.
+    ShuttingDown,
+    Storage(StorageError),
+    Database(sqlx::Error),
+    /// Something went wrong that the client cannot do anything about. The message is
+    /// only logged.
+    Internal(String),
+}
+
+impl AppError {
+    /// Stable machine readable code, clients should match on this rather than the message.
+    pub fn code(&self) -> &'static str {
+        match self {
+            AppError::BadRequest(_) => "bad_request",
+            AppError::InvalidJson(_) => "invalid_json",
+            AppError::Multipart(_) => "invalid_multipart",
+            AppError::MissingField(_) => "missing_field",
+            AppError::MissingToken => "missing_token",
+            AppError::InvalidToken => "invalid_token",
+            AppError::ExpiredToken => "expired_token",
+            AppError::InvalidCredentials => "invalid_credentials",
+            AppError::AccountDisabled => "account_disabled",
+            AppError::TooManyAttempts { .. } => "too_many_attempts",
+            AppError::AccountLocked { .. } => "account_locked",
+            AppError::InvalidTwoFactorCode => "invalid_two_factor_code",
+            AppError::TwoFactorAlreadyEnabled => "two_factor_already_enabled",
+            AppError::TwoFactorNotEnabled => "two_factor_not_enabled",
+            AppError::InvalidResetToken => "invalid_reset_token",
+            AppError::RegistrationClosed => "registration_closed",
+            AppError::InviteRequired => "invite_required",
+            AppError::InvalidInvite => "invalid_invite",
+            AppError::ProofOfWorkRequired => "pow_required",
+            AppError::InvalidProofOfWork(_) => "invalid_pow",
+            AppError::InsufficientScope(_) => "insufficient_scope",
+            AppError::SessionRequired => "session_required",
+            AppError::Forbidden => "forbidden",
+            AppError::NotFound(_) => "not_found",
+            AppError::UsernameTaken => "username_taken",
+            AppError::InvalidUsername(_) => "invalid_username",
+            AppError::WeakPassword(_) => "weak_password",
+            AppError::MissingRecipient => "missing_recipient",
+            AppError::UploadSizeOutOfRange { .. } => "upload_too_large",
+            AppError::FileScanPending => "file_scan_pending",
+            AppError::FileQuarantined => "file_quarantined",
+            AppError::Storage(e) => match e {
+                StorageError::SessionNotFound => "upload_not_found",
+                StorageError::OffsetMismatch { .. } => "upload_offset_mismatch",
+                StorageError::ExceedsDeclaredSize => "upload_too_large",
+                StorageError::Incomplete { .. } => "upload_incomplete",
+                StorageError::ChecksumMismatch => "upload_checksum_mismatch",
+                StorageError::QuotaExceeded { .. } => "quota_exceeded",
+                StorageError::GlobalQuotaExceeded => "storage_full",
+                StorageError::Infected { .. } => "file_infected",
+                StorageError::Io(_) | StorageError::Database(_) => "storage_error",
+            },
+            AppError::ShuttingDown => "shutting_down",
+            AppError::Database(_) => "database_error",
+            AppError::Internal(_) => "internal_error",
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            AppError::BadRequest(_)
+            | AppError::MissingField(_)
+            | AppError::MissingRecipient
+            | AppError::InvalidResetToken
+            | AppError::InvalidInvite
+            | AppError::ProofOfWorkRequired
+            | AppError::InvalidProofOfWork(_)
+            | AppError::InvalidUsername(_)
+            | AppError::WeakPassword(_) => StatusCode::BAD_REQUEST,
+            AppError::InvalidJson(e) => e.status(),
+            AppError::Multipart(e) => e.status(),
+            AppError::MissingToken
+            | AppError::InvalidToken
+            | AppError::ExpiredToken
+            | AppError::InvalidCredentials
+            | AppError::InvalidTwoFactorCode => StatusCode::UNAUTHORIZED,
+            AppError::AccountDisabled
+            | AppError::Forbidden
+            | AppError::RegistrationClosed
+            | AppError::InviteRequired
+            | AppError::InsufficientScope(_)
+            | AppError::SessionRequired
+            | AppError::FileQuarantined => StatusCode::FORBIDDEN,
+            AppError::NotFound(_) => StatusCode::NOT_FOUND,
+            AppError::UsernameTaken
+            | AppError::TwoFactorAlreadyEnabled
+            | AppError::TwoFactorNotEnabled => StatusCode::CONFLICT,
+            AppError::TooManyAttempts { .. } | AppError::AccountLocked { .. } => StatusCode::TOO_MANY_REQUESTS,
+            AppError::UploadSizeOutOfRange { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            AppError::FileScanPending => StatusCode::LOCKED,
+            AppError::Storage(e) => match e {
+                StorageError::SessionNotFound => StatusCode::NOT_FOUND,
+                StorageError::OffsetMismatch { .. } | StorageError::Incomplete { .. } => StatusCode::CONFLICT,
+                StorageError::ExceedsDeclaredSize | StorageError::QuotaExceeded { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+                StorageError::ChecksumMismatch | StorageError::Infected { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+                StorageError::GlobalQuotaExceeded => StatusCode::INSUFFICIENT_STORAGE,
+                StorageError::Io(_) | StorageError::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            },
+            AppError::ShuttingDown => StatusCode::SERVICE_UNAVAILABLE,
+            AppError::Database(_) | AppError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Human readable message that is safe to show to the client.
+    pub fn message(&self) -> String {
+        match self {
+            AppError::BadRequest(msg) => msg.clone(),
+            AppError::InvalidJson(e) => e.body_text(),
+            AppError::Multipart(e) => format!("Failed to read multipart form data: {}", e.body_text()),
+            AppError::MissingField(field) => format!("Missing field: {}", field),
+            AppError::MissingToken => "Missing bearer token".to_string(),
+            AppError::InvalidToken => "Invalid token".to_string(),
+            AppError::ExpiredToken => "Token expired".to_string(),
+            AppError::InvalidCredentials => "Invalid credentials".to_string(),
+            AppError::AccountDisabled => "This account has been disabled".to_string(),
+            AppError::TooManyAttempts { .. } => "Too many attempts, try again later".to_string(),
+            AppError::AccountLocked { .. } => "Account is temporarily locked after too many failed logins".to_string(),
+            AppError::InvalidTwoFactorCode => "Invalid two-factor code".to_string(),
+            AppError::TwoFactorAlreadyEnabled => "Two-factor authentication is already enabled".to_string(),
+            AppError::TwoFactorNotEnabled => "Two-factor authentication is not enabled".to_string(),
+            AppError::InvalidResetToken => "This password reset link is invalid or has expired".to_string(),
+            AppError::RegistrationClosed => "Registration is closed".to_string(),
+            AppError::InviteRequired => "Registration needs an invite code".to_string(),
+            AppError::InvalidInvite => "This invite code is invalid, used up or has expired".to_string(),
+            AppError::ProofOfWorkRequired => "Registration needs a solved challenge from /register/challenge".to_string(),
+            AppError::InvalidProofOfWork(problem) => problem.to_string(),
+            AppError::InsufficientScope(scope) => format!("This token lacks the {} scope", scope.as_str()),
+            AppError::SessionRequired => "API tokens can't be used for this".to_string(),
+            AppError::Forbidden => "You are not allowed to do this".to_string(),
+            AppError::NotFound(what) => format!("{} not found", what),
+            AppError::UsernameTaken => "Username already exists".to_string(),
+            AppError::InvalidUsername(problem) => problem.to_string(),
+            AppError::WeakPassword(problem) => problem.to_string(),
+            AppError::MissingRecipient => "Direct messages need a recipient".to_string(),
+            AppError::UploadSizeOutOfRange { .. } => "Upload size is out of range".to_string(),
+            AppError::FileScanPending => "File is still being scanned".to_string(),
+            AppError::FileQuarantined => "File has been quarantined".to_string(),
+            AppError::Storage(e) => match e {
+                StorageError::SessionNotFound => "Upload not found".to_string(),
+                StorageError::OffsetMismatch { .. } => "Upload offset mismatch".to_string(),
+                StorageError::ExceedsDeclaredSize => "Chunk exceeds the declared upload size".to_string(),
+                StorageError::Incomplete { .. } => "Upload is incomplete".to_string(),
+                StorageError::ChecksumMismatch => "Upload checksum mismatch, the upload has been discarded".to_string(),
+                StorageError::QuotaExceeded { .. } => "Storage quota exceeded".to_string(),
+                StorageError::GlobalQuotaExceeded => "Server storage is full".to_string(),
+                StorageError::Infected { .. } => "File was rejected by the malware scanner".to_string(),
+                StorageError::Io(_) | StorageError::Database(_) => "Failed to store upload".to_string(),
+            },
+            AppError::ShuttingDown => "Server is restarting".to_string(),
+            AppError::Database(_) => "Database query failed".to_string(),
+            AppError::Internal(_) => "Internal server error".to_string(),
+        }
+    }
+
+    /// Extra fields merged into the HTTP error body.
+    fn details(&self) -> Option<Value> {
+        match self {
+            AppError::Storage(StorageError::OffsetMismatch { expected }) => Some(json!({ "offset": expected })),
+            AppError::Storage(StorageError::Incomplete { received, total }) => {
+                Some(json!({ "offset": received, "size": total }))
+            }
+            AppError::Storage(StorageError::QuotaExceeded { used, quota, requested }) => Some(json!({
+                "used_bytes": used,
+                "quota_bytes": quota,
+                "requested_bytes": requested
+            })),
+            AppError::Storage(StorageError::Infected { signature }) => Some(json!({ "signature": signature })),
+            AppError::UploadSizeOutOfRange { max_size } => Some(json!({ "max_size": max_size })),
+            AppError::FileScanPending => Some(json!({ "scan_status": ScanStatus::Pending })),
+            AppError::FileQuarantined => Some(json!({ "scan_status": ScanStatus::Infected })),
+            AppError::MissingField(field) => Some(json!({ "field": field })),
+            AppError::InvalidUsername(problem) => Some(json!({ "reason": problem.reason() })),
+            AppError::InvalidProofOfWork(problem) => Some(json!({ "reason": problem.reason() })),
+            AppError::InsufficientScope(scope) => Some(json!({ "scope": scope })),
+            AppError::WeakPassword(problem) => match problem {
+                PasswordProblem::TooShort { min } => Some(json!({ "reason": problem.reason(), "min_length": min })),
+                PasswordProblem::TooLong { max } => Some(json!({ "reason": problem.reason(), "max_length": max })),
+                PasswordProblem::TooWeak { score, min, .. } => {
+                    Some(json!({ "reason": problem.reason(), "score": score, "min_score": min }))
+                }
+                PasswordProblem::Breached => Some(json!({ "reason": problem.reason() })),
+            },
+            AppError::TooManyAttempts { retry_after_secs } | AppError::AccountLocked { retry_after_secs } => {
+                Some(json!({ "retry_after_secs": retry_after_secs }))
+            }
+            _ => None,
+        }
+    }
+
+    /// Value for the `Retry-After` header.
+    fn retry_after(&self) -> Option<u64> {
+        match self {
+            AppError::TooManyAttempts { retry_after_secs } | AppError::AccountLocked { retry_after_secs } => {
+                Some(*retry_after_secs)
+            }
+            _ => None,
+        }
+    }
+
+    fn is_server_error(&self) -> bool {
+        self.status().is_server_error()
+    }
+
+    /// Error frame sent over the WebSocket, with the same codes as the HTTP API.
+    pub fn ws_frame(&self) -> String {
+        json!({
+            "type": "error",
+            "code": self.code(),
+            "message": self.message()
+        }).to_string()
+    }
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AppError::Multipart(e) => write!(f, "multipart error: {}", e),
+            AppError::Storage(e) => write!(f, "{}", e),
+            AppError::Database(e) => write!(f, "database error: {}", e),
+            AppError::Internal(msg) => write!(f, "internal error: {}", msg),
+            _ => write!(f, "{}", self.message()),
+        }
+    }
+}
+
+impl std::error::Error for AppError {}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        if self.is_server_error() {
+            tracing::error!(code = self.code(), error = %self, "request failed");
+        }
+
+        let mut body = Map::new();
+        body.insert("status".into(), "error".into());
+        body.insert("code".into(), self.code().into());
+        body.insert("message".into(), self.message().into());
+        if let Some(Value::Object(details)) = self.details() {
+            body.extend(details);
+        }
+
+        let mut response = (self.status(), Json(Value::Object(body))).into_response();
+        if let Some(secs) = self.retry_after() {
+            response.headers_mut().insert(header::RETRY_AFTER, HeaderValue::from(secs));
+        }
+        response
+    }
+}
+
+impl From<sqlx::Error> for AppError {
+    fn from(e: sqlx::Error) -> Self {
+        match &e {
+            sqlx::Error::RowNotFound => AppError::NotFound("Record"),
+            _ => AppError::Database(e),
+        }
+    }
+}
+
+impl From<StorageError> for AppError {
+    fn from(e: StorageError) -> Self {
+        AppError::Storage(e)
+    }
+}
+
+impl From<JsonRejection> for AppError {
+    fn from(e: JsonRejection) -> Self {
+        AppError::InvalidJson(e)
+    }
+}
+
+impl From<MultipartError> for AppError {
+    fn from(e: MultipartError) -> Self {
+        AppError::Multipart(e)
+    }
+}
+
+impl From<jsonwebtoken::errors::Error> for AppError {
+    fn from(e: jsonwebtoken::errors::Error) -> Self {
+        match e.kind() {
+            JwtErrorKind::ExpiredSignature => AppError::ExpiredToken,
+            // Signing only fails on a broken key, which is our fault rather than the client's
+            JwtErrorKind::InvalidKeyFormat | JwtErrorKind::InvalidRsaKey(_) | JwtErrorKind::InvalidEcdsaKey => {
+                AppError::Internal(format!("JWT key error: {}", e))
+            }
+            _ => AppError::InvalidToken,
+        }
+    }
+}
+
+impl From<argon2::password_hash::Error> for AppError {
+    fn from(e: argon2::password_hash::Error) -> Self {
+        AppError::Internal(format!("password hashing failed: {}", e))
+    }
+}
+
+
+/// `Json` extractor whose rejection is an [`AppError`], so malformed bodies get the
+/// same error shape as everything else.
+pub struct JsonBody<T>(pub T);
+
+impl<S, T> FromRequest<S> for JsonBody<T>
+where
+    Json<T>: FromRequest<S, Rejection = JsonRejection>,
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let Json(value) = Json::<T>::from_request(req, state).await?;
+        Ok(JsonBody(value))
+    }
+}
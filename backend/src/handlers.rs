@@ -1,18 +1,39 @@
 // handlers.rs
 use std::collections::HashSet;
+use std::net::{IpAddr, SocketAddr};
 use std::path::PathBuf;
 use std::sync::Arc;
-use axum::extract::{Multipart, Path};
+use axum::body::Bytes;
+use axum::extract::{ConnectInfo, Multipart, Path};
+use axum::http::HeaderMap;
 use axum::Extension;
 use axum::{extract::State, http::StatusCode, Json};
 use axum::response::IntoResponse;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 use tokio::sync::RwLock;
 use uuid::Uuid;
-use crate::models::AuthenticatedUser;
-use crate::ws::ChatState;
-use crate::{auth::create_jwt, db::get_pool, models::{MessageModel, User}, utils::{hash_password, verify_password}, ws::SharedChatState};
+use crate::error::{AppError, JsonBody};
+use crate::auth::{
+    create_challenge, decode_challenge, generate_api_token, generate_invite_code, generate_reset_token, hash_api_token,
+    hash_invite_code, hash_reset_token, CHALLENGE_TTL_MINUTES,
+};
+use crate::config::{Config, RegistrationMode};
+use crate::models::{ApiScope, ApiScopes, ApiToken, AuthenticatedUser, NewAccount, Profile, ScanStatus, TwoFactor, UploadSession, UserList};
+use crate::repository::{InvitedRegistration, Repositories};
+use crate::janitor;
+use crate::metrics::metrics;
+use crate::policy::UsernameProblem;
+use crate::pow::Challenge;
+use crate::profile::ProfileUpdate;
+use crate::ratelimit::{LoginLimiter, NewLockout, Throttled};
+use crate::scanner::{self, ClamdScanner};
+use crate::telemetry;
+use crate::totp;
+use crate::username;
+use crate::storage::{self, StorageError, StorageQuota, StoredUpload};
+use crate::ws::{ChatState, SocketCredential};
+use crate::{auth::create_jwt, models::{MessageModel, User}, utils::{client_ip, dummy_password_hash, hash_password, verify_password}, ws::SharedChatState};
 use axum::{
     body::Body,
     http::Request,
@@ -28,126 +49,434 @@ pub struct AuthPayload {
 }
 
 
-pub async fn register(Json(payload): Json<AuthPayload>) -> Result<Json<User>, (StatusCode, Json<Value>)> {
+#[derive(Deserialize)]
+pub struct RegisterPayload {
+    pub username: String,
+    pub password: String,
+    /// Needed while registration is invite-only, checked whenever it's sent.
+    #[serde(default)]
+    pub invite_code: Option<String>,
+    /// From `/register/challenge`, needed unless `registration.pow_difficulty` is 0.
+    #[serde(default)]
+    pub pow_challenge: Option<String>,
+    #[serde(default)]
+    pub pow_nonce: Option<String>,
+}
+
+
+/// The repositories handlers read and write through.
+async fn repos(state: &SharedChatState) -> Repositories {
+    state.read().await.repos.clone()
+}
+
+
+pub async fn register(
+    State(state): State<SharedChatState>,
+    JsonBody(payload): JsonBody<RegisterPayload>
+) -> Result<Json<NewAccount>, AppError> {
+
+    let (repos, password_policy, username_policy, mode, proof_of_work) = {
+        let state = state.read().await;
+        (
+            state.repos.clone(),
+            state.password_policy.clone(),
+            state.username_policy.clone(),
+            state.config.registration.mode,
+            state.proof_of_work.clone(),
+        )
+    };
+
+    let invite_code = payload.invite_code.as_deref().map(str::trim).filter(|code| !code.is_empty());
+    match (mode, invite_code) {
+        (RegistrationMode::Closed, _) => return Err(AppError::RegistrationClosed),
+        (RegistrationMode::InviteOnly, None) => return Err(AppError::InviteRequired),
+        _ => {}
+    }
+
+    // Checked before anything that costs the server more than a hash, but only used
+    // up once the request is valid, so a taken name doesn't cost the client a new one
+    let solution = match &proof_of_work {
+        Some(proof_of_work) => {
+            let (Some(challenge), Some(nonce)) = (payload.pow_challenge.as_deref(), payload.pow_nonce.as_deref()) else {
+                return Err(AppError::ProofOfWorkRequired);
+            };
+            Some(proof_of_work.verify(challenge.trim(), nonce.trim()).map_err(AppError::InvalidProofOfWork)?)
+        }
+        None => None,
+    };
+
+    let username = username::normalize(&payload.username);
+    username_policy.check(&username).map_err(AppError::InvalidUsername)?;
+    password_policy.check(&payload.password, &[&username]).await.map_err(AppError::WeakPassword)?;
+    check_username_available(&repos, &username, None).await?;
 
-    let pool = get_pool().await;
-    let hashed = hash_password(&payload.password)
-        .map_err(|_e| {
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(json!({"error": "Password hashing failed"})),
-            )
-        })?;
+    if let (Some(proof_of_work), Some(solution)) = (&proof_of_work, solution) {
+        proof_of_work.redeem(solution).map_err(AppError::InvalidProofOfWork)?;
+    }
+
+    let hashed = hash_password(&payload.password)?;
+
+    let created = match invite_code {
+        Some(code) => match repos.invites.register_with_invite(&username, &hashed, &hash_invite_code(code)).await {
+            Ok(InvitedRegistration::Created(user)) => Ok(Some(user)),
+            Ok(InvitedRegistration::UsernameTaken) => Ok(None),
+            Ok(InvitedRegistration::InvalidInvite) => return Err(AppError::InvalidInvite),
+            Err(e) => Err(e),
+        },
+        None => repos.users.create(&username, &hashed).await,
+    };
 
-    match User::create(&pool, &payload.username, &hashed).await {
-        Ok(user) => Ok(Json(user)),
+    match created {
+        Ok(Some(user)) => Ok(Json(user.into())),
+        Ok(None) => Err(AppError::UsernameTaken),
         Err(e) => {
-            eprintln!("Registration failed: {:?}", e);
-            if let sqlx::Error::Database(db_err) = &e {
-                if db_err.is_unique_violation() {
-                    return Err((
-                        StatusCode::CONFLICT,
-                        Json(json!({
-                            "status": "error",
-                            "message": "Username already exists !!"
-                        })),
-                    ));
-                }
-            }
-            Err((
-                StatusCode::BAD_REQUEST,
-                Json(json!({
-                    "status": "error",
-                    "message": "Registration Failed"
-                })),
-            ))
+            tracing::warn!(error = %e, "registration failed");
+            Err(e.into())
         }
     }
 }
 
 
-pub async fn get_meapi(
-    Extension(user): Extension<AuthenticatedUser>
-) -> (StatusCode, Json<Value>) {
+/// A fresh proof-of-work challenge for `/register`, `404` when none is needed.
+pub async fn registration_challenge(State(state): State<SharedChatState>) -> Result<Json<Challenge>, AppError> {
+    let proof_of_work = state.read().await.proof_of_work.clone().ok_or(AppError::NotFound("Challenge"))?;
+    Ok(Json(proof_of_work.issue()?))
+}
+
+
+/// Rejects a name that is taken or looks like a taken one. `current_user` may pick
+/// a name that looks like its own, to change the case of it for example.
+async fn check_username_available(
+    repos: &Repositories,
+    username: &str,
+    current_user: Option<Uuid>,
+) -> Result<(), AppError> {
+    if let Some(existing) = repos.users.find_by_skeleton(&username::skeleton(username)).await?
+        && Some(existing.id) != current_user
+    {
+        return Err(if username::canonical(&existing.username) == username::canonical(username) {
+            AppError::UsernameTaken
+        } else {
+            AppError::InvalidUsername(UsernameProblem::Confusable)
+        });
+    }
+    Ok(())
+}
+
+
+/// Liveness probe, answers as long as the process is serving requests.
+pub async fn healthz() -> Json<Value> {
+    Json(json!({ "status": "ok" }))
+}
+
+
+/// How long the readiness probe waits for the database.
+const READINESS_DB_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(2);
+
+/// Readiness probe, fails while the database is unreachable or the server is draining
+/// so load balancers stop sending traffic.
+pub async fn readyz(State(state): State<SharedChatState>) -> (StatusCode, Json<Value>) {
+    let (repos, shutting_down) = {
+        let state = state.read().await;
+        (state.repos.clone(), state.shutting_down)
+    };
+
+    let database = match tokio::time::timeout(READINESS_DB_TIMEOUT, repos.backend.ping()).await {
+        Ok(Ok(())) => Ok(()),
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(_) => Err("timed out".to_string()),
+    };
+
+    let ready = database.is_ok() && !shutting_down;
+    let status = if ready { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    if let Err(e) = &database {
+        tracing::warn!(error = %e, "readiness check failed");
+    }
+
     (
-        StatusCode::OK,
+        status,
         Json(json!({
-            "id": user.id,
-            "username": user.username,
-            "avatar_url": user.avatar_url
-        }))
+            "status": if ready { "ok" } else { "unavailable" },
+            "checks": {
+                "database": database.err().unwrap_or_else(|| "ok".to_string()),
+                "shutting_down": shutting_down
+            }
+        })),
     )
 }
 
 
-pub async fn login(
-    Json(payload): Json<AuthPayload>
-) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
-    let pool = get_pool().await;
+/// The caller's own profile, which also carries their role.
+#[derive(Serialize)]
+pub struct MyProfile {
+    #[serde(flatten)]
+    pub profile: Profile,
+    pub role: String,
+}
 
-    let user = match User::find_by_username(&pool, &payload.username).await {
-        Ok(user) => user,
-        Err(e) => {
-            eprintln!("ERROR {}", e);
-            return Err((
-                StatusCode::UNAUTHORIZED,
-                Json(json!({
-                    "status": "error",
-                    "message": "Invalid credentials"
-                })),
-            ))
-        }
+pub async fn get_meapi(
+    State(state): State<SharedChatState>,
+    Extension(user): Extension<AuthenticatedUser>
+) -> Result<Json<MyProfile>, AppError> {
+    let repos = repos(&state).await;
+    let profile = repos.users.profile(user.id).await?.ok_or(AppError::NotFound("User"))?;
+
+    Ok(Json(MyProfile { profile: profile.without_expired_status(chrono::Utc::now()), role: user.role }))
+}
+
+
+/// Changes the caller's profile fields and pushes the result to every client.
+pub async fn update_me(
+    State(state): State<SharedChatState>,
+    Extension(user): Extension<AuthenticatedUser>,
+    JsonBody(payload): JsonBody<ProfileUpdate>
+) -> Result<Json<MyProfile>, AppError> {
+    let repos = repos(&state).await;
+    let now = chrono::Utc::now();
+
+    // An expired status is gone, a new emoji alone must not bring back its text
+    let current = repos.users.profile(user.id).await?.ok_or(AppError::NotFound("User"))?;
+    let mut fields = current.without_expired_status(now).fields;
+    payload.apply(&mut fields, now)?;
+
+    let profile = repos.users.set_profile(user.id, &fields).await?.ok_or(AppError::NotFound("User"))?;
+    tracing::info!(user_id = %user.id, "profile updated");
+    state.read().await.broadcast_profile(&profile);
+
+    Ok(Json(MyProfile { profile: profile.without_expired_status(now), role: user.role }))
+}
+
+
+pub async fn get_user_profile(
+    State(state): State<SharedChatState>,
+    Path(username): Path<String>,
+) -> Result<Json<Profile>, AppError> {
+    let repos = repos(&state).await;
+    let user = repos.users.find_by_username(&username).await?.ok_or(AppError::NotFound("User"))?;
+    let profile = repos.users.profile(user.id).await?.ok_or(AppError::NotFound("User"))?;
+
+    Ok(Json(profile.without_expired_status(chrono::Utc::now())))
+}
+
+
+pub async fn login(
+    State(state): State<SharedChatState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    JsonBody(payload): JsonBody<AuthPayload>
+) -> Result<Json<Value>, AppError> {
+    let (repos, config, limiter) = {
+        let state = state.read().await;
+        (state.repos.clone(), state.config.clone(), state.login_limiter.clone())
     };
+    let ip = client_ip(&headers, peer, config.server.trust_forwarded_for);
 
-    // Verify password
-    match verify_password(&payload.password, &user.password_hash) {
-        Ok(true) => {
-            let token = create_jwt(&user).map_err(|_| {
-                (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    Json(json!({"status": "error", "message": "Token generation failed"})),
-                )
-            })?;
-            
-            Ok(Json(json!({
-                "status": "success",
-                "token": token,
-                "user_id": user.id,
-                "username": user.username
-            })))
-        }
-        Ok(false) => {
-            Err((
-                StatusCode::UNAUTHORIZED,
-                Json(json!({
-                    "status": "error",
-                    "message": "Invalid credentials"
-                })),
-            ))
+    check_throttle(&limiter, &payload.username, ip)?;
+
+    let user = repos.users.find_by_username(&payload.username).await?;
+
+    // Unknown usernames are checked against a dummy hash, otherwise skipping Argon2
+    // would tell an attacker which usernames exist
+    let verified = match &user {
+        Some(user) => verify_password(&payload.password, &user.password_hash)?,
+        None => {
+            verify_password(&payload.password, dummy_password_hash())?;
+            false
         }
-        Err(_) => {
-            Err((
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(json!({"status": "error", "message": "Password verification failed"})),
-            ))
+    };
+    // Bots only sign in with API tokens
+    let user = match user {
+        Some(user) if verified && !user.is_bot => user,
+        _ => {
+            if let Some(lockout) = limiter.record_failure(&payload.username, Some(ip)) {
+                record_lockout(&repos, &payload.username, ip, lockout).await;
+            }
+            metrics().auth_failures.with_label_values(&[AppError::InvalidCredentials.code()]).inc();
+            return Err(AppError::InvalidCredentials);
         }
+    };
+
+    if user.is_disabled() {
+        metrics().auth_failures.with_label_values(&[AppError::AccountDisabled.code()]).inc();
+        return Err(AppError::AccountDisabled);
+    }
+
+    // The failure counter is only cleared once the second factor is checked too,
+    // otherwise knowing the password would allow unlimited code guesses
+    if repos.two_factor.two_factor(user.id).await?.is_some_and(|tf| tf.is_enabled()) {
+        let challenge = create_challenge(user.id, &config.auth)?;
+        return Ok(Json(json!({
+            "status": "two_factor_required",
+            "challenge_token": challenge,
+            "expires_in_secs": CHALLENGE_TTL_MINUTES * 60
+        })));
     }
+    limiter.record_success(&payload.username);
+
+    let token = create_jwt(&user, &config.auth)?;
+
+    Ok(Json(json!({
+        "status": "success",
+        "token": token,
+        "user_id": user.id,
+        "username": user.username,
+        "username_needs_rename": user.username_needs_rename
+    })))
 }
 
+#[derive(Deserialize)]
+pub struct TwoFactorLoginPayload {
+    pub challenge_token: String,
+    /// A TOTP code or an unused recovery code.
+    pub code: String,
+}
 
-pub async fn list_users() -> impl IntoResponse {
-   let pool = get_pool().await;
 
-   match User::find_all(&pool).await {
-        Ok(users) => {
-            (StatusCode::OK, Json(users)).into_response()
-        }
+/// Second login step for users with two-factor authentication.
+pub async fn login_two_factor(
+    State(state): State<SharedChatState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    JsonBody(payload): JsonBody<TwoFactorLoginPayload>
+) -> Result<Json<Value>, AppError> {
+    let (repos, config, limiter) = {
+        let state = state.read().await;
+        (state.repos.clone(), state.config.clone(), state.login_limiter.clone())
+    };
+    let ip = client_ip(&headers, peer, config.server.trust_forwarded_for);
 
-        Err(e) => {
-            eprintln!("Error in fetching users: {}", e);
-            (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({"error": "Database query failed"}))).into_response()
+    let user_id = decode_challenge(&payload.challenge_token, &config.auth)
+        .map_err(AppError::from)
+        .inspect_err(|e| metrics().auth_failures.with_label_values(&[e.code()]).inc())?;
+    let user = repos.users.find_by_id(user_id).await?.ok_or(AppError::InvalidToken)?;
+
+    if user.is_disabled() {
+        metrics().auth_failures.with_label_values(&[AppError::AccountDisabled.code()]).inc();
+        return Err(AppError::AccountDisabled);
+    }
+
+    require_second_factor(&repos, &limiter, &user.username, user.id, ip, &payload.code).await?;
+    limiter.record_success(&user.username);
+
+    let token = create_jwt(&user, &config.auth)?;
+
+    Ok(Json(json!({
+        "status": "success",
+        "token": token,
+        "user_id": user.id,
+        "username": user.username,
+        "username_needs_rename": user.username_needs_rename
+    })))
+}
+
+/// Turns a throttled attempt into its error before any password or code is checked.
+fn check_throttle(limiter: &LoginLimiter, username: &str, ip: IpAddr) -> Result<(), AppError> {
+    let Err(throttled) = limiter.check(username, Some(ip)) else {
+        return Ok(());
+    };
+
+    let error = match throttled {
+        Throttled::Backoff(wait) => AppError::TooManyAttempts { retry_after_secs: retry_after_secs(wait) },
+        Throttled::Locked(wait) => AppError::AccountLocked { retry_after_secs: retry_after_secs(wait) },
+    };
+    metrics().auth_failures.with_label_values(&[error.code()]).inc();
+    Err(error)
+}
+
+/// Checks a TOTP or recovery code. Wrong codes count as failed logins for the
+/// username, so they can't be guessed any faster than passwords.
+async fn require_second_factor(
+    repos: &Repositories,
+    limiter: &LoginLimiter,
+    username: &str,
+    user_id: Uuid,
+    ip: IpAddr,
+    code: &str,
+) -> Result<(), AppError> {
+    check_throttle(limiter, username, ip)?;
+
+    let two_factor = repos.two_factor.two_factor(user_id).await?.filter(|tf| tf.is_enabled());
+    let Some(two_factor) = two_factor else {
+        return Err(AppError::TwoFactorNotEnabled);
+    };
+
+    if check_second_factor(repos, user_id, &two_factor, code).await? {
+        return Ok(());
+    }
+
+    if let Some(lockout) = limiter.record_failure(username, Some(ip)) {
+        record_lockout(repos, username, ip, lockout).await;
+    }
+    metrics().auth_failures.with_label_values(&[AppError::InvalidTwoFactorCode.code()]).inc();
+    Err(AppError::InvalidTwoFactorCode)
+}
+
+/// Whether `code` is a current TOTP code or one of the unused recovery codes, and
+/// marks it as used.
+async fn check_second_factor(repos: &Repositories, user_id: Uuid, two_factor: &TwoFactor, code: &str) -> Result<bool, AppError> {
+    if totp::is_totp_code(code) {
+        let Some(secret) = &two_factor.totp_secret else {
+            return Ok(false);
+        };
+        let now = chrono::Utc::now().timestamp().max(0) as u64;
+        return match totp::matching_step(secret, code, now) {
+            Some(step) => Ok(repos.two_factor.use_totp_step(user_id, step).await?),
+            None => Ok(false),
+        };
+    }
+
+    let code = totp::normalize_recovery_code(code);
+    if code.is_empty() {
+        return Ok(false);
+    }
+    for recovery_code in repos.two_factor.unused_recovery_codes(user_id).await? {
+        if verify_password(&code, &recovery_code.code_hash)? {
+            return Ok(repos.two_factor.use_recovery_code(recovery_code.id).await?);
         }
     }
+
+    Ok(false)
+}
+
+/// Fresh recovery codes for the user, plus their hashes for storage.
+fn new_recovery_codes() -> Result<(Vec<String>, Vec<String>), AppError> {
+    let codes = totp::generate_recovery_codes();
+    let hashes = codes
+        .iter()
+        .map(|code| hash_password(&totp::normalize_recovery_code(code)))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok((codes, hashes))
+}
+
+/// Whole seconds, rounded up so clients never retry a moment too early.
+fn retry_after_secs(wait: std::time::Duration) -> u64 {
+    wait.as_secs() + u64::from(wait.subsec_nanos() > 0)
+}
+
+/// Keeps the lockout for administrators. A failure here must not turn the failed
+/// login into a server error, so it is only logged.
+async fn record_lockout(repos: &Repositories, username: &str, ip: IpAddr, lockout: NewLockout) {
+    tracing::warn!(
+        username,
+        %ip,
+        failures = lockout.failures,
+        minutes = lockout.duration.as_secs() / 60,
+        "locked account after failed logins"
+    );
+
+    let locked_until = chrono::Utc::now() + chrono::Duration::from_std(lockout.duration).unwrap_or_default();
+    let failures = i32::try_from(lockout.failures).unwrap_or(i32::MAX);
+    if let Err(e) = repos.users.record_lockout(username, Some(&ip.to_string()), failures, locked_until).await {
+        tracing::error!(error = %e, "failed to record lockout");
+    }
+}
+
+
+pub async fn list_users(State(state): State<SharedChatState>) -> Result<Json<Vec<UserList>>, AppError> {
+   let repos = repos(&state).await;
+
+   Ok(Json(repos.users.list().await?))
 }
 
 pub async fn get_dms(State(state): State<SharedChatState>) -> Json<serde_json::Value> {
@@ -157,26 +486,29 @@ pub async fn get_dms(State(state): State<SharedChatState>) -> Json<serde_json::V
     Json(json!({ "dms": usernames }))
 }
 
-pub async fn get_public_messages() -> impl IntoResponse {
-    let pool = get_pool().await;
+/// How many public messages `/public` returns.
+const PUBLIC_MESSAGES_LIMIT: i64 = 100;
 
-    match MessageModel::get_public_messages(&pool).await {
-        Ok(messages) => (StatusCode::OK, Json(messages)).into_response(),
-        Err(e) => {
-            eprintln!("DB error in public message: {}", e);
-            (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({"error": "Database query failed"}))).into_response()
-        }
-    }
-}
+pub async fn get_public_messages(State(state): State<SharedChatState>) -> Result<Json<Vec<MessageModel>>, AppError> {
+    let repos = repos(&state).await;
 
+    Ok(Json(repos.messages.public(PUBLIC_MESSAGES_LIMIT).await?))
+}
 
-pub async fn auth_middleware(mut req: Request<Body>, next: Next) -> Result<Response, StatusCode> {
-    let pool = get_pool().await;
-    let headers = req.headers();
-    println!("Headers: {:?}", headers);
 
-    let user = AuthenticatedUser::from_auth_header(headers.clone(), pool)
-        .await.map_err(|_| StatusCode::UNAUTHORIZED)?;
+pub async fn auth_middleware(
+    State(state): State<SharedChatState>,
+    mut req: Request<Body>,
+    next: Next,
+) -> Result<Response, AppError> {
+    let (repos, config) = {
+        let state = state.read().await;
+        (state.repos.clone(), state.config.clone())
+    };
+    let user = AuthenticatedUser::from_auth_header(req.headers(), &repos, &config.auth)
+        .await
+        .inspect_err(|e| metrics().auth_failures.with_label_values(&[e.code()]).inc())?;
+    tracing::Span::current().record("user_id", tracing::field::display(user.id));
 
     req.extensions_mut().insert(user);
 
@@ -185,70 +517,45 @@ pub async fn auth_middleware(mut req: Request<Body>, next: Next) -> Result<Respo
 
 
 pub async fn get_dm_messages(
+    State(state): State<SharedChatState>,
     Path(target_user): Path<String>,
     Extension(auth_user): Extension<AuthenticatedUser>,
-) -> impl IntoResponse {
-    let pool = get_pool().await;
-    let current_user = &auth_user.username;
-
-
-    if current_user != &target_user {
-        // We'll check if there's a DM between current_user and target_user
-        // This logic assumes both sides can see the conversation
-        match MessageModel::get_dm_messages(&pool, current_user, &target_user).await {
-            Ok(messages) =>
-            { 
-                        if messages.is_empty() {
-                            println!("No messages found.");
-                        } else {
-                            println!("Messages found: {:?}", messages);
-                        }
-                        println!(" MESSAGES b/w {} {} {:?}", current_user, &target_user,&messages.to_owned());
-                        (StatusCode::OK, Json(messages)).into_response()
-                
-            }
-            Err(e) => {
-                eprintln!("DB error in dm messages: {}", e);
-                (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    Json(json!({ "error": "Database query failed" }))
-                ).into_response()
-            }
-        }
-    } 
-    else {
-        (
-            StatusCode::BAD_REQUEST,
-            Json(json!({ "error": "Cannot load DMs with yourself" }))
-        ).into_response()
+) -> Result<Json<Vec<MessageModel>>, AppError> {
+    let repos = repos(&state).await;
+
+    let target = repos.users.find_by_username(&target_user).await?.ok_or(AppError::NotFound("User"))?;
+    if target.id == auth_user.id {
+        return Err(AppError::BadRequest("Cannot load DMs with yourself".to_string()));
     }
+
+    // We'll check if there's a DM between current_user and target_user
+    // This logic assumes both sides can see the conversation
+    let messages = repos.messages.direct(auth_user.id, target.id).await?;
+    tracing::debug!(target_user = %target_user, count = messages.len(), "loaded direct messages");
+
+    Ok(Json(messages))
 }
 
 
 
 pub async fn handle_uploads(
     State(state): State<Arc<RwLock<ChatState>>>,
+    Extension(auth_user): Extension<AuthenticatedUser>,
     mut multipart: Multipart,
-) -> impl IntoResponse {
-    let state = state.read().await; 
+) -> Result<Json<Value>, AppError> {
+    let target = UploadTarget::of(&state).await;
     let mut filename = None;
     let mut file_data = None;
 
     // Go through all form fields
-    while let Some(field) = multipart.next_field().await.unwrap() {
+    while let Some(field) = multipart.next_field().await? {
         let name = field.name().unwrap_or_default().to_string();
 
         match name.as_str() {
             "file" => {
                 let original_name = field.file_name().unwrap_or("file").to_string();
-                let pat = PathBuf::from(&original_name);
-                  let ext= pat.extension()
-                    .and_then(|e| e.to_str())
-                    .unwrap_or("");
-                let unique_name = format!("{}_{}.{}", Uuid::new_v4(), chrono::Utc::now().timestamp(), ext);
-
-                let data = field.bytes().await.unwrap();
-                filename = Some(unique_name);
+                let data = field.bytes().await?;
+                filename = Some(original_name);
                 file_data = Some(data);
             }
             "sender" => {
@@ -261,178 +568,1110 @@ pub async fn handle_uploads(
         }
     }
 
-    if let (Some(name), Some(data)) = (filename, file_data) {
-        let upload_dir = PathBuf::from(&state.upload_dir).join("uploads");
+    let (Some(original_name), Some(data)) = (filename, file_data) else {
+        return Err(AppError::MissingField("file"));
+    };
+
+    metrics().upload_bytes.with_label_values(&["upload"]).inc_by(data.len() as u64);
+
+    let stored = storage::store_upload(
+        &target.repos,
+        &target.root.join("uploads"),
+        &target.quota,
+        target.initial_status(),
+        Some(auth_user.id),
+        &original_name,
+        &data,
+    ).await?;
+
+    tracing::info!(
+        upload_url = %stored.upload_url(),
+        size = stored.blob.size_bytes,
+        deduplicated = stored.deduplicated,
+        "stored upload"
+    );
+    target.start_scan(&stored);
+    Ok(Json(stored_upload_json(&stored)))
+}
+
 
-        if let Err(e) = tokio::fs::create_dir_all(&upload_dir).await {
-            eprintln!("Failed to create avatar dir: {:?}", e);
-            return (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(json!({ "error": "Could not create avatar folder" })),
-            );
+fn stored_upload_json(stored: &StoredUpload) -> Value {
+    json!({
+        "status": "success",
+        "filename": stored.blob.file_name(),
+        "upload_url": stored.upload_url(),
+        "attachment_id": stored.attachment.id,
+        "sha256": stored.blob.sha256,
+        "size": stored.blob.size_bytes,
+        "deduplicated": stored.deduplicated,
+        "scan_status": stored.blob.scan_status
+    })
+}
+
+
+/// What storing an upload needs from [`ChatState`], copied out so the lock isn't held
+/// while a file is read, hashed or moved.
+struct UploadTarget {
+    repos: Repositories,
+    root: PathBuf,
+    quota: StorageQuota,
+    scanner: Option<ClamdScanner>,
+}
+
+impl UploadTarget {
+    async fn of(state: &SharedChatState) -> Self {
+        let state = state.read().await;
+        Self {
+            repos: state.repos.clone(),
+            root: state.upload_dir.clone(),
+            quota: state.storage_quota,
+            scanner: state.scanner.clone(),
         }
+    }
+
+    fn initial_status(&self) -> ScanStatus {
+        scanner::initial_status(self.scanner.as_ref())
+    }
 
-        let full_path = upload_dir.join(&name);
-        let upload_url = format!("/uploads/{}", name);
-        println!("{}", upload_url.clone());
-        match tokio::fs::write(&full_path, &data).await {
-            Ok(_) => (
-                StatusCode::OK,
-                Json(serde_json::json!({ 
-                    "status": "success", 
-                    "filename": name,
-                    "upload_url": upload_url
-                }))
-            ),
-            Err(err) => (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(serde_json::json!({ "error": err.to_string() })),
-            ),
+    /// Hands a newly stored blob to the malware scanner, if one is configured.
+    fn start_scan(&self, stored: &StoredUpload) {
+        if let Some(scanner) = &self.scanner
+            && stored.blob.scan_status == ScanStatus::Pending
+        {
+            scanner::spawn_scan(self.repos.uploads.clone(), scanner.clone(), self.root.clone(), stored.blob.clone());
         }
-    } else {
-        (
-            StatusCode::BAD_REQUEST,
-            Json(serde_json::json!({ "error": "Missing file data" })),
-        )
     }
 }
 
 
-pub async fn handle_avatar(
-    State(state): State<Arc<RwLock<ChatState>>>,
-    mut multipart: Multipart,
-) -> impl IntoResponse {
-    let pool = get_pool().await;
-    let state = state.read().await;
-    let mut filename = None;
-    let mut file_data = None;
-    let mut id: Option<Uuid> = None;
+/// Keeps files in `/uploads` from being downloaded until the scanner has cleared them.
+pub async fn upload_scan_gate(
+    State(state): State<SharedChatState>,
+    req: Request<Body>,
+    next: Next,
+) -> Result<Response, AppError> {
+    let repos = repos(&state).await;
+    let file_name = req.uri().path().rsplit('/').next().unwrap_or_default().to_string();
 
-    while let Some(field_result) = multipart.next_field().await.transpose() {
-        let field = match field_result {
-            Ok(f) => f,
-            Err(e) => {
-                eprintln!("Multipart read error: {:?}", e);
-                return (
-                    StatusCode::BAD_REQUEST,
-                    Json(json!({ "error": "Failed to read multipart form data" })),
-                );
-            }
-        };
+    match repos.uploads.find_blob_by_file_name(&file_name).await? {
+        Some(blob) => match blob.scan_status {
+            ScanStatus::Clean => Ok(next.run(req).await),
+            ScanStatus::Pending => Err(AppError::FileScanPending),
+            ScanStatus::Infected => Err(AppError::FileQuarantined),
+        },
+        // Files stored before content addressing have no blob row
+        None => Ok(next.run(req).await),
+    }
+}
 
-        let name = field.name().unwrap_or_default().to_string();
 
-        match name.as_str() {
-            "avatar" => {
-                let original_name = field.file_name().unwrap_or("file").to_string();
-                let path_buf = PathBuf::from(&original_name);
-                let ext = path_buf.extension()
-                    .and_then(|e| e.to_str())
-                    .unwrap_or("");
-
-                let unique_name = format!(
-                    "{}_{}.{}",
-                    Uuid::new_v4(),
-                    chrono::Utc::now().timestamp(),
-                    ext
-                );
-
-                let data = match field.bytes().await {
-                    Ok(d) => d,
-                    Err(e) => {
-                        eprintln!("Failed to read file bytes: {:?}", e);
-                        return (
-                            StatusCode::BAD_REQUEST,
-                            Json(json!({ "error": "Failed to read uploaded file" })),
-                        );
-                    }
-                };
-
-                filename = Some(unique_name);
-                file_data = Some(data);
-            }
+#[derive(Deserialize)]
+pub struct CreateUploadPayload {
+    pub file_name: String,
+    pub size: i64,
+    pub sha256: Option<String>,
+}
 
-            "user_id" => {
-                let val = match field.text().await {
-                    Ok(t) => t,
-                    Err(e) => {
-                        eprintln!("Failed to read user_id field: {:?}", e);
-                        return (
-                            StatusCode::BAD_REQUEST,
-                            Json(json!({ "error": "Invalid user ID" })),
-                        );
-                    }
-                };
-
-                eprintln!("Got user_id from multipart: {:?}", val);
-
-                match Uuid::parse_str(val.trim()) {
-                    Ok(uuid) => id = Some(uuid),
-                    Err(_) => {
-                        eprintln!("Invalid UUID: {}", val);
-                        return (
-                            StatusCode::BAD_REQUEST,
-                            Json(json!({ "error": "Invalid user ID" })),
-                        );
-                    }
-                }
-            }
+/// Header carrying the byte offset of a resumable upload, as in the tus protocol.
+pub const UPLOAD_OFFSET_HEADER: &str = "upload-offset";
 
-            _ => {}
-        }
+
+fn upload_session_response(status: StatusCode, session: &UploadSession) -> Response {
+    (
+        status,
+        [(UPLOAD_OFFSET_HEADER, session.received_bytes.to_string())],
+        Json(json!({
+            "id": session.id,
+            "file_name": session.file_name,
+            "size": session.total_size,
+            "offset": session.received_bytes,
+            "expires_at": session.expires_at
+        })),
+    ).into_response()
+}
+
+
+pub async fn create_resumable_upload(
+    State(state): State<SharedChatState>,
+    Extension(auth_user): Extension<AuthenticatedUser>,
+    JsonBody(payload): JsonBody<CreateUploadPayload>,
+) -> Result<Response, AppError> {
+    let (repos, partial_dir, quota) = {
+        let state = state.read().await;
+        (state.repos.clone(), state.upload_dir.join("partial_uploads"), state.storage_quota)
+    };
+
+    if payload.size < 0 || payload.size > storage::MAX_RESUMABLE_UPLOAD_SIZE {
+        return Err(AppError::UploadSizeOutOfRange { max_size: storage::MAX_RESUMABLE_UPLOAD_SIZE });
     }
 
-    eprintln!(
-        "filename: {:?}, file_data: {:?}, id: {:?}",
-        filename,
-        file_data.as_ref().map(|f| f.len()),
-        id
-    );
+    if let Some(sha256) = &payload.sha256
+        && (sha256.len() != 64 || !sha256.chars().all(|c| c.is_ascii_hexdigit()))
+    {
+        return Err(AppError::BadRequest("sha256 must be a hex encoded SHA-256 digest".to_string()));
+    }
 
-    if let (Some(name), Some(data), Some(uid)) = (filename, file_data, id) {
-        let avatar_dir = PathBuf::from(&state.upload_dir).join("avatars");
+    let session = storage::create_upload_session(
+        &repos,
+        &partial_dir,
+        &quota,
+        auth_user.id,
+        &payload.file_name,
+        payload.size,
+        payload.sha256.as_deref(),
+    ).await?;
 
-        if let Err(e) = tokio::fs::create_dir_all(&avatar_dir).await {
-            eprintln!("Failed to create avatar dir: {:?}", e);
-            return (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(json!({ "error": "Could not create avatar folder" })),
-            );
-        }
+    Ok(upload_session_response(StatusCode::CREATED, &session))
+}
 
-        let full_path = avatar_dir.join(&name);
-
-        match tokio::fs::write(&full_path, &data).await {
-            Ok(_) => {
-                let avatar_url = format!("/avatars/{}", name);
-                println!("{}", avatar_url.clone());
-                match User::insert_pfp(pool, Some(uid), avatar_url.clone()).await {
-                    Ok(_) => (
-                        StatusCode::OK,
-                        Json(json!({ "status": "success", "filename": name, "avatarUrl": avatar_url})),
-                    ),
-                    Err(e) => {
-                        eprintln!("DB update error: {}", e);
-                        (
-                            StatusCode::INTERNAL_SERVER_ERROR,
-                            Json(json!({ "error": "Database update failed" })),
-                        )
-                    }
-                }
-            }
 
-            Err(e) => {
-                eprintln!("Failed to write file: {:?}", e);
-                (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    Json(json!({ "error": "Failed to write avatar to disk" })),
-                )
-            }
-        }
+pub async fn get_resumable_upload(
+    State(state): State<SharedChatState>,
+    Extension(auth_user): Extension<AuthenticatedUser>,
+    Path(id): Path<Uuid>,
+) -> Result<Response, AppError> {
+    let repos = repos(&state).await;
+
+    let session = repos.uploads.find_session(id, auth_user.id).await?
+        .ok_or(StorageError::SessionNotFound)?;
+
+    Ok(upload_session_response(StatusCode::OK, &session))
+}
+
+
+pub async fn patch_resumable_upload(
+    State(state): State<SharedChatState>,
+    Extension(auth_user): Extension<AuthenticatedUser>,
+    Path(id): Path<Uuid>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response, AppError> {
+    let (repos, partial_dir) = {
+        let state = state.read().await;
+        (state.repos.clone(), state.upload_dir.join("partial_uploads"))
+    };
+
+    let offset = headers
+        .get(UPLOAD_OFFSET_HEADER)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.trim().parse::<i64>().ok())
+        .ok_or_else(|| AppError::BadRequest("Missing or invalid Upload-Offset header".to_string()))?;
+
+    metrics().upload_bytes.with_label_values(&["resumable"]).inc_by(body.len() as u64);
+    let session = storage::append_chunk(&repos, &partial_dir, id, auth_user.id, offset, &body).await?;
+    Ok(upload_session_response(StatusCode::OK, &session))
+}
+
+
+pub async fn finalize_resumable_upload(
+    State(state): State<SharedChatState>,
+    Extension(auth_user): Extension<AuthenticatedUser>,
+    Path(id): Path<Uuid>,
+) -> Result<Json<Value>, AppError> {
+    let target = UploadTarget::of(&state).await;
+
+    let stored = storage::finalize_upload(
+        &target.repos,
+        &target.root.join("partial_uploads"),
+        &target.root.join("uploads"),
+        &target.quota,
+        target.initial_status(),
+        id,
+        auth_user.id,
+    ).await?;
+
+    target.start_scan(&stored);
+    Ok(Json(stored_upload_json(&stored)))
+}
+
+
+pub async fn delete_resumable_upload(
+    State(state): State<SharedChatState>,
+    Extension(auth_user): Extension<AuthenticatedUser>,
+    Path(id): Path<Uuid>,
+) -> Result<StatusCode, AppError> {
+    let (repos, partial_dir) = {
+        let state = state.read().await;
+        (state.repos.clone(), state.upload_dir.join("partial_uploads"))
+    };
+
+    let session = repos.uploads.find_session(id, auth_user.id).await?
+        .ok_or(StorageError::SessionNotFound)?;
+
+    storage::abort_upload(&repos, &partial_dir, session.id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+
+/// How many files `/api/me/storage` lists.
+const LARGEST_FILES_LIMIT: i64 = 10;
+
+#[derive(Deserialize)]
+pub struct QuotaPayload {
+    /// New quota in bytes, `null` to fall back to the default quota.
+    pub quota_bytes: Option<i64>,
+}
+
+
+async fn storage_report(repos: &Repositories, user_id: Uuid, default_quota: Option<i64>) -> Result<Value, sqlx::Error> {
+    let usage = repos.users.storage_usage(user_id).await?;
+    let files = repos.uploads.largest_attachments(user_id, LARGEST_FILES_LIMIT).await?;
+    let quota = usage.quota_bytes.or(default_quota);
+
+    Ok(json!({
+        "used_bytes": usage.used_bytes,
+        "quota_bytes": quota,
+        "remaining_bytes": quota.map(|q| (q - usage.used_bytes).max(0)),
+        "largest_files": files.iter().map(|f| json!({
+            "id": f.id,
+            "name": f.original_name,
+            "size": f.size_bytes,
+            "upload_url": f.upload_url(),
+            "created_at": f.created_at
+        })).collect::<Vec<_>>()
+    }))
+}
+
+
+pub async fn get_my_two_factor(
+    State(state): State<SharedChatState>,
+    Extension(auth_user): Extension<AuthenticatedUser>,
+) -> Result<Json<Value>, AppError> {
+    let repos = repos(&state).await;
+    let two_factor = repos.two_factor.two_factor(auth_user.id).await?.ok_or(AppError::NotFound("User"))?;
+
+    let recovery_codes_left = if two_factor.is_enabled() {
+        repos.two_factor.unused_recovery_codes(auth_user.id).await?.len()
     } else {
-        (
-            StatusCode::BAD_REQUEST,
-            Json(json!({ "error": "Missing avatar or user ID" })),
-        )
+        0
+    };
+
+    Ok(Json(json!({
+        "enabled": two_factor.is_enabled(),
+        "enabled_at": two_factor.totp_enabled_at,
+        "recovery_codes_left": recovery_codes_left
+    })))
+}
+
+
+/// Starts enrollment with a new secret. Nothing is enforced until the first code
+/// is confirmed through `/api/me/2fa/enable`; calling this again replaces the secret.
+pub async fn enroll_two_factor(
+    State(state): State<SharedChatState>,
+    Extension(auth_user): Extension<AuthenticatedUser>,
+) -> Result<Json<Value>, AppError> {
+    let repos = repos(&state).await;
+    let two_factor = repos.two_factor.two_factor(auth_user.id).await?.ok_or(AppError::NotFound("User"))?;
+    if two_factor.is_enabled() {
+        return Err(AppError::TwoFactorAlreadyEnabled);
+    }
+
+    let secret = totp::generate_secret();
+    let uri = totp::otpauth_uri(&secret, &auth_user.username)
+        .ok_or_else(|| AppError::Internal("could not build otpauth URI".to_string()))?;
+    repos.two_factor.set_totp_secret(auth_user.id, &secret).await?;
+
+    Ok(Json(json!({ "secret": secret, "otpauth_uri": uri })))
+}
+
+
+#[derive(Deserialize)]
+pub struct TwoFactorCodePayload {
+    pub code: String,
+}
+
+
+/// Confirms enrollment with a code from the authenticator app and returns the
+/// recovery codes. They are only stored hashed and can't be shown again.
+pub async fn enable_two_factor(
+    State(state): State<SharedChatState>,
+    Extension(auth_user): Extension<AuthenticatedUser>,
+    JsonBody(payload): JsonBody<TwoFactorCodePayload>
+) -> Result<Json<Value>, AppError> {
+    let repos = repos(&state).await;
+    let two_factor = repos.two_factor.two_factor(auth_user.id).await?.ok_or(AppError::NotFound("User"))?;
+    if two_factor.is_enabled() {
+        return Err(AppError::TwoFactorAlreadyEnabled);
+    }
+    let Some(secret) = two_factor.totp_secret else {
+        return Err(AppError::BadRequest("Start enrollment through /api/me/2fa/enroll first".to_string()));
+    };
+
+    let now = chrono::Utc::now().timestamp().max(0) as u64;
+    let step = totp::matching_step(&secret, &payload.code, now).ok_or(AppError::InvalidTwoFactorCode)?;
+
+    let (codes, hashes) = new_recovery_codes()?;
+    if !repos.two_factor.enable_totp(auth_user.id, step, &hashes).await? {
+        return Err(AppError::BadRequest("Start enrollment through /api/me/2fa/enroll first".to_string()));
     }
+    tracing::info!(user_id = %auth_user.id, "two-factor authentication enabled");
+
+    Ok(Json(json!({ "status": "success", "recovery_codes": codes })))
+}
+
+
+#[derive(Deserialize)]
+pub struct DisableTwoFactorPayload {
+    pub password: String,
+    pub code: String,
+}
+
+
+/// Turns 2FA off. Needs the password and a code, a stolen token alone is not enough.
+pub async fn disable_two_factor(
+    State(state): State<SharedChatState>,
+    Extension(auth_user): Extension<AuthenticatedUser>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    JsonBody(payload): JsonBody<DisableTwoFactorPayload>
+) -> Result<Json<Value>, AppError> {
+    let (repos, config, limiter) = {
+        let state = state.read().await;
+        (state.repos.clone(), state.config.clone(), state.login_limiter.clone())
+    };
+    let ip = client_ip(&headers, peer, config.server.trust_forwarded_for);
+
+    check_throttle(&limiter, &auth_user.username, ip)?;
+    let user = repos.users.find_by_id(auth_user.id).await?.ok_or(AppError::NotFound("User"))?;
+    if !verify_password(&payload.password, &user.password_hash)? {
+        if let Some(lockout) = limiter.record_failure(&auth_user.username, Some(ip)) {
+            record_lockout(&repos, &auth_user.username, ip, lockout).await;
+        }
+        return Err(AppError::InvalidCredentials);
+    }
+    require_second_factor(&repos, &limiter, &auth_user.username, auth_user.id, ip, &payload.code).await?;
+
+    repos.two_factor.disable_totp(auth_user.id).await?;
+    tracing::warn!(user_id = %auth_user.id, "two-factor authentication disabled");
+
+    Ok(Json(json!({ "status": "success" })))
+}
+
+
+/// Replaces all recovery codes, for when they were lost or mostly used up.
+pub async fn regenerate_recovery_codes(
+    State(state): State<SharedChatState>,
+    Extension(auth_user): Extension<AuthenticatedUser>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    JsonBody(payload): JsonBody<TwoFactorCodePayload>
+) -> Result<Json<Value>, AppError> {
+    let (repos, config, limiter) = {
+        let state = state.read().await;
+        (state.repos.clone(), state.config.clone(), state.login_limiter.clone())
+    };
+    let ip = client_ip(&headers, peer, config.server.trust_forwarded_for);
+
+    if !totp::is_totp_code(&payload.code) {
+        return Err(AppError::BadRequest("A code from the authenticator app is required".to_string()));
+    }
+    require_second_factor(&repos, &limiter, &auth_user.username, auth_user.id, ip, &payload.code).await?;
+
+    let (codes, hashes) = new_recovery_codes()?;
+    repos.two_factor.replace_recovery_codes(auth_user.id, &hashes).await?;
+
+    Ok(Json(json!({ "status": "success", "recovery_codes": codes })))
+}
+
+
+#[derive(Deserialize)]
+pub struct ChangePasswordPayload {
+    pub current_password: String,
+    pub new_password: String,
+}
+
+
+/// Sets a new password and signs out every other session. The caller gets a
+/// fresh token in place of the one it used.
+pub async fn change_password(
+    State(state): State<SharedChatState>,
+    Extension(auth_user): Extension<AuthenticatedUser>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    JsonBody(payload): JsonBody<ChangePasswordPayload>
+) -> Result<Json<Value>, AppError> {
+    let (repos, config, limiter, password_policy) = {
+        let state = state.read().await;
+        (state.repos.clone(), state.config.clone(), state.login_limiter.clone(), state.password_policy.clone())
+    };
+    let ip = client_ip(&headers, peer, config.server.trust_forwarded_for);
+
+    check_throttle(&limiter, &auth_user.username, ip)?;
+    let user = repos.users.find_by_id(auth_user.id).await?.ok_or(AppError::NotFound("User"))?;
+    if !verify_password(&payload.current_password, &user.password_hash)? {
+        if let Some(lockout) = limiter.record_failure(&auth_user.username, Some(ip)) {
+            record_lockout(&repos, &auth_user.username, ip, lockout).await;
+        }
+        return Err(AppError::InvalidCredentials);
+    }
+
+    password_policy
+        .check(&payload.new_password, &[&user.username, &payload.current_password])
+        .await
+        .map_err(AppError::WeakPassword)?;
+    let hashed = hash_password(&payload.new_password)?;
+    let user = repos.users.set_password(&user.username, &hashed).await?.ok_or(AppError::NotFound("User"))?;
+    tracing::info!(user_id = %user.id, "password changed");
+    state.read().await.close_sockets(SocketCredential::Session(user.id));
+
+    let token = create_jwt(&user, &config.auth)?;
+
+    Ok(Json(json!({ "status": "success", "token": token })))
+}
+
+
+#[derive(Deserialize)]
+pub struct ChangeUsernamePayload {
+    pub username: String,
+}
+
+
+/// Renames the caller. Messages refer to users by id, so their history moves along,
+/// and the old name is free for others afterwards. The caller gets a fresh token
+/// carrying the new name.
+pub async fn change_username(
+    State(state): State<SharedChatState>,
+    Extension(auth_user): Extension<AuthenticatedUser>,
+    JsonBody(payload): JsonBody<ChangeUsernamePayload>
+) -> Result<Json<Value>, AppError> {
+    let (repos, config, username_policy) = {
+        let state = state.read().await;
+        (state.repos.clone(), state.config.clone(), state.username_policy.clone())
+    };
+
+    let username = username::normalize(&payload.username);
+    username_policy.check(&username).map_err(AppError::InvalidUsername)?;
+    check_username_available(&repos, &username, Some(auth_user.id)).await?;
+
+    let user = repos.users.rename(auth_user.id, &username).await?.ok_or(AppError::UsernameTaken)?;
+    tracing::info!(user_id = %user.id, old_username = %auth_user.username, new_username = %user.username, "username changed");
+
+    let profile = repos.users.profile(user.id).await?;
+    {
+        let mut state = state.write().await;
+        state.rename_user(&auth_user.username, &user.username);
+        if let Some(profile) = &profile {
+            state.broadcast_profile(profile);
+        }
+    }
+
+    let token = create_jwt(&user, &config.auth)?;
+
+    Ok(Json(json!({ "status": "success", "username": user.username, "token": token })))
+}
+
+
+/// Stores a new reset token for `user` and returns it with its expiry.
+async fn issue_password_reset(
+    repos: &Repositories,
+    config: &Config,
+    user: &User,
+) -> Result<(String, chrono::DateTime<chrono::Utc>), AppError> {
+    let token = generate_reset_token();
+    let expires_at = chrono::Utc::now() + config.reset_token_ttl();
+    repos.users.create_password_reset(user.id, &hash_reset_token(&token), expires_at).await?;
+
+    Ok((token, expires_at))
+}
+
+
+#[derive(Deserialize)]
+pub struct PasswordResetRequestPayload {
+    pub username: String,
+}
+
+
+/// Sends a reset link through the configured notifier. The answer is the same
+/// whether or not the user exists, and the link is sent in the background so the
+/// response time doesn't tell either.
+pub async fn request_password_reset(
+    State(state): State<SharedChatState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    JsonBody(payload): JsonBody<PasswordResetRequestPayload>
+) -> Result<(StatusCode, Json<Value>), AppError> {
+    let (repos, config, notifier, limiter) = {
+        let state = state.read().await;
+        (state.repos.clone(), state.config.clone(), state.reset_notifier.clone(), state.reset_limiter.clone())
+    };
+    let Some(notifier) = notifier else {
+        return Err(AppError::BadRequest("Password reset is not available, ask an administrator".to_string()));
+    };
+
+    // Every request counts, whether or not the user exists, so the throttle gives
+    // nothing away and links can't be sent in bulk
+    let ip = client_ip(&headers, peer, config.server.trust_forwarded_for);
+    check_throttle(&limiter, &payload.username, ip)?;
+    limiter.record_failure(&payload.username, Some(ip));
+
+    tokio::spawn(async move {
+        let user = match repos.users.find_by_username(&payload.username).await {
+            Ok(Some(user)) if !user.is_disabled() => user,
+            Ok(_) => return,
+            Err(e) => {
+                tracing::error!(error = %e, "password reset lookup failed");
+                return;
+            }
+        };
+
+        let (token, expires_at) = match issue_password_reset(&repos, &config, &user).await {
+            Ok(issued) => issued,
+            Err(e) => {
+                tracing::error!(error = %e, user_id = %user.id, "could not create password reset token");
+                return;
+            }
+        };
+        let Some(link) = config.password_reset_link(&token) else {
+            return;
+        };
+
+        match notifier.send_reset_link(&user, &link, expires_at).await {
+            Ok(()) => tracing::info!(user_id = %user.id, "password reset link sent"),
+            Err(e) => tracing::error!(error = %e, user_id = %user.id, "could not send password reset link"),
+        }
+    });
+
+    Ok((StatusCode::ACCEPTED, Json(json!({ "status": "accepted" }))))
+}
+
+
+#[derive(Deserialize)]
+pub struct PasswordResetPayload {
+    pub token: String,
+    pub new_password: String,
+}
+
+
+/// Sets a new password with a reset token. The token works once, and every
+/// session of the user is signed out.
+pub async fn reset_password(
+    State(state): State<SharedChatState>,
+    JsonBody(payload): JsonBody<PasswordResetPayload>
+) -> Result<Json<Value>, AppError> {
+    let (repos, limiter, password_policy) = {
+        let state = state.read().await;
+        (state.repos.clone(), state.login_limiter.clone(), state.password_policy.clone())
+    };
+
+    // Checked and hashed before the token is used up, so a rejected password doesn't
+    // waste the link. The username is not known yet, so it can't be compared against
+    password_policy.check(&payload.new_password, &[]).await.map_err(AppError::WeakPassword)?;
+    let hashed = hash_password(&payload.new_password)?;
+
+    let user_id = repos.users.use_password_reset(&hash_reset_token(&payload.token)).await?
+        .ok_or(AppError::InvalidResetToken)?;
+    let user = repos.users.find_by_id(user_id).await?.ok_or(AppError::InvalidResetToken)?;
+    repos.users.set_password(&user.username, &hashed).await?;
+
+    // Whoever holds the link could have set the password anyway
+    limiter.unlock(&user.username);
+    tracing::info!(user_id = %user.id, "password reset");
+    state.read().await.close_sockets(SocketCredential::Session(user.id));
+
+    Ok(Json(json!({ "status": "success", "username": user.username })))
+}
+
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CreateApiTokenPayload {
+    pub name: String,
+    pub scopes: Vec<ApiScope>,
+    /// Leave out for a token that never expires.
+    pub expires_in_days: Option<i64>,
+}
+
+
+/// Longest token name in characters.
+const MAX_API_TOKEN_NAME_LEN: usize = 64;
+
+/// Stores a new API token for `user_id` and returns it with its stored record. The
+/// token itself is only ever returned here.
+async fn issue_api_token(
+    repos: &Repositories,
+    user_id: Uuid,
+    payload: CreateApiTokenPayload,
+) -> Result<(String, ApiToken), AppError> {
+    let name = payload.name.trim();
+    if name.is_empty() || name.chars().count() > MAX_API_TOKEN_NAME_LEN {
+        return Err(AppError::BadRequest(format!("name must be 1 to {} characters long", MAX_API_TOKEN_NAME_LEN)));
+    }
+    if name.chars().any(char::is_control) {
+        return Err(AppError::BadRequest("name must not contain control characters".to_string()));
+    }
+
+    let mut scopes = payload.scopes;
+    scopes.sort_by_key(|scope| scope.as_str());
+    scopes.dedup();
+    if scopes.is_empty() {
+        return Err(AppError::BadRequest("scopes must not be empty".to_string()));
+    }
+
+    let expires_at = match payload.expires_in_days {
+        Some(days) if days <= 0 => return Err(AppError::BadRequest("expires_in_days must be positive".to_string())),
+        Some(days) => Some(
+            chrono::Duration::try_days(days)
+                .and_then(|ttl| chrono::Utc::now().checked_add_signed(ttl))
+                .ok_or_else(|| AppError::BadRequest("expires_in_days is too large".to_string()))?,
+        ),
+        None => None,
+    };
+
+    let token = generate_api_token();
+    let api_token = repos.api_tokens
+        .create_api_token(user_id, name, &hash_api_token(&token), &ApiScopes(scopes), expires_at)
+        .await?;
+
+    Ok((token, api_token))
+}
+
+
+pub async fn get_my_api_tokens(
+    State(state): State<SharedChatState>,
+    Extension(auth_user): Extension<AuthenticatedUser>,
+) -> Result<Json<Value>, AppError> {
+    let repos = repos(&state).await;
+
+    Ok(Json(json!({ "tokens": repos.api_tokens.api_tokens(auth_user.id).await? })))
+}
+
+
+/// Creates a personal API token for scripts and integrations.
+pub async fn create_my_api_token(
+    State(state): State<SharedChatState>,
+    Extension(auth_user): Extension<AuthenticatedUser>,
+    JsonBody(payload): JsonBody<CreateApiTokenPayload>,
+) -> Result<Json<Value>, AppError> {
+    let repos = repos(&state).await;
+
+    let (token, api_token) = issue_api_token(&repos, auth_user.id, payload).await?;
+    tracing::info!(user_id = %auth_user.id, token_id = %api_token.id, "api token created");
+
+    Ok(Json(json!({ "status": "success", "token": token, "api_token": api_token })))
+}
+
+
+pub async fn revoke_my_api_token(
+    State(state): State<SharedChatState>,
+    Extension(auth_user): Extension<AuthenticatedUser>,
+    Path(id): Path<Uuid>,
+) -> Result<Json<Value>, AppError> {
+    let repos = repos(&state).await;
+
+    if !repos.api_tokens.revoke_api_token(id, Some(auth_user.id)).await? {
+        return Err(AppError::NotFound("Token"));
+    }
+    tracing::info!(user_id = %auth_user.id, token_id = %id, "api token revoked");
+    state.read().await.close_sockets(SocketCredential::ApiToken(id));
+
+    Ok(Json(json!({ "status": "success", "id": id })))
+}
+
+
+pub async fn get_my_storage(
+    State(state): State<SharedChatState>,
+    Extension(auth_user): Extension<AuthenticatedUser>,
+) -> Result<Json<Value>, AppError> {
+    let (repos, default_quota) = {
+        let state = state.read().await;
+        (state.repos.clone(), state.storage_quota.per_user_bytes)
+    };
+
+    Ok(Json(storage_report(&repos, auth_user.id, default_quota).await?))
+}
+
+
+pub async fn admin_middleware(req: Request<Body>, next: Next) -> Result<Response, AppError> {
+    match req.extensions().get::<AuthenticatedUser>() {
+        Some(user) if user.is_admin() => Ok(next.run(req).await),
+        Some(_) => Err(AppError::Forbidden),
+        None => Err(AppError::MissingToken),
+    }
+}
+
+
+/// Lets only session tokens through, for account settings and administration.
+pub async fn session_middleware(req: Request<Body>, next: Next) -> Result<Response, AppError> {
+    match req.extensions().get::<AuthenticatedUser>() {
+        Some(user) if user.scopes.is_none() => Ok(next.run(req).await),
+        Some(_) => Err(AppError::SessionRequired),
+        None => Err(AppError::MissingToken),
+    }
+}
+
+
+/// Lets API tokens through only with `scope`, session tokens always.
+pub async fn require_scope(State(scope): State<ApiScope>, req: Request<Body>, next: Next) -> Result<Response, AppError> {
+    match req.extensions().get::<AuthenticatedUser>() {
+        Some(user) if user.allows(scope) => Ok(next.run(req).await),
+        Some(_) => Err(AppError::InsufficientScope(scope)),
+        None => Err(AppError::MissingToken),
+    }
+}
+
+
+/// Looks up a user by name, turning a missing row into a 404 naming the user.
+async fn find_user(repos: &Repositories, username: &str) -> Result<User, AppError> {
+    repos.users.find_by_username(username).await?.ok_or(AppError::NotFound("User"))
+}
+
+
+pub async fn admin_get_user_storage(
+    State(state): State<SharedChatState>,
+    Path(username): Path<String>,
+) -> Result<Json<Value>, AppError> {
+    let (repos, default_quota) = {
+        let state = state.read().await;
+        (state.repos.clone(), state.storage_quota.per_user_bytes)
+    };
+    let user = find_user(&repos, &username).await?;
+
+    Ok(Json(storage_report(&repos, user.id, default_quota).await?))
+}
+
+
+pub async fn admin_set_user_quota(
+    State(state): State<SharedChatState>,
+    Path(username): Path<String>,
+    JsonBody(payload): JsonBody<QuotaPayload>,
+) -> Result<Json<Value>, AppError> {
+    let repos = repos(&state).await;
+
+    if payload.quota_bytes.is_some_and(|q| q < 0) {
+        return Err(AppError::BadRequest("quota_bytes must not be negative".to_string()));
+    }
+
+    let user = repos.users.set_storage_quota(&username, payload.quota_bytes).await?
+        .ok_or(AppError::NotFound("User"))?;
+
+    Ok(Json(json!({ "status": "success", "username": user.username, "quota_bytes": payload.quota_bytes })))
+}
+
+
+#[derive(Deserialize, Default)]
+pub struct JanitorPayload {
+    /// Overrides the configured dry-run setting for this run.
+    pub dry_run: Option<bool>,
+}
+
+
+pub async fn admin_run_janitor(
+    State(state): State<SharedChatState>,
+    payload: Option<Json<JanitorPayload>>,
+) -> (StatusCode, Json<janitor::JanitorReport>) {
+    let (repos, root, mut options) = {
+        let state = state.read().await;
+        (state.repos.clone(), state.upload_dir.clone(), state.janitor)
+    };
+
+    let payload = payload.map(|Json(p)| p).unwrap_or_default();
+    if let Some(dry_run) = payload.dry_run {
+        options.dry_run = dry_run;
+    }
+
+    let report = janitor::run(&repos, &root, &options).await;
+    (StatusCode::OK, Json(report))
+}
+
+
+#[derive(Deserialize)]
+pub struct LogLevelPayload {
+    /// Filter directives, e.g. `info` or `backend=debug,tower_http=debug`.
+    pub filter: String,
+}
+
+
+pub async fn admin_get_log_level() -> Json<Value> {
+    Json(json!({ "filter": telemetry::current_filter() }))
+}
+
+
+pub async fn admin_set_log_level(
+    Extension(auth_user): Extension<AuthenticatedUser>,
+    JsonBody(payload): JsonBody<LogLevelPayload>,
+) -> Result<Json<Value>, AppError> {
+    telemetry::set_filter(&payload.filter).map_err(AppError::BadRequest)?;
+    tracing::warn!(admin = %auth_user.username, filter = %payload.filter, "log filter changed");
+
+    Ok(Json(json!({ "status": "success", "filter": telemetry::current_filter() })))
+}
+
+
+/// How many recorded lockouts `/api/admin/lockouts` returns.
+const RECENT_LOCKOUTS_LIMIT: i64 = 100;
+
+/// Usernames locked right now plus the most recent recorded lockouts.
+pub async fn admin_get_lockouts(State(state): State<SharedChatState>) -> Result<Json<Value>, AppError> {
+    let (repos, limiter) = {
+        let state = state.read().await;
+        (state.repos.clone(), state.login_limiter.clone())
+    };
+
+    let locked: Vec<Value> = limiter
+        .locked()
+        .into_iter()
+        .map(|(username, wait)| json!({ "username": username, "retry_after_secs": retry_after_secs(wait) }))
+        .collect();
+    let recent = repos.users.recent_lockouts(RECENT_LOCKOUTS_LIMIT).await?;
+
+    Ok(Json(json!({ "locked": locked, "recent": recent })))
+}
+
+
+pub async fn admin_unlock_user(
+    State(state): State<SharedChatState>,
+    Extension(auth_user): Extension<AuthenticatedUser>,
+    Path(username): Path<String>,
+) -> Result<Json<Value>, AppError> {
+    let limiter = state.read().await.login_limiter.clone();
+
+    if !limiter.unlock(&username) {
+        return Err(AppError::NotFound("Lockout"));
+    }
+    tracing::warn!(admin = %auth_user.username, username = %username, "login lockout lifted");
+
+    Ok(Json(json!({ "status": "success", "username": username })))
+}
+
+
+/// Creates a reset link for a user, for when no notifier is configured or the
+/// user can't receive it. The token is returned as well in case `link_base_url`
+/// is unset.
+pub async fn admin_create_password_reset(
+    State(state): State<SharedChatState>,
+    Extension(auth_user): Extension<AuthenticatedUser>,
+    Path(username): Path<String>,
+) -> Result<Json<Value>, AppError> {
+    let (repos, config) = {
+        let state = state.read().await;
+        (state.repos.clone(), state.config.clone())
+    };
+
+    let user = repos.users.find_by_username(&username).await?.ok_or(AppError::NotFound("User"))?;
+    let (token, expires_at) = issue_password_reset(&repos, &config, &user).await?;
+    tracing::warn!(admin = %auth_user.username, username = %username, "password reset link created");
+
+    Ok(Json(json!({
+        "status": "success",
+        "username": user.username,
+        "token": token,
+        "link": config.password_reset_link(&token),
+        "expires_at": expires_at
+    })))
+}
+
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CreateInvitePayload {
+    /// Defaults to `registration.invite_max_uses`.
+    pub max_uses: Option<i32>,
+    /// Defaults to `registration.invite_ttl_hours`, `0` for an invite that never expires.
+    pub expires_in_hours: Option<i64>,
+}
+
+
+/// Creates an invite code. The code is only returned here, the server keeps a hash.
+pub async fn admin_create_invite(
+    State(state): State<SharedChatState>,
+    Extension(auth_user): Extension<AuthenticatedUser>,
+    JsonBody(payload): JsonBody<CreateInvitePayload>,
+) -> Result<Json<Value>, AppError> {
+    let (repos, config) = {
+        let state = state.read().await;
+        (state.repos.clone(), state.config.clone())
+    };
+
+    let max_uses = payload.max_uses.unwrap_or(config.registration.invite_max_uses);
+    if max_uses <= 0 {
+        return Err(AppError::BadRequest("max_uses must be positive".to_string()));
+    }
+    let ttl = match payload.expires_in_hours {
+        Some(hours) if hours < 0 => return Err(AppError::BadRequest("expires_in_hours must not be negative".to_string())),
+        Some(0) => None,
+        Some(hours) => Some(chrono::Duration::try_hours(hours).ok_or_else(|| {
+            AppError::BadRequest("expires_in_hours is too large".to_string())
+        })?),
+        None => config.invite_ttl(),
+    };
+    let expires_at = match ttl {
+        Some(ttl) => Some(chrono::Utc::now().checked_add_signed(ttl).ok_or_else(|| {
+            AppError::BadRequest("expires_in_hours is too large".to_string())
+        })?),
+        None => None,
+    };
+
+    let code = generate_invite_code();
+    let invite = repos.invites.create_invite(auth_user.id, &hash_invite_code(&code), max_uses, expires_at).await?;
+    tracing::warn!(admin = %auth_user.username, invite_id = %invite.id, max_uses, "invite created");
+
+    Ok(Json(json!({
+        "status": "success",
+        "code": code,
+        "invite": invite
+    })))
+}
+
+
+/// Every invite, with who created it and who registered with it.
+pub async fn admin_get_invites(State(state): State<SharedChatState>) -> Result<Json<Value>, AppError> {
+    let repos = repos(&state).await;
+    let now = chrono::Utc::now();
+
+    let invites: Vec<Value> = repos.invites
+        .invites()
+        .await?
+        .into_iter()
+        .map(|invite| {
+            let usable = invite.is_usable(now);
+            let mut value = json!(invite);
+            value["usable"] = json!(usable);
+            value
+        })
+        .collect();
+
+    Ok(Json(json!({ "invites": invites })))
+}
+
+
+/// Stops an invite from being used again. Users who already registered with it stay.
+pub async fn admin_revoke_invite(
+    State(state): State<SharedChatState>,
+    Extension(auth_user): Extension<AuthenticatedUser>,
+    Path(id): Path<Uuid>,
+) -> Result<Json<Value>, AppError> {
+    let repos = repos(&state).await;
+
+    if !repos.invites.revoke_invite(id).await? {
+        return Err(AppError::NotFound("Invite"));
+    }
+    tracing::warn!(admin = %auth_user.username, invite_id = %id, "invite revoked");
+
+    Ok(Json(json!({ "status": "success", "id": id })))
+}
+
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CreateBotPayload {
+    pub username: String,
+}
+
+
+/// Creates a bot account. Bots can't log in, they use tokens from
+/// `/api/admin/users/{username}/tokens`.
+pub async fn admin_create_bot(
+    State(state): State<SharedChatState>,
+    Extension(auth_user): Extension<AuthenticatedUser>,
+    JsonBody(payload): JsonBody<CreateBotPayload>,
+) -> Result<Json<NewAccount>, AppError> {
+    let (repos, username_policy) = {
+        let state = state.read().await;
+        (state.repos.clone(), state.username_policy.clone())
+    };
+
+    let username = username::normalize(&payload.username);
+    username_policy.check(&username).map_err(AppError::InvalidUsername)?;
+    check_username_available(&repos, &username, None).await?;
+
+    let password: [u8; 32] = rand::random();
+    let hashed = hash_password(&hex::encode(password))?;
+    let bot = repos.users.create_bot(&username, &hashed).await?.ok_or(AppError::UsernameTaken)?;
+    tracing::warn!(admin = %auth_user.username, bot = %bot.username, "bot created");
+
+    Ok(Json(bot.into()))
+}
+
+
+pub async fn admin_get_api_tokens(
+    State(state): State<SharedChatState>,
+    Path(username): Path<String>,
+) -> Result<Json<Value>, AppError> {
+    let repos = repos(&state).await;
+    let user = find_user(&repos, &username).await?;
+
+    Ok(Json(json!({ "username": user.username, "tokens": repos.api_tokens.api_tokens(user.id).await? })))
+}
+
+
+/// Creates a token for a bot. People create their own tokens.
+pub async fn admin_create_api_token(
+    State(state): State<SharedChatState>,
+    Extension(auth_user): Extension<AuthenticatedUser>,
+    Path(username): Path<String>,
+    JsonBody(payload): JsonBody<CreateApiTokenPayload>,
+) -> Result<Json<Value>, AppError> {
+    let repos = repos(&state).await;
+    let user = find_user(&repos, &username).await?;
+    if !user.is_bot {
+        return Err(AppError::BadRequest("Only bots get tokens from admins".to_string()));
+    }
+
+    let (token, api_token) = issue_api_token(&repos, user.id, payload).await?;
+    tracing::warn!(admin = %auth_user.username, bot = %user.username, token_id = %api_token.id, "bot token created");
+
+    Ok(Json(json!({ "status": "success", "token": token, "api_token": api_token })))
+}
+
+
+pub async fn admin_revoke_api_token(
+    State(state): State<SharedChatState>,
+    Extension(auth_user): Extension<AuthenticatedUser>,
+    Path(id): Path<Uuid>,
+) -> Result<Json<Value>, AppError> {
+    let repos = repos(&state).await;
+
+    if !repos.api_tokens.revoke_api_token(id, None).await? {
+        return Err(AppError::NotFound("Token"));
+    }
+    tracing::warn!(admin = %auth_user.username, token_id = %id, "api token revoked");
+    state.read().await.close_sockets(SocketCredential::ApiToken(id));
+
+    Ok(Json(json!({ "status": "success", "id": id })))
+}
+
+
+pub async fn handle_avatar(
+    State(state): State<Arc<RwLock<ChatState>>>,
+    Extension(auth_user): Extension<AuthenticatedUser>,
+    mut multipart: Multipart,
+) -> Result<Json<Value>, AppError> {
+    let target = UploadTarget::of(&state).await;
+    let mut filename = None;
+    let mut file_data = None;
+
+    while let Some(field) = multipart.next_field().await? {
+        if field.name() == Some("avatar") {
+            filename = Some(field.file_name().unwrap_or("file").to_string());
+            file_data = Some(field.bytes().await?);
+        }
+    }
+
+    let (Some(original_name), Some(data)) = (filename, file_data) else {
+        return Err(AppError::MissingField("avatar"));
+    };
+
+    metrics().upload_bytes.with_label_values(&["avatar"]).inc_by(data.len() as u64);
+
+    // Avatars are stored like any other upload, so they count against the quota
+    let stored = storage::store_upload(
+        &target.repos,
+        &target.root.join("uploads"),
+        &target.quota,
+        target.initial_status(),
+        Some(auth_user.id),
+        &original_name,
+        &data,
+    ).await?;
+
+    let avatar_url = stored.upload_url();
+    tracing::info!(user_id = %auth_user.id, avatar_url = %avatar_url, size = data.len(), "stored avatar");
+    target.start_scan(&stored);
+    target.repos.users.set_avatar(auth_user.id, &avatar_url).await?
+        .ok_or(AppError::NotFound("User"))?;
+    if let Some(profile) = target.repos.users.profile(auth_user.id).await? {
+        state.read().await.broadcast_profile(&profile);
+    }
+
+    Ok(Json(json!({ "status": "success", "filename": stored.blob.file_name(), "avatarUrl": avatar_url})))
 }
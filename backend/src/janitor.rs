@@ -0,0 +1,263 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+
+use crate::repository::Repositories;
+use crate::storage;
+
+/// Settings for the background task that removes files nothing refers to anymore.
+#[derive(Debug, Clone, Copy)]
+pub struct JanitorOptions {
+    /// Files younger than this are never touched, so uploads whose message is still
+    /// being sent survive.
+    pub grace_period: Duration,
+    /// How often the janitor runs.
+    pub interval: Duration,
+    /// Only report what would be removed.
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RemovedFile {
+    pub path: String,
+    pub size_bytes: i64,
+}
+
+/// What a janitor run removed, or would have removed in dry-run mode.
+#[derive(Debug, Serialize)]
+pub struct JanitorReport {
+    pub dry_run: bool,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: DateTime<Utc>,
+    /// Attachments whose file was never sent in a message.
+    pub orphaned_attachments: Vec<RemovedFile>,
+    /// Files in `uploads/` that no blob row and no message refer to.
+    pub untracked_uploads: Vec<RemovedFile>,
+    /// Files in `avatars/` that no user has as their avatar.
+    pub unused_avatars: Vec<RemovedFile>,
+    pub expired_upload_sessions: usize,
+    /// Blobs deleted from disk because their last attachment went away.
+    pub collected_blobs: Vec<RemovedFile>,
+    pub reclaimed_bytes: i64,
+    pub errors: Vec<String>,
+}
+
+impl JanitorReport {
+    fn new(dry_run: bool) -> Self {
+        Self {
+            dry_run,
+            started_at: Utc::now(),
+            finished_at: Utc::now(),
+            orphaned_attachments: Vec::new(),
+            untracked_uploads: Vec::new(),
+            unused_avatars: Vec::new(),
+            expired_upload_sessions: 0,
+            collected_blobs: Vec::new(),
+            reclaimed_bytes: 0,
+            errors: Vec::new(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.orphaned_attachments.is_empty()
+            && self.untracked_uploads.is_empty()
+            && self.unused_avatars.is_empty()
+            && self.expired_upload_sessions == 0
+            && self.collected_blobs.is_empty()
+            && self.errors.is_empty()
+    }
+}
+
+/// Last path segment of a stored URL such as `/uploads/abc.png`.
+fn url_file_name(url: &str) -> Option<&str> {
+    url.rsplit('/').next().filter(|name| !name.is_empty())
+}
+
+/// Files directly inside `dir` whose last modification is older than `cutoff`.
+async fn stale_files(dir: &Path, cutoff: DateTime<Utc>) -> Result<Vec<(String, PathBuf, i64)>, std::io::Error> {
+    let mut files = Vec::new();
+
+    let mut entries = match tokio::fs::read_dir(dir).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(files),
+        Err(e) => return Err(e),
+    };
+
+    while let Some(entry) = entries.next_entry().await? {
+        let metadata = entry.metadata().await?;
+        if !metadata.is_file() {
+            continue;
+        }
+
+        let modified: DateTime<Utc> = metadata.modified()?.into();
+        if modified >= cutoff {
+            continue;
+        }
+
+        if let Some(name) = entry.file_name().to_str() {
+            files.push((name.to_string(), entry.path(), metadata.len() as i64));
+        }
+    }
+
+    Ok(files)
+}
+
+async fn remove_file(path: &Path, dry_run: bool, report: &mut JanitorReport) -> bool {
+    if dry_run {
+        return true;
+    }
+
+    match storage::remove_if_exists(path).await {
+        Ok(_) => true,
+        Err(e) => {
+            report.errors.push(format!("failed to remove {}: {}", path.display(), e));
+            false
+        }
+    }
+}
+
+/// Runs one cleanup pass over the upload and avatar directories below `root`.
+pub async fn run(repos: &Repositories, root: &Path, options: &JanitorOptions) -> JanitorReport {
+    let mut report = JanitorReport::new(options.dry_run);
+    let cutoff = report.started_at - options.grace_period;
+
+    if let Err(e) = clean_attachments(repos, cutoff, options.dry_run, &mut report).await {
+        report.errors.push(format!("orphaned attachments: {}", e));
+    }
+    if let Err(e) = clean_untracked_uploads(repos, &root.join("uploads"), cutoff, options.dry_run, &mut report).await {
+        report.errors.push(format!("untracked uploads: {}", e));
+    }
+    if let Err(e) = clean_avatars(repos, &root.join("avatars"), cutoff, options.dry_run, &mut report).await {
+        report.errors.push(format!("avatars: {}", e));
+    }
+    if let Err(e) = clean_storage(repos, root, options.dry_run, &mut report).await {
+        report.errors.push(format!("blob storage: {}", e));
+    }
+
+    report.finished_at = Utc::now();
+    report
+}
+
+async fn clean_attachments(
+    repos: &Repositories,
+    cutoff: DateTime<Utc>,
+    dry_run: bool,
+    report: &mut JanitorReport,
+) -> Result<(), sqlx::Error> {
+    for attachment in repos.uploads.find_orphaned_attachments(cutoff).await? {
+        if !dry_run {
+            repos.uploads.delete_attachment(attachment.id).await?;
+        }
+        report.orphaned_attachments.push(RemovedFile {
+            path: format!("/uploads/{}", crate::models::blob_file_name(&attachment.sha256, &attachment.extension)),
+            size_bytes: attachment.size_bytes,
+        });
+    }
+
+    Ok(())
+}
+
+async fn clean_untracked_uploads(
+    repos: &Repositories,
+    dir: &Path,
+    cutoff: DateTime<Utc>,
+    dry_run: bool,
+    report: &mut JanitorReport,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut known: HashSet<String> = repos.uploads.find_all_blobs().await?
+        .iter()
+        .map(|blob| blob.file_name())
+        .collect();
+    known.extend(
+        repos.messages.upload_urls().await?
+            .iter()
+            .filter_map(|url| url_file_name(url))
+            .map(str::to_string),
+    );
+
+    for (name, path, size_bytes) in stale_files(dir, cutoff).await? {
+        if known.contains(&name) {
+            continue;
+        }
+        if remove_file(&path, dry_run, report).await {
+            report.reclaimed_bytes += size_bytes;
+            report.untracked_uploads.push(RemovedFile { path: format!("/uploads/{}", name), size_bytes });
+        }
+    }
+
+    Ok(())
+}
+
+async fn clean_avatars(
+    repos: &Repositories,
+    dir: &Path,
+    cutoff: DateTime<Utc>,
+    dry_run: bool,
+    report: &mut JanitorReport,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let in_use: HashSet<String> = repos.users.avatar_urls().await?
+        .iter()
+        .filter_map(|url| url_file_name(url))
+        .map(str::to_string)
+        .collect();
+
+    for (name, path, size_bytes) in stale_files(dir, cutoff).await? {
+        if in_use.contains(&name) {
+            continue;
+        }
+        if remove_file(&path, dry_run, report).await {
+            report.reclaimed_bytes += size_bytes;
+            report.unused_avatars.push(RemovedFile { path: format!("/avatars/{}", name), size_bytes });
+        }
+    }
+
+    Ok(())
+}
+
+/// Expires abandoned resumable uploads and collects blobs without references.
+async fn clean_storage(
+    repos: &Repositories,
+    root: &Path,
+    dry_run: bool,
+    report: &mut JanitorReport,
+) -> Result<(), storage::StorageError> {
+    let blobs = if dry_run {
+        report.expired_upload_sessions = repos.uploads.find_expired_sessions().await?.len();
+        repos.uploads.find_unreferenced_blobs(Utc::now() - storage::GC_GRACE_PERIOD).await?
+    } else {
+        report.expired_upload_sessions = storage::expire_upload_sessions(repos, &root.join("partial_uploads")).await?;
+        storage::collect_garbage(repos, &root.join("uploads")).await?
+    };
+
+    for blob in blobs {
+        report.reclaimed_bytes += blob.size_bytes;
+        report.collected_blobs.push(RemovedFile {
+            path: format!("/uploads/{}", blob.file_name()),
+            size_bytes: blob.size_bytes,
+        });
+    }
+
+    Ok(())
+}
+
+/// Starts the janitor in the background, logging a report after every run that did something.
+pub fn spawn(repos: Repositories, root: PathBuf, options: JanitorOptions) {
+    tokio::spawn(async move {
+        let period = options.interval.to_std().unwrap_or(std::time::Duration::from_secs(60 * 60));
+        let mut interval = tokio::time::interval(period);
+
+        loop {
+            interval.tick().await;
+            let report = run(&repos, &root, &options).await;
+            if !report.is_empty() {
+                tracing::info!(
+                    target: "janitor",
+                    report = %serde_json::to_string(&report).unwrap_or_default(),
+                    reclaimed_bytes = report.reclaimed_bytes,
+                    "janitor run finished"
+                );
+            }
+        }
+    });
+}
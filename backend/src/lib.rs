@@ -0,0 +1,23 @@
+pub mod auth;
+pub mod config;
+pub mod db;
+pub mod error;
+pub mod handlers;
+pub mod janitor;
+pub mod metrics;
+pub mod models;
+pub mod notify;
+pub mod policy;
+pub mod pow;
+pub mod profile;
+pub mod ratelimit;
+pub mod repository;
+pub mod routes;
+pub mod scanner;
+pub mod storage;
+pub mod telemetry;
+pub mod tls;
+pub mod totp;
+pub mod username;
+pub mod utils;
+pub mod ws;
@@ -1,85 +1,221 @@
-mod auth;
-mod db;
-mod handlers;
-mod models;
-mod utils;
-mod ws;
-
-use axum::http::header;
-use axum::{middleware, Extension};
-use axum::{
-    Router,
-    http::{HeaderValue, Method},
-    routing::{get, post},
-};
-use handlers::auth_middleware;
-use std::{env, net::SocketAddr};
-use tower_http::cors::CorsLayer;
+use std::net::SocketAddr;
 use std::sync::Arc;
-use tokio::sync::RwLock;
-use tower_http::services::ServeDir;
-use ws::ChatState;
+use std::future::IntoFuture;
+use std::time::Duration;
+use futures::FutureExt;
+use tokio::sync::{watch, RwLock};
+use backend::config::{self, Config};
+use backend::ws::{self, ChatState};
+use backend::{db, janitor, routes, scanner, telemetry, tls, username, utils};
 
 
 #[tokio::main]
 async fn main() {
-    if let Ok(database_url) = env::var("DATABASE_URL") {
-        println!("Connected to: {}", database_url);
+    let config = match Config::load() {
+        Ok(config) => Arc::new(config),
+        Err(e) => {
+            eprintln!("Configuration error: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    if let Err(e) = telemetry::init(&config.logging) {
+        eprintln!("Logging initialization failed: {}", e);
+        std::process::exit(1);
+    }
+
+    tracing::info!(database = %config::redact_url(&config.database.url), "connecting to database");
+
+    let database = match db::connect(&config.database).await {
+        Ok(database) => database,
+        Err(e) => {
+            tracing::error!(error = %e, "database initialization failed");
+            std::process::exit(1);
+        }
+    };
+    let migrate_only = std::env::args().any(|arg| arg == "--migrate-only");
+
+    if let Err(e) = database.check_schema_version().await {
+        tracing::error!(error = %e, "refusing to start");
+        std::process::exit(1);
+    }
+
+    let schema_current = if migrate_only || config.database.run_migrations {
+        if let Err(e) = database.migrate().await {
+            tracing::error!(error = %e, "database migration failed");
+            std::process::exit(1);
+        }
+        tracing::info!(version = db::latest_known_version(), "database schema is up to date");
+        true
     } else {
-        println!("DATABASE_URL is not set");
+        match database.pending_migrations().await {
+            Ok(pending) if !pending.is_empty() => {
+                tracing::warn!(?pending, "pending migrations are not applied, run with --migrate-only");
+                false
+            }
+            Ok(_) => true,
+            Err(e) => {
+                tracing::warn!(error = %e, "could not check for pending migrations");
+                false
+            }
+        }
+    };
+
+    let repos = database.repositories();
+
+    // Accounts from before usernames were normalized, needs the columns to exist
+    if schema_current && let Err(e) = username::backfill(repos.users.as_ref()).await {
+        tracing::error!(error = %e, "failed to store canonical usernames");
     }
 
-    if let Err(e) = db::init().await {
-        eprintln!("Database initialization failed: {}", e);
+    if migrate_only {
         return;
     }
 
-    let (chat_state, _rx) = ChatState::new();
+    // Hashed up front, otherwise the first login for an unknown username is slower
+    // than the rest and stands out
+    tokio::task::spawn_blocking(utils::dummy_password_hash);
+
+    let (chat_state, _rx) = ChatState::new(config.clone(), repos.clone());
+    let upload_root = chat_state.upload_dir.clone();
+    let janitor_options = chat_state.janitor;
+    let scanner = chat_state.scanner.clone();
     let shared_state = Arc::new(RwLock::new(chat_state));
 
+    // Removes orphaned uploads, unused avatars, abandoned resumable uploads and
+    // blobs nothing refers to anymore
+    janitor::spawn(repos.clone(), upload_root.clone(), janitor_options);
+
+    match scanner {
+        Some(scanner) => {
+            tracing::info!(address = ?scanner.address, "scanning uploads with clamd");
+            scanner::spawn_rescans(repos.uploads.clone(), scanner, upload_root.clone());
+        }
+        None => tracing::warn!("no clamd address configured, uploads are not scanned"),
+    }
+
+
+    let app = routes::router(shared_state.clone(), &config, &upload_root);
+
+    let tls = match config.tls_paths() {
+        Some((cert_path, key_path)) => match tls::load(cert_path, key_path).await {
+            Ok(tls) => {
+                tls::spawn_reload(
+                    tls.clone(),
+                    cert_path.to_path_buf(),
+                    key_path.to_path_buf(),
+                    Duration::from_secs(config.tls.reload_interval_secs),
+                );
+                Some(tls)
+            }
+            Err(e) => {
+                tracing::error!(cert = %cert_path.display(), error = %e, "failed to load TLS certificate");
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+
+    let addr = config.listen_addr();
+    let listener = match tokio::net::TcpListener::bind(addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            tracing::error!(%addr, error = %e, "failed to bind");
+            std::process::exit(1);
+        }
+    };
+    tracing::info!(%addr, tls = tls.is_some(), "listening");
+
+    if let Some(redirect_addr) = config.redirect_addr() {
+        match tls::spawn_redirect(redirect_addr, addr.port()).await {
+            Ok(()) => tracing::info!(addr = %redirect_addr, "redirecting HTTP to HTTPS"),
+            Err(e) => {
+                tracing::error!(addr = %redirect_addr, error = %e, "failed to bind");
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let (draining_tx, mut draining_rx) = watch::channel(false);
+    let shutdown = async move {
+        shutdown_signal().await;
+        ws::close_all(&shared_state).await;
+        let _ = draining_tx.send(true);
+    };
+
+    let server = match tls {
+        None => axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
+            .with_graceful_shutdown(shutdown)
+            .into_future()
+            .boxed(),
+        Some(tls) => {
+            // axum::serve only speaks plain HTTP, axum-server does the TLS handshake
+            let handle = axum_server::Handle::new();
+            let shutdown_handle = handle.clone();
+            tokio::spawn(async move {
+                shutdown.await;
+                shutdown_handle.graceful_shutdown(None);
+            });
+
+            let listener = match listener.into_std() {
+                Ok(listener) => listener,
+                Err(e) => {
+                    tracing::error!(%addr, error = %e, "failed to bind");
+                    std::process::exit(1);
+                }
+            };
+            axum_server::from_tcp_rustls(listener, tls)
+                .handle(handle)
+                .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+                .boxed()
+        }
+    };
+
+    let drain_timeout = config.shutdown_timeout();
+    let deadline = async move {
+        let _ = draining_rx.wait_for(|draining| *draining).await;
+        tokio::time::sleep(drain_timeout).await;
+    };
+
+    tokio::select! {
+        result = server => match result {
+            Ok(()) => tracing::info!("server stopped"),
+            Err(e) => tracing::error!(error = %e, "server error"),
+        },
+        _ = deadline => tracing::warn!(timeout_secs = drain_timeout.as_secs(), "in-flight requests did not finish in time"),
+    }
+}
+
+
+/// Resolves on Ctrl+C or SIGTERM.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            tracing::error!(error = %e, "failed to listen for Ctrl+C");
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(e) => {
+                tracing::error!(error = %e, "failed to listen for SIGTERM");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
 
-    let public_routes = Router::new()
-        .route("/register", post(handlers::register))
-        .route("/login", post(handlers::login))
-        .route("/ws/{username}", get(ws::handle_socket))
-        .route("/users", get(handlers::list_users))
-        .route("/public", get(handlers::get_public_messages))
-        .with_state(shared_state.clone());
-
-
-    let protected_routes = Router::new()
-        .route("/dms", get(handlers::get_dms))
-        .route("/dm/{target_user}", get(handlers::get_dm_messages))
-        .route("/me", get(handlers::get_meapi))
-        .route("/upload", post(handlers::handle_uploads))
-        .layer(middleware::from_fn(auth_middleware))
-        .route("/avatar-upload", post(handlers::handle_avatar))
-        .layer(middleware::from_fn(auth_middleware));
-
-
-    let app = Router::new()
-        .merge(public_routes)
-        .nest("/api", protected_routes)
-        .nest_service("/avatars", ServeDir::new("avatars"))
-        .nest_service("/uploads", ServeDir::new("uploads"))
-        .with_state(shared_state.clone())
-        .layer(Extension(shared_state))
-        .layer(
-            CorsLayer::new()
-                .allow_origin([
-                    "http://localhost:5173".parse::<HeaderValue>().unwrap(),
-                    "http://192.168.1.45:5173".parse::<HeaderValue>().unwrap(),
-                    "http://tauri.localhost".parse::<HeaderValue>().unwrap(),
-                    "https://brochat.duckdns.org".parse::<HeaderValue>().unwrap()
-                ])
-                .allow_credentials(true)
-                .allow_methods([Method::GET, Method::POST])
-                .allow_headers([header::AUTHORIZATION, header::CONTENT_TYPE]),
-        );
-
-    let addr = SocketAddr::from(([0, 0, 0, 0], 3000));
-    println!("[+] Listening on {}", addr);
-    axum::serve(tokio::net::TcpListener::bind(addr).await.unwrap(), app)
-        .await
-        .unwrap();
+    tracing::info!("shutdown requested, draining connections");
 }
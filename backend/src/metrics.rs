@@ -0,0 +1,147 @@
+use std::future::Future;
+use std::sync::LazyLock;
+use std::time::Instant;
+use axum::body::Body;
+use axum::extract::{MatchedPath, State};
+use axum::http::{header, HeaderMap, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts, Registry, TextEncoder,
+};
+
+use crate::error::AppError;
+use crate::utils::constant_time_eq;
+use crate::ws::SharedChatState;
+
+/// Every metric the server exports on `/metrics`.
+pub struct Metrics {
+    registry: Registry,
+    pub ws_connections: IntGauge,
+    /// WebSocket frames received, by `type`.
+    pub ws_messages: IntCounterVec,
+    /// Broadcast messages a slow connection skipped because it fell behind.
+    pub broadcast_lagged: IntCounter,
+    /// Query latency by query name and outcome.
+    pub db_query_seconds: HistogramVec,
+    pub db_pool_connections: IntGauge,
+    pub db_pool_idle: IntGauge,
+    /// Bytes received for uploads, by `kind` (upload, resumable, avatar).
+    pub upload_bytes: IntCounterVec,
+    /// Rejected logins and requests, by error code.
+    pub auth_failures: IntCounterVec,
+    pub http_request_seconds: HistogramVec,
+}
+
+fn register<T: prometheus::core::Collector + Clone + 'static>(registry: &Registry, collector: T) -> T {
+    registry
+        .register(Box::new(collector.clone()))
+        .expect("metric names are unique");
+    collector
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new_custom(Some("brochat".to_string()), None).expect("valid registry prefix");
+
+        Self {
+            ws_connections: register(&registry, IntGauge::new(
+                "ws_connections", "Currently connected WebSockets",
+            ).unwrap()),
+            ws_messages: register(&registry, IntCounterVec::new(
+                Opts::new("ws_messages_total", "WebSocket frames received"), &["type"],
+            ).unwrap()),
+            broadcast_lagged: register(&registry, IntCounter::new(
+                "broadcast_lagged_total", "Broadcast messages dropped for connections that fell behind",
+            ).unwrap()),
+            db_query_seconds: register(&registry, HistogramVec::new(
+                HistogramOpts::new("db_query_duration_seconds", "Database query latency"),
+                &["query", "outcome"],
+            ).unwrap()),
+            db_pool_connections: register(&registry, IntGauge::new(
+                "db_pool_connections", "Open database connections",
+            ).unwrap()),
+            db_pool_idle: register(&registry, IntGauge::new(
+                "db_pool_idle_connections", "Idle database connections",
+            ).unwrap()),
+            upload_bytes: register(&registry, IntCounterVec::new(
+                Opts::new("upload_bytes_total", "Bytes received for uploads"), &["kind"],
+            ).unwrap()),
+            auth_failures: register(&registry, IntCounterVec::new(
+                Opts::new("auth_failures_total", "Failed logins and rejected tokens"), &["reason"],
+            ).unwrap()),
+            http_request_seconds: register(&registry, HistogramVec::new(
+                HistogramOpts::new("http_request_duration_seconds", "HTTP request latency"),
+                &["method", "route", "status"],
+            ).unwrap()),
+            registry,
+        }
+    }
+}
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+pub fn metrics() -> &'static Metrics {
+    &METRICS
+}
+
+/// Runs a query future and records how long it took.
+pub async fn timed<T, E>(query: &'static str, fut: impl Future<Output = Result<T, E>>) -> Result<T, E> {
+    let start = Instant::now();
+    let result = fut.await;
+    let outcome = if result.is_ok() { "ok" } else { "error" };
+    metrics()
+        .db_query_seconds
+        .with_label_values(&[query, outcome])
+        .observe(start.elapsed().as_secs_f64());
+    result
+}
+
+/// Records latency for every routed request. Labelled by the route template rather
+/// than the raw path to keep cardinality bounded.
+pub async fn track_http(req: Request<Body>, next: Next) -> Response {
+    let start = Instant::now();
+    let method = req.method().to_string();
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| "unmatched".to_string());
+
+    let response = next.run(req).await;
+
+    metrics()
+        .http_request_seconds
+        .with_label_values(&[method.as_str(), route.as_str(), response.status().as_str()])
+        .observe(start.elapsed().as_secs_f64());
+
+    response
+}
+
+/// Prometheus text format, for scrapers that send `metrics.bearer_token`.
+pub async fn handle_metrics(State(state): State<SharedChatState>, headers: HeaderMap) -> Result<Response, AppError> {
+    let state = state.read().await;
+    let expected = state.config.metrics.bearer_token.as_deref().ok_or(AppError::NotFound("Metrics"))?;
+    let token = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "))
+        .ok_or(AppError::MissingToken)?;
+    if !constant_time_eq(token.trim().as_bytes(), expected.trim().as_bytes()) {
+        return Err(AppError::InvalidToken);
+    }
+
+    if let Some(pool) = state.repos.backend.pool_stats() {
+        metrics().db_pool_connections.set(pool.size as i64);
+        metrics().db_pool_idle.set(pool.idle as i64);
+    }
+
+    let mut buffer = Vec::new();
+    let encoder = TextEncoder::new();
+    if let Err(e) = encoder.encode(&metrics().registry.gather(), &mut buffer) {
+        tracing::error!(error = %e, "failed to encode metrics");
+        return Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response());
+    }
+
+    Ok(([(header::CONTENT_TYPE, encoder.format_type().to_string())], buffer).into_response())
+}
@@ -1,237 +1,544 @@
-use axum::http::{HeaderMap, StatusCode};
+use axum::http::HeaderMap;
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use sqlx::{FromRow, PgPool};
-use jsonwebtoken::{decode, DecodingKey, Validation};
+use sqlx::FromRow;
 use uuid::Uuid;
 
+use crate::auth::{decode_jwt, hash_api_token, API_TOKEN_PREFIX};
+use crate::config::AuthConfig;
+use crate::error::AppError;
+use crate::repository::Repositories;
+
 #[derive(Serialize, Deserialize, Debug, Clone, FromRow)]
 pub struct User {
     pub id: Uuid,
     pub username: String,
     pub password_hash: String,
-    pub avatar_url: String
+    pub avatar_url: String,
+    pub role: String,
+    pub disabled_at: Option<DateTime<Utc>>,
+    /// Bumped on every password change, tokens issued with an older version are rejected.
+    #[serde(default)]
+    pub token_version: i32,
+    /// Run by an integration, signs in with API tokens only.
+    #[serde(default)]
+    pub is_bot: bool,
+    /// Lost its name to an older account when usernames were normalized, see
+    /// [`crate::username::backfill`]. Cleared by a rename.
+    #[serde(default)]
+    pub username_needs_rename: bool
 }
 #[derive(Debug, Clone, FromRow, Serialize)]
 pub struct MessageModel {
     pub id: Uuid,
+    pub sender_id: Uuid,
+    /// The sender's current username.
     pub sender: String,
+    pub target_user_id: Option<Uuid>,
     pub target_username: Option<String>,
     pub message: String,
     pub message_type: String,
     pub timestamp: DateTime<Utc>,
     pub avatar_url: Option<String>,
-    pub upload_url: Option<String>
+    pub upload_url: Option<String>,
+    pub sender_is_bot: bool
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "TEXT", rename_all = "lowercase")]
+pub enum ScanStatus {
+    Pending,
+    Clean,
+    Infected
+}
+
+#[derive(Debug, Clone, FromRow, Serialize)]
+pub struct Blob {
+    pub sha256: String,
+    pub size_bytes: i64,
+    pub extension: String,
+    pub ref_count: i32,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    pub scan_status: ScanStatus,
+    pub scan_signature: Option<String>,
+    pub scanned_at: Option<DateTime<Utc>>
+}
+
+/// Content about to be referenced by a new attachment.
+#[derive(Debug, Clone)]
+pub struct NewBlob {
+    pub sha256: String,
+    pub size_bytes: i64,
+    pub extension: String,
+    /// Status given to the blob if this content has not been stored before.
+    pub scan_status: ScanStatus
+}
+
+#[derive(Debug, Clone, FromRow, Serialize)]
+pub struct Attachment {
+    pub id: Uuid,
+    pub blob_sha256: String,
+    pub uploader_id: Option<Uuid>,
+    pub original_name: String,
+    pub created_at: DateTime<Utc>
+}
+
+/// A user's TOTP settings, see [`crate::totp`].
+#[derive(Debug, Clone, FromRow)]
+pub struct TwoFactor {
+    /// Base32 secret, stored on enrollment before 2FA is enabled.
+    pub totp_secret: Option<String>,
+    pub totp_enabled_at: Option<DateTime<Utc>>,
+    /// Newest time step a code was accepted for.
+    pub totp_last_step: Option<i64>,
 }
 
+impl TwoFactor {
+    pub fn is_enabled(&self) -> bool {
+        self.totp_enabled_at.is_some() && self.totp_secret.is_some()
+    }
+}
+
+/// An unused recovery code, only its hash is stored.
+#[derive(Debug, Clone, FromRow)]
+pub struct RecoveryCode {
+    pub id: Uuid,
+    pub code_hash: String,
+}
+
+/// A username locked after too many failed logins, see [`crate::ratelimit`].
+#[derive(Debug, Clone, FromRow, Serialize)]
+pub struct LoginLockout {
+    pub id: Uuid,
+    pub username: String,
+    pub ip_address: Option<String>,
+    pub failures: i32,
+    pub locked_until: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// An invite code for registering while registration is invite-only. Only its hash
+/// is stored, the code itself is shown once.
+#[derive(Debug, Clone, FromRow, Serialize)]
+pub struct Invite {
+    pub id: Uuid,
+    pub created_by: Option<Uuid>,
+    /// The creator's current username, `None` once they are deleted.
+    pub created_by_username: Option<String>,
+    pub max_uses: i32,
+    pub uses: i32,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub revoked_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+    /// Users who registered with it, oldest first.
+    #[sqlx(skip)]
+    pub invitees: Vec<String>,
+}
+
+impl ApiToken {
+    /// Whether the token still lets its holder in.
+    pub fn is_active(&self, now: DateTime<Utc>) -> bool {
+        self.revoked_at.is_none() && self.expires_at.is_none_or(|at| at > now)
+    }
+}
+
+impl Invite {
+    /// Whether the code still lets someone register.
+    pub fn is_usable(&self, now: DateTime<Utc>) -> bool {
+        self.revoked_at.is_none() && self.uses < self.max_uses && self.expires_at.is_none_or(|at| at > now)
+    }
+}
+
+/// What an API token may be used for. Session tokens from `/login` may do everything.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ApiScope {
+    /// Message history and receiving messages over the WebSocket.
+    #[serde(rename = "messages:read")]
+    MessagesRead,
+    /// Sending messages over the WebSocket.
+    #[serde(rename = "messages:write")]
+    MessagesWrite,
+    /// Uploading and managing files.
+    #[serde(rename = "uploads")]
+    Uploads,
+}
+
+impl ApiScope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ApiScope::MessagesRead => "messages:read",
+            ApiScope::MessagesWrite => "messages:write",
+            ApiScope::Uploads => "uploads",
+        }
+    }
+}
+
+impl std::str::FromStr for ApiScope {
+    type Err = UnknownScope;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "messages:read" => Ok(ApiScope::MessagesRead),
+            "messages:write" => Ok(ApiScope::MessagesWrite),
+            "uploads" => Ok(ApiScope::Uploads),
+            _ => Err(UnknownScope(value.to_string())),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct UnknownScope(pub String);
+
+impl std::fmt::Display for UnknownScope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "unknown API scope {:?}", self.0)
+    }
+}
+
+impl std::error::Error for UnknownScope {}
+
+/// The scopes of a token, stored space separated.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(transparent)]
+pub struct ApiScopes(pub Vec<ApiScope>);
+
+impl ApiScopes {
+    pub fn contains(&self, scope: ApiScope) -> bool {
+        self.0.contains(&scope)
+    }
+
+    pub fn to_db(&self) -> String {
+        self.0.iter().map(ApiScope::as_str).collect::<Vec<_>>().join(" ")
+    }
+}
+
+impl TryFrom<String> for ApiScopes {
+    type Error = UnknownScope;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.split_whitespace().map(str::parse).collect::<Result<_, _>>().map(ApiScopes)
+    }
+}
+
+/// A long lived token for an integration. Only its hash is stored, the token itself
+/// is shown once.
+#[derive(Debug, Clone, FromRow, Serialize)]
+pub struct ApiToken {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub name: String,
+    #[sqlx(try_from = "String")]
+    pub scopes: ApiScopes,
+    pub expires_at: Option<DateTime<Utc>>,
+    /// Updated at most once a minute.
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub revoked_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, FromRow, Serialize)]
+pub struct StorageUsage {
+    pub used_bytes: i64,
+    pub quota_bytes: Option<i64>
+}
+
+/// An attachment together with the size of the blob it points at.
+#[derive(Debug, Clone, FromRow, Serialize)]
+pub struct UserFile {
+    pub id: Uuid,
+    pub original_name: String,
+    pub sha256: String,
+    pub extension: String,
+    pub size_bytes: i64,
+    pub created_at: DateTime<Utc>
+}
+
+/// An attachment that no message from its uploader refers to.
+#[derive(Debug, Clone, FromRow, Serialize)]
+pub struct OrphanedAttachment {
+    pub id: Uuid,
+    pub original_name: String,
+    pub sha256: String,
+    pub extension: String,
+    pub size_bytes: i64,
+    pub created_at: DateTime<Utc>
+}
+
+#[derive(Debug, Clone, FromRow, Serialize)]
+pub struct UploadSession {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub file_name: String,
+    pub total_size: i64,
+    pub received_bytes: i64,
+    pub expected_sha256: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>
+}
+
+
+/// How often `last_used_at` of an API token is written, not on every request.
+const API_TOKEN_LAST_USED_SECS: i64 = 60;
 
 #[derive(Clone, Debug)]
 pub struct AuthenticatedUser {
     pub id: Uuid,
     pub username: String,
-    pub avatar_url: Option<String>
+    pub avatar_url: Option<String>,
+    pub role: String,
+    pub is_bot: bool,
+    /// Set when the request came with an API token rather than a session token.
+    pub scopes: Option<ApiScopes>,
+    /// The API token the request came with, `None` for session tokens.
+    pub api_token_id: Option<Uuid>,
+    /// [`User::token_version`] the session token was issued with, `None` for API tokens.
+    pub token_version: Option<i32>,
+}
+
+#[derive(Deserialize, Serialize, Debug, sqlx::FromRow)]
+pub struct UserList{
+    pub username: String,
+    pub display_name: Option<String>,
+    pub avatar_url: String
+}
+
+/// The parts of a profile users edit themselves, see [`crate::profile`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, FromRow)]
+pub struct ProfileFields {
+    pub display_name: Option<String>,
+    pub bio: Option<String>,
+    pub pronouns: Option<String>,
+    pub status_text: Option<String>,
+    pub status_emoji: Option<String>,
+    /// The status is hidden from then on, `None` keeps it until it's changed.
+    pub status_expires_at: Option<DateTime<Utc>>,
+    /// IANA name such as `Europe/Berlin`.
+    pub timezone: Option<String>,
+}
+
+/// What everyone gets to see about a user.
+#[derive(Debug, Clone, Serialize, FromRow)]
+pub struct Profile {
+    pub id: Uuid,
+    pub username: String,
+    pub avatar_url: String,
+    pub is_bot: bool,
+    #[sqlx(flatten)]
+    #[serde(flatten)]
+    pub fields: ProfileFields,
+}
+
+/// What `/register` and `POST /api/admin/bots` answer about the account they created.
+#[derive(Debug, Serialize)]
+pub struct NewAccount {
+    pub id: Uuid,
+    pub username: String,
+    pub avatar_url: String,
+    pub is_bot: bool,
+}
+
+impl From<User> for NewAccount {
+    fn from(user: User) -> Self {
+        Self { id: user.id, username: user.username, avatar_url: user.avatar_url, is_bot: user.is_bot }
+    }
+}
+
+/// Row counts and sizes printed by `brochat-admin stats`.
+#[derive(Debug, Serialize, FromRow)]
+pub struct ServerStats {
+    pub users: i64,
+    pub admins: i64,
+    pub disabled_users: i64,
+    pub public_messages: i64,
+    pub direct_messages: i64,
+    pub attachments: i64,
+    pub blobs: i64,
+    pub blob_bytes: i64,
+    pub pending_scans: i64,
+    pub upload_sessions: i64,
+}
+
+/// A user as written by `brochat-admin export`, including the password hash so the
+/// account keeps working after an import.
+#[derive(Debug, Serialize, Deserialize, FromRow)]
+pub struct ExportedUser {
+    pub id: Uuid,
+    pub username: String,
+    pub password_hash: String,
+    pub avatar_url: Option<String>,
+    pub role: String,
+    pub storage_quota_bytes: Option<i64>,
+    pub disabled_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub is_bot: bool,
+}
+
+/// Sender and recipient go by username, so exports from before messages referred to
+/// users by id still import.
+#[derive(Debug, Serialize, Deserialize, FromRow)]
+pub struct ExportedMessage {
+    pub id: Uuid,
+    pub sender: String,
+    pub target_username: Option<String>,
+    pub message_type: String,
+    pub message: String,
+    pub upload_url: Option<String>,
+    pub timestamp: DateTime<Utc>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
-struct Claims {
-    sub: String, // subject, e.g. username
-    exp: usize,  // expiration time
+pub struct Export {
+    pub exported_at: DateTime<Utc>,
+    /// Newest migration of the exporting server.
+    pub schema_version: i64,
+    pub users: Vec<ExportedUser>,
+    pub messages: Vec<ExportedMessage>,
 }
 
-#[derive(Deserialize, Serialize, Debug, sqlx::FromRow)]
-pub struct UserList{
-    username: String
+/// What an import added, rows that already existed are skipped.
+#[derive(Debug, Default, Serialize)]
+pub struct ImportSummary {
+    pub users: u64,
+    pub messages: u64,
 }
 
 impl AuthenticatedUser {
-    pub async  fn from_auth_header(headers: HeaderMap, pool: &PgPool) -> Result<Self, StatusCode> {
+    pub fn is_admin(&self) -> bool {
+        self.role == "admin"
+    }
+
+    /// Whether the caller may use `scope`. Session tokens may do everything their user can.
+    pub fn allows(&self, scope: ApiScope) -> bool {
+        self.scopes.as_ref().is_none_or(|scopes| scopes.contains(scope))
+    }
+
+    pub async fn from_auth_header(headers: &HeaderMap, repos: &Repositories, config: &AuthConfig) -> Result<Self, AppError> {
         let auth_header = headers
             .get("Authorization")
             .and_then(|h| h.to_str().ok())
-            .ok_or(StatusCode::UNAUTHORIZED)?;
+            .ok_or(AppError::MissingToken)?;
+
+        let token = auth_header
+            .strip_prefix("Bearer ")
+            .ok_or(AppError::MissingToken)?
+            .trim();
 
-        if !auth_header.starts_with("Bearer ") {
-            return Err(StatusCode::UNAUTHORIZED);
+        Self::from_token(token, repos, config).await
+    }
+
+    /// Checks a session token from `/login` or an API token.
+    pub async fn from_token(token: &str, repos: &Repositories, config: &AuthConfig) -> Result<Self, AppError> {
+        if token.starts_with(API_TOKEN_PREFIX) {
+            return Self::from_api_token(token, repos).await;
         }
 
-        let token = auth_header.trim_start_matches("Bearer ").trim();
+        let claims = decode_jwt(token, config)?;
 
-        let secret = std::env::var("JWT_SECRET").unwrap_or_else(|_| "your-secret-key".to_string());
+        // A valid token for a user that no longer exists
+        let user = repos.users.find_by_id(claims.sub).await?.ok_or(AppError::InvalidToken)?;
 
-        let decoded = decode::<Claims>(
-            token,
-            &DecodingKey::from_secret(secret.as_ref()),
-            &Validation::default(),
-        )
-        .map_err(|_| StatusCode::UNAUTHORIZED)?;
+        // Issued before the last password change
+        if claims.ver != user.token_version {
+            return Err(AppError::InvalidToken);
+        }
+
+        Self::for_user(user, None)
+    }
 
-        
-        // let claims = decode_jwt(token).map_err(|_|()).unwrap();
+    async fn from_api_token(token: &str, repos: &Repositories) -> Result<Self, AppError> {
+        let api_token = repos.api_tokens.find_api_token(&hash_api_token(token)).await?.ok_or(AppError::InvalidToken)?;
+        let user = repos.users.find_by_id(api_token.user_id).await?.ok_or(AppError::InvalidToken)?;
+
+        let now = Utc::now();
+        if api_token.last_used_at.is_none_or(|at| now - at >= chrono::Duration::seconds(API_TOKEN_LAST_USED_SECS)) {
+            repos.api_tokens.touch_api_token(api_token.id, now).await?;
+        }
+
+        Self::for_user(user, Some(api_token))
+    }
 
-        let userid = Uuid::parse_str(&decoded.claims.sub).map_err(|_| StatusCode::UNAUTHORIZED)?;
-        let user = User::find_by_id(pool, &userid)
-        .await.map_err(|_| StatusCode::UNAUTHORIZED)?;
+    fn for_user(user: User, api_token: Option<ApiToken>) -> Result<Self, AppError> {
+        if user.is_disabled() {
+            return Err(AppError::AccountDisabled);
+        }
 
         Ok(Self {
             id: user.id,
             username: user.username,
-            avatar_url: Some(user.avatar_url)
+            avatar_url: Some(user.avatar_url),
+            role: user.role,
+            is_bot: user.is_bot,
+            api_token_id: api_token.as_ref().map(|token| token.id),
+            token_version: api_token.is_none().then_some(user.token_version),
+            scopes: api_token.map(|token| token.scopes),
         })
     }
 }
 
 
 impl User {
-    pub async fn create(pool: &PgPool, username: &str, password_hash: &str) -> Result<Self, sqlx::Error> {
-        let id = Uuid::new_v4();
-        let user = sqlx::query_as::<_, User>(
-            "INSERT INTO users (id, username, password_hash) VALUES ($1, $2, $3) RETURNING *"
-        )
-        .bind(id)
-        .bind(username)
-        .bind(password_hash)
-        .fetch_one(pool)
-        .await?;
-        Ok(user)
+    pub fn is_disabled(&self) -> bool {
+        self.disabled_at.is_some()
     }
+}
 
 
-    pub async fn find_by_username(pool: &sqlx::PgPool, username: &str) -> Result<Self, sqlx::Error> {
-        sqlx::query_as(
-            "SELECT * FROM users WHERE username = $1"
-        )
-        .bind(username)
-        .fetch_one(pool)
-        .await
+impl Profile {
+    /// Clears a status whose expiry has passed. Stores keep it until it's changed,
+    /// this runs before a profile leaves the server.
+    pub fn without_expired_status(mut self, now: DateTime<Utc>) -> Self {
+        if self.fields.status_expires_at.is_some_and(|expires_at| expires_at <= now) {
+            self.fields.status_text = None;
+            self.fields.status_emoji = None;
+            self.fields.status_expires_at = None;
+        }
+        self
     }
+}
 
 
-    pub async fn find_by_id(pool: &PgPool, user_id: &Uuid) -> Result<Self, sqlx::Error> {
-        sqlx::query_as(
-            "SELECT id, username, password_hash, avatar_url FROM users WHERE id = $1"
-        )
-        .bind(user_id)
-        .fetch_one(pool)
-        .await
+impl MessageModel {
+    /// Direct messages need someone to go to, public ones don't.
+    pub fn check_recipient(message_type: &str, target_username: Option<&str>) -> Result<(), AppError> {
+        if message_type == "dm" && target_username.is_none_or(str::is_empty) {
+            return Err(AppError::MissingRecipient);
+        }
+        Ok(())
     }
+}
 
 
-    pub async fn find_all(pool: &PgPool) -> Result<Vec<UserList>, sqlx::Error> {
-        sqlx::query_as::<_, UserList>(
-            "SELECT username FROM users"
-        )
-        .fetch_all(pool)
-        .await
-    }
-
+/// SHA-256 of the blob an upload URL points at, from the file name after its last
+/// `/uploads/`. Clients send both full and relative URLs.
+pub fn upload_url_sha256(url: &str) -> Option<&str> {
+    let (_, file_name) = url.rsplit_once("/uploads/")?;
+    let sha256 = file_name.get(..64)?;
+    let hex = sha256.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'));
+    (hex && matches!(file_name.as_bytes().get(64), None | Some(b'.'))).then_some(sha256)
+}
 
-    pub async fn insert_pfp(pool: &PgPool, id: Option<Uuid>, avatar_url: String) -> Result<Self, sqlx::Error> {
-        let abc = sqlx::query_as::<_, User>(
-            "UPDATE users SET avatar_url = $1 WHERE id = $2 RETURNING *"
-        )
-        .bind(avatar_url)
-        .bind(id)
-        .fetch_one(pool).await?;
 
-        Ok(abc)
+pub fn blob_file_name(sha256: &str, extension: &str) -> String {
+    if extension.is_empty() {
+        sha256.to_string()
+    } else {
+        format!("{}.{}", sha256, extension)
     }
-
 }
 
 
-impl MessageModel {
-    pub async fn save_message(
-        pool: &PgPool,
-        username: &str,
-        message_type: &str,
-        message: &str,
-        timestamp: &DateTime<Utc>,
-        target_username: Option<&str>,
-        upload_url: Option<String>
-    ) -> Result<(), sqlx::Error> {
-        let id = Uuid::new_v4();
-    
-        if message_type == "dm" && target_username.is_none() {
-            panic!("DM message must have a target_username");
-        }
-    
-        sqlx::query!(
-            r#"
-            INSERT INTO messages (id, sender, target_username, message_type, message, upload_url, timestamp)
-            VALUES ($1, $2, $3, $4, $5, $6, $7)
-            "#,
-            id,
-            username,
-            target_username,
-            message_type,
-            message,
-            upload_url,
-            timestamp
-        )
-        .execute(pool)
-        .await?;
-    
-        Ok(())
-    }
-    
-
-    pub async fn get_public_messages(pool: &PgPool) -> Result<Vec<MessageModel>, sqlx::Error> {
-        let rows = sqlx::query_as::<_, MessageModel>(
-            r#"
-                SELECT
-                    messages.id,
-                    messages.sender,
-                    messages.message,
-                    messages.target_username,
-                    messages.message_type,
-                    messages.timestamp,
-                    messages.upload_url,
-                    users.avatar_url
-                FROM messages
-                JOIN users ON messages.sender = users.username
-                WHERE messages.message_type = 'chat'
-                ORDER BY messages.timestamp ASC
-                LIMIT $1;
-            "#
-        )
-        .bind(100)
-        .fetch_all(pool)
-        .await?;
-
-        Ok(rows)
-    }
-
-
-    pub async fn get_dm_messages(pool: &PgPool, current_user: &str, target_user: &str) -> Result<Vec<MessageModel>, sqlx::Error> {
-        let rows = sqlx::query_as::<_, MessageModel>(
-            r#"
-            SELECT
-                messages.id,
-                messages.sender,
-                messages.message,
-                messages.target_username,
-                messages.message_type,
-                messages.timestamp,
-                messages.upload_url,
-                users.avatar_url
-            FROM messages
-            JOIN users ON messages.sender = users.username
-            WHERE message_type = 'dm'
-            AND (
-                (LOWER(TRIM(sender)) = LOWER(TRIM($1)) AND LOWER(TRIM(target_username)) = LOWER(TRIM($2)))
-                OR
-                (LOWER(TRIM(sender)) = LOWER(TRIM($2)) AND LOWER(TRIM(target_username)) = LOWER(TRIM($1)))
-            )
-            ORDER BY timestamp ASC
-            "#
-        )
-        .bind(current_user)
-        .bind(target_user)
-        .fetch_all(pool)
-        .await?;
-    println!("Checking DMs between: '{}' and '{}'", current_user, target_user);
-    
-        Ok(rows)
+impl UserFile {
+    pub fn upload_url(&self) -> String {
+        format!("/uploads/{}", blob_file_name(&self.sha256, &self.extension))
     }
+}
 
+
+impl Blob {
+    /// Name of the blob on disk, which is also the last segment of its `/uploads` URL.
+    pub fn file_name(&self) -> String {
+        blob_file_name(&self.sha256, &self.extension)
+    }
 }
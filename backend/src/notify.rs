@@ -0,0 +1,47 @@
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::time::Duration;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use tokio::process::Command;
+
+use crate::models::User;
+
+/// Delivers password reset links to users. Without one, administrators hand out
+/// links from `/api/admin/users/{username}/password-reset` instead.
+#[async_trait]
+pub trait ResetNotifier: Send + Sync {
+    async fn send_reset_link(&self, user: &User, link: &str, expires_at: DateTime<Utc>) -> std::io::Result<()>;
+}
+
+/// Runs a program for every reset link, for example a script that sends mail. It
+/// gets `BROCHAT_RESET_USERNAME`, `BROCHAT_RESET_LINK` and `BROCHAT_RESET_EXPIRES_AT`
+/// in its environment, never as arguments, so the link doesn't show up in `ps`.
+#[derive(Debug, Clone)]
+pub struct CommandNotifier {
+    pub program: PathBuf,
+    pub timeout: Duration,
+}
+
+#[async_trait]
+impl ResetNotifier for CommandNotifier {
+    async fn send_reset_link(&self, user: &User, link: &str, expires_at: DateTime<Utc>) -> std::io::Result<()> {
+        let mut child = Command::new(&self.program)
+            .env("BROCHAT_RESET_USERNAME", &user.username)
+            .env("BROCHAT_RESET_LINK", link)
+            .env("BROCHAT_RESET_EXPIRES_AT", expires_at.to_rfc3339())
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()?;
+
+        let status = tokio::time::timeout(self.timeout, child.wait())
+            .await
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "notify command did not finish in time"))??;
+
+        if !status.success() {
+            return Err(std::io::Error::other(format!("notify command exited with {}", status)));
+        }
+        Ok(())
+    }
+}
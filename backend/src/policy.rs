@@ -0,0 +1,209 @@
+use std::fmt;
+use std::path::PathBuf;
+use sha1::{Digest, Sha1};
+
+use crate::username;
+
+/// Characters of the SHA-1 that name a range file, as in the Pwned Passwords range API.
+const RANGE_PREFIX_LEN: usize = 5;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PasswordProblem {
+    TooShort { min: usize },
+    TooLong { max: usize },
+    /// Below the required strength, with what made it easy to guess when known.
+    TooWeak { score: u8, min: u8, warning: Option<String> },
+    /// Found in the breached password list.
+    Breached,
+}
+
+impl PasswordProblem {
+    /// Stable reason for clients, next to the `weak_password` error code.
+    pub fn reason(&self) -> &'static str {
+        match self {
+            PasswordProblem::TooShort { .. } => "too_short",
+            PasswordProblem::TooLong { .. } => "too_long",
+            PasswordProblem::TooWeak { .. } => "too_weak",
+            PasswordProblem::Breached => "breached",
+        }
+    }
+}
+
+impl fmt::Display for PasswordProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PasswordProblem::TooShort { min } => write!(f, "Password must be at least {} characters long", min),
+            PasswordProblem::TooLong { max } => write!(f, "Password must be at most {} characters long", max),
+            PasswordProblem::TooWeak { warning: Some(warning), .. } => write!(f, "Password is too easy to guess: {}", warning),
+            PasswordProblem::TooWeak { warning: None, .. } => write!(f, "Password is too easy to guess"),
+            PasswordProblem::Breached => write!(f, "Password appears in a list of breached passwords, choose another one"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UsernameProblem {
+    TooShort { min: usize },
+    TooLong { max: usize },
+    InvalidCharacters,
+    /// Letters from more than one script, like Latin mixed with Cyrillic.
+    MixedScripts,
+    Reserved,
+    /// Looks like an existing username without being the same one.
+    Confusable,
+}
+
+impl UsernameProblem {
+    /// Stable reason for clients, next to the `invalid_username` error code.
+    pub fn reason(&self) -> &'static str {
+        match self {
+            UsernameProblem::TooShort { .. } => "too_short",
+            UsernameProblem::TooLong { .. } => "too_long",
+            UsernameProblem::InvalidCharacters => "invalid_characters",
+            UsernameProblem::MixedScripts => "mixed_scripts",
+            UsernameProblem::Reserved => "reserved",
+            UsernameProblem::Confusable => "confusable",
+        }
+    }
+}
+
+impl fmt::Display for UsernameProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UsernameProblem::TooShort { min } => write!(f, "Username must be at least {} characters long", min),
+            UsernameProblem::TooLong { max } => write!(f, "Username must be at most {} characters long", max),
+            UsernameProblem::InvalidCharacters => {
+                write!(f, "Username may only contain letters, digits, '.', '_' and '-' and must start with a letter or digit")
+            }
+            UsernameProblem::MixedScripts => write!(f, "Username must not mix letters from different alphabets"),
+            UsernameProblem::Reserved => write!(f, "This username is reserved"),
+            UsernameProblem::Confusable => write!(f, "Username looks too much like an existing one"),
+        }
+    }
+}
+
+/// Offline copy of breached password hashes, split into range files like the Pwned
+/// Passwords range API: one file per 5 character SHA-1 prefix, named `21BD1` or
+/// `21BD1.txt`, holding `SUFFIX:COUNT` lines. `haveibeenpwned-downloader` writes
+/// exactly this. Only the one small file for a password's prefix is read.
+#[derive(Debug, Clone)]
+pub struct BreachedPasswords {
+    pub dir: PathBuf,
+}
+
+impl BreachedPasswords {
+    pub async fn contains(&self, password: &str) -> std::io::Result<bool> {
+        let digest = hex::encode_upper(Sha1::digest(password.as_bytes()));
+        let (prefix, suffix) = digest.split_at(RANGE_PREFIX_LEN);
+
+        let mut contents = None;
+        for name in [prefix.to_string(), format!("{}.txt", prefix)] {
+            match tokio::fs::read_to_string(self.dir.join(name)).await {
+                Ok(file) => {
+                    contents = Some(file);
+                    break;
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        // A partial list simply has no file for some prefixes
+        let Some(contents) = contents else {
+            return Ok(false);
+        };
+
+        Ok(contents.lines().any(|line| {
+            let hash = line.split(':').next().unwrap_or_default().trim();
+            hash.eq_ignore_ascii_case(suffix)
+        }))
+    }
+}
+
+/// What new passwords have to satisfy, on registration and on every change.
+#[derive(Debug, Clone)]
+pub struct PasswordPolicy {
+    /// In characters, not bytes.
+    pub min_length: usize,
+    /// Keeps hashing and strength estimation cheap.
+    pub max_length: usize,
+    /// Minimum zxcvbn score from 0 (guessable in a thousand tries) to 4, `0` turns
+    /// the estimation off.
+    pub min_strength: u8,
+    pub breached: Option<BreachedPasswords>,
+}
+
+impl PasswordPolicy {
+    /// `user_inputs` are words the password should not be built from, such as the username.
+    pub async fn check(&self, password: &str, user_inputs: &[&str]) -> Result<(), PasswordProblem> {
+        let length = password.chars().count();
+        if length < self.min_length {
+            return Err(PasswordProblem::TooShort { min: self.min_length });
+        }
+        if length > self.max_length {
+            return Err(PasswordProblem::TooLong { max: self.max_length });
+        }
+
+        if self.min_strength > 0 {
+            let entropy = zxcvbn::zxcvbn(password, user_inputs);
+            let score = u8::from(entropy.score());
+            if score < self.min_strength {
+                let warning = entropy.feedback().and_then(|f| f.warning()).map(|w| w.to_string());
+                return Err(PasswordProblem::TooWeak { score, min: self.min_strength, warning });
+            }
+        }
+
+        if let Some(breached) = &self.breached {
+            match breached.contains(password).await {
+                Ok(true) => return Err(PasswordProblem::Breached),
+                Ok(false) => {}
+                // Not worth locking everyone out of registering over a broken list
+                Err(e) => tracing::error!(error = %e, dir = %breached.dir.display(), "cannot read breached password list"),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Rules for new usernames, checked on the [`username::normalize`]d form. Existing
+/// accounts are not checked again.
+#[derive(Debug, Clone)]
+pub struct UsernamePolicy {
+    pub min_length: usize,
+    pub max_length: usize,
+    /// Also blocks lookalikes and other spellings of these. Administrators can still
+    /// create them with `brochat-admin create-user`.
+    pub reserved: Vec<String>,
+}
+
+impl UsernamePolicy {
+    pub fn check(&self, username: &str) -> Result<(), UsernameProblem> {
+        let length = username.chars().count();
+        if length < self.min_length {
+            return Err(UsernameProblem::TooShort { min: self.min_length });
+        }
+        if length > self.max_length {
+            return Err(UsernameProblem::TooLong { max: self.max_length });
+        }
+
+        let starts_well = username.chars().next().is_some_and(char::is_alphanumeric);
+        let allowed = username.chars().all(|c| c.is_alphanumeric() || matches!(c, '.' | '_' | '-'));
+        if !starts_well || !allowed {
+            return Err(UsernameProblem::InvalidCharacters);
+        }
+        if !username::has_safe_characters(username) {
+            return Err(UsernameProblem::MixedScripts);
+        }
+
+        if self.is_reserved(username) {
+            return Err(UsernameProblem::Reserved);
+        }
+
+        Ok(())
+    }
+
+    pub fn is_reserved(&self, username: &str) -> bool {
+        let skeleton = username::skeleton(username);
+        self.reserved.iter().any(|reserved| username::skeleton(reserved) == skeleton)
+    }
+}
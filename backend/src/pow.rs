@@ -0,0 +1,183 @@
+//! Hashcash-style puzzles that `/register` asks for, so every signup costs the client
+//! some CPU time without handing visitors to an outside CAPTCHA service.
+//!
+//! A challenge is a signed token. The client looks for a nonce such that the SHA-256
+//! of `<challenge>:<nonce>` starts with `difficulty` zero bits and sends both along
+//! with the registration. Nothing is stored until a challenge is solved, so fetching
+//! challenges can't fill up memory.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+use chrono::{DateTime, Duration, Utc};
+use jsonwebtoken::errors::ErrorKind;
+use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+/// Audience of challenge tokens, so neither session nor 2FA tokens pass as one.
+const POW_AUDIENCE: &str = "brochat-pow";
+
+/// Highest difficulty that can be configured. Every bit doubles the expected work,
+/// 32 would take a browser hours.
+pub const MAX_DIFFICULTY: u8 = 32;
+
+/// Longest nonce accepted, any counter fits easily.
+const MAX_NONCE_LEN: usize = 64;
+
+/// Solved challenges above this are pruned of the expired ones.
+const MAX_TRACKED: usize = 10_000;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct PowClaims {
+    jti: Uuid,
+    exp: usize,
+    aud: String,
+    /// Kept in the token, so changing the setting doesn't break challenges in flight.
+    difficulty: u8,
+}
+
+/// Body of `GET /register/challenge`.
+#[derive(Debug, Clone, Serialize)]
+pub struct Challenge {
+    pub challenge: String,
+    /// Leading zero bits the hash needs.
+    pub difficulty: u8,
+    pub algorithm: &'static str,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowProblem {
+    /// Not a challenge this server issued.
+    Invalid,
+    Expired,
+    AlreadyUsed,
+    WrongSolution,
+}
+
+impl PowProblem {
+    /// Stable reason for clients, next to the `invalid_pow` error code.
+    pub fn reason(&self) -> &'static str {
+        match self {
+            PowProblem::Invalid => "invalid",
+            PowProblem::Expired => "expired",
+            PowProblem::AlreadyUsed => "already_used",
+            PowProblem::WrongSolution => "wrong_solution",
+        }
+    }
+}
+
+impl fmt::Display for PowProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PowProblem::Invalid => write!(f, "Invalid registration challenge"),
+            PowProblem::Expired => write!(f, "Registration challenge expired, request a new one"),
+            PowProblem::AlreadyUsed => write!(f, "Registration challenge was already used, request a new one"),
+            PowProblem::WrongSolution => write!(f, "Registration challenge was not solved"),
+        }
+    }
+}
+
+/// A correct solution that hasn't been used yet, see [`ProofOfWork::redeem`].
+#[derive(Debug)]
+pub struct Solution {
+    jti: Uuid,
+    expires_at: DateTime<Utc>,
+}
+
+/// Issues and checks challenges. Solved ones are remembered in memory until they
+/// expire, a restart forgets them, which at worst lets a solution be used twice.
+pub struct ProofOfWork {
+    difficulty: u8,
+    ttl: Duration,
+    secret: String,
+    used: Mutex<HashMap<Uuid, DateTime<Utc>>>,
+}
+
+impl ProofOfWork {
+    pub fn new(difficulty: u8, ttl: Duration, secret: String) -> Self {
+        Self {
+            difficulty,
+            ttl,
+            secret,
+            used: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn issue(&self) -> Result<Challenge, jsonwebtoken::errors::Error> {
+        let expires_at = Utc::now() + self.ttl;
+        let claims = PowClaims {
+            jti: Uuid::new_v4(),
+            exp: expires_at.timestamp() as usize,
+            aud: POW_AUDIENCE.to_string(),
+            difficulty: self.difficulty,
+        };
+        let challenge = encode(&Header::default(), &claims, &EncodingKey::from_secret(self.secret.as_ref()))?;
+
+        Ok(Challenge { challenge, difficulty: self.difficulty, algorithm: "sha256", expires_at })
+    }
+
+    /// Checks a solution without using it up, so a registration that fails
+    /// validation can be sent again with the same one.
+    pub fn verify(&self, challenge: &str, nonce: &str) -> Result<Solution, PowProblem> {
+        let mut validation = Validation::new(Algorithm::HS256);
+        validation.set_audience(&[POW_AUDIENCE]);
+        validation.set_required_spec_claims(&["exp", "aud", "jti"]);
+        validation.leeway = 0;
+
+        let claims = decode::<PowClaims>(challenge, &DecodingKey::from_secret(self.secret.as_ref()), &validation)
+            .map_err(|e| match e.kind() {
+                ErrorKind::ExpiredSignature => PowProblem::Expired,
+                _ => PowProblem::Invalid,
+            })?
+            .claims;
+
+        if nonce.is_empty() || nonce.len() > MAX_NONCE_LEN || !is_solution(challenge, nonce, claims.difficulty) {
+            return Err(PowProblem::WrongSolution);
+        }
+
+        if self.used.lock().unwrap().contains_key(&claims.jti) {
+            return Err(PowProblem::AlreadyUsed);
+        }
+
+        let expires_at = DateTime::from_timestamp(claims.exp as i64, 0).unwrap_or_else(Utc::now);
+        Ok(Solution { jti: claims.jti, expires_at })
+    }
+
+    /// Uses up a verified solution, accepting each challenge once.
+    pub fn redeem(&self, solution: Solution) -> Result<(), PowProblem> {
+        let now = Utc::now();
+        let mut used = self.used.lock().unwrap();
+        if used.len() >= MAX_TRACKED {
+            used.retain(|_, expires_at| *expires_at > now);
+        }
+        if used.insert(solution.jti, solution.expires_at).is_some() {
+            return Err(PowProblem::AlreadyUsed);
+        }
+
+        Ok(())
+    }
+}
+
+/// Whether SHA-256 of `<challenge>:<nonce>` starts with `difficulty` zero bits.
+pub fn is_solution(challenge: &str, nonce: &str, difficulty: u8) -> bool {
+    let digest = Sha256::new()
+        .chain_update(challenge.as_bytes())
+        .chain_update(b":")
+        .chain_update(nonce.as_bytes())
+        .finalize();
+    leading_zero_bits(&digest) >= u32::from(difficulty)
+}
+
+fn leading_zero_bits(bytes: &[u8]) -> u32 {
+    let mut bits = 0;
+    for byte in bytes {
+        bits += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    bits
+}
@@ -0,0 +1,116 @@
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize, Deserializer};
+
+use crate::error::AppError;
+use crate::models::ProfileFields;
+
+/// Longest values in characters, the bio being the only one shown at length.
+const MAX_DISPLAY_NAME_LEN: usize = 64;
+const MAX_BIO_LEN: usize = 500;
+const MAX_PRONOUNS_LEN: usize = 32;
+const MAX_STATUS_TEXT_LEN: usize = 100;
+/// Room for emoji built from several code points, or a `:shortcode:`.
+const MAX_STATUS_EMOJI_LEN: usize = 32;
+
+/// Body of `PATCH /api/me`. Fields left out stay as they are, `null` or an empty
+/// string clears them.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProfileUpdate {
+    #[serde(default, deserialize_with = "nullable")]
+    pub display_name: Option<Option<String>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub bio: Option<Option<String>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub pronouns: Option<Option<String>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub status_text: Option<Option<String>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub status_emoji: Option<Option<String>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub status_expires_at: Option<Option<DateTime<Utc>>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub timezone: Option<Option<String>>,
+}
+
+impl ProfileUpdate {
+    /// Checks the new values and writes them over `fields`. A new status without an
+    /// expiry stays until it's changed, rather than inheriting the old one's.
+    pub fn apply(self, fields: &mut ProfileFields, now: DateTime<Utc>) -> Result<(), AppError> {
+        if let Some(display_name) = self.display_name {
+            fields.display_name = text("display_name", display_name, MAX_DISPLAY_NAME_LEN, false)?;
+        }
+        if let Some(bio) = self.bio {
+            fields.bio = text("bio", bio, MAX_BIO_LEN, true)?;
+        }
+        if let Some(pronouns) = self.pronouns {
+            fields.pronouns = text("pronouns", pronouns, MAX_PRONOUNS_LEN, false)?;
+        }
+
+        let status_changed = self.status_text.is_some() || self.status_emoji.is_some();
+        if let Some(status_text) = self.status_text {
+            fields.status_text = text("status_text", status_text, MAX_STATUS_TEXT_LEN, false)?;
+        }
+        if let Some(status_emoji) = self.status_emoji {
+            let status_emoji = text("status_emoji", status_emoji, MAX_STATUS_EMOJI_LEN, false)?;
+            if status_emoji.as_deref().is_some_and(|emoji| emoji.chars().any(char::is_whitespace)) {
+                return Err(AppError::BadRequest("status_emoji must not contain spaces".to_string()));
+            }
+            fields.status_emoji = status_emoji;
+        }
+        match self.status_expires_at {
+            Some(Some(expires_at)) if expires_at <= now => {
+                return Err(AppError::BadRequest("status_expires_at must be in the future".to_string()));
+            }
+            Some(expires_at) => fields.status_expires_at = expires_at,
+            None if status_changed => fields.status_expires_at = None,
+            None => {}
+        }
+        if fields.status_text.is_none() && fields.status_emoji.is_none() {
+            fields.status_expires_at = None;
+        }
+
+        if let Some(timezone) = self.timezone {
+            fields.timezone = match text("timezone", timezone, usize::MAX, false)? {
+                Some(timezone) => {
+                    let tz = Tz::from_str_insensitive(&timezone)
+                        .map_err(|_| AppError::BadRequest(format!("Unknown timezone {}", timezone)))?;
+                    Some(tz.name().to_string())
+                }
+                None => None,
+            };
+        }
+
+        Ok(())
+    }
+}
+
+/// Trimmed, `None` when empty. Only multiline fields may contain line breaks.
+fn text(field: &str, value: Option<String>, max_len: usize, multiline: bool) -> Result<Option<String>, AppError> {
+    let Some(value) = value else {
+        return Ok(None);
+    };
+    let value = value.trim().replace("\r\n", "\n");
+    if value.is_empty() {
+        return Ok(None);
+    }
+
+    if value.chars().count() > max_len {
+        return Err(AppError::BadRequest(format!("{} must be at most {} characters long", field, max_len)));
+    }
+    if value.chars().any(|c| c.is_control() && !(multiline && c == '\n')) {
+        return Err(AppError::BadRequest(format!("{} must not contain control characters", field)));
+    }
+
+    Ok(Some(value))
+}
+
+/// Tells a field set to `null` apart from one that was left out.
+fn nullable<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
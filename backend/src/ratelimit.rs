@@ -0,0 +1,176 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Settings for throttling failed logins, see [`LoginLimiter`].
+#[derive(Debug, Clone, Copy)]
+pub struct LoginLimits {
+    /// Failed attempts per username before backoff starts.
+    pub free_attempts: u32,
+    /// Failed attempts per client address before backoff starts. Higher than
+    /// `free_attempts` because many users can share an address.
+    pub ip_free_attempts: u32,
+    /// Wait after the first failure past the free attempts, doubled for every further one.
+    pub backoff_base: Duration,
+    pub backoff_max: Duration,
+    /// Failed attempts per username that lock it for `lockout_duration`.
+    pub lockout_threshold: u32,
+    pub lockout_duration: Duration,
+}
+
+/// Why a login attempt was turned away before the password was checked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Throttled {
+    /// Too many recent failures, try again after the backoff.
+    Backoff(Duration),
+    /// The username is locked after reaching the lockout threshold.
+    Locked(Duration),
+}
+
+/// A lockout that just started, to be recorded for administrators.
+#[derive(Debug, Clone, Copy)]
+pub struct NewLockout {
+    pub failures: u32,
+    pub duration: Duration,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Failures {
+    count: u32,
+    last_failure: Instant,
+    blocked_until: Option<Instant>,
+    locked: bool,
+}
+
+impl Failures {
+    fn remaining(&self, now: Instant) -> Option<Duration> {
+        self.blocked_until.filter(|until| *until > now).map(|until| until - now)
+    }
+}
+
+/// Entries above this are pruned of everything that no longer blocks anyone.
+const MAX_TRACKED: usize = 10_000;
+
+/// Failed logins per username and per client address, kept in memory. Restarting
+/// the server forgets them; recorded lockouts stay in the database.
+pub struct LoginLimiter {
+    limits: LoginLimits,
+    usernames: Mutex<HashMap<String, Failures>>,
+    addresses: Mutex<HashMap<IpAddr, Failures>>,
+}
+
+impl LoginLimiter {
+    pub fn new(limits: LoginLimits) -> Self {
+        Self {
+            limits,
+            usernames: Mutex::new(HashMap::new()),
+            addresses: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Failures are forgotten once nothing was tried for this long.
+    fn forget_after(&self) -> Duration {
+        self.limits.lockout_duration.max(self.limits.backoff_max)
+    }
+
+    /// Usernames are compared in their canonical form, so `Alice` and `alice` share a counter.
+    fn key(username: &str) -> String {
+        crate::username::canonical(username)
+    }
+
+    /// Checked before the password so throttled attempts don't cost an Argon2 hash.
+    pub fn check(&self, username: &str, ip: Option<IpAddr>) -> Result<(), Throttled> {
+        let now = Instant::now();
+
+        if let Some(failures) = self.usernames.lock().unwrap().get(&Self::key(username))
+            && let Some(remaining) = failures.remaining(now)
+        {
+            return Err(if failures.locked { Throttled::Locked(remaining) } else { Throttled::Backoff(remaining) });
+        }
+
+        if let Some(ip) = ip
+            && let Some(remaining) = self.addresses.lock().unwrap().get(&ip).and_then(|f| f.remaining(now))
+        {
+            return Err(Throttled::Backoff(remaining));
+        }
+
+        Ok(())
+    }
+
+    /// Counts a failed attempt. Returns the lockout when this failure started one.
+    pub fn record_failure(&self, username: &str, ip: Option<IpAddr>) -> Option<NewLockout> {
+        let now = Instant::now();
+        let limits = self.limits;
+        let forget_after = self.forget_after();
+
+        if let Some(ip) = ip {
+            let mut addresses = self.addresses.lock().unwrap();
+            let failures = bump(&mut addresses, ip, now, forget_after);
+            failures.blocked_until = backoff(&limits, failures.count, limits.ip_free_attempts).map(|wait| now + wait);
+        }
+
+        let mut usernames = self.usernames.lock().unwrap();
+        let failures = bump(&mut usernames, Self::key(username), now, forget_after);
+
+        if failures.count >= limits.lockout_threshold {
+            let started = !failures.locked;
+            failures.locked = true;
+            failures.blocked_until = Some(now + limits.lockout_duration);
+            return started.then_some(NewLockout { failures: failures.count, duration: limits.lockout_duration });
+        }
+
+        failures.blocked_until = backoff(&limits, failures.count, limits.free_attempts).map(|wait| now + wait);
+        None
+    }
+
+    /// Clears the username after a successful login. The address keeps its count, a
+    /// valid account must not reset the budget for guessing others.
+    pub fn record_success(&self, username: &str) {
+        self.usernames.lock().unwrap().remove(&Self::key(username));
+    }
+
+    /// Lifts a lockout early. Returns whether the username had any failures.
+    pub fn unlock(&self, username: &str) -> bool {
+        self.usernames.lock().unwrap().remove(&Self::key(username)).is_some()
+    }
+
+    /// Usernames that are locked right now, with the time left.
+    pub fn locked(&self) -> Vec<(String, Duration)> {
+        let now = Instant::now();
+        self.usernames
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, failures)| failures.locked)
+            .filter_map(|(username, failures)| Some((username.clone(), failures.remaining(now)?)))
+            .collect()
+    }
+}
+
+/// Increments the counter for `key`, starting over when the last failure is old.
+fn bump<K: std::hash::Hash + Eq>(
+    map: &mut HashMap<K, Failures>,
+    key: K,
+    now: Instant,
+    forget_after: Duration,
+) -> &mut Failures {
+    if map.len() >= MAX_TRACKED {
+        map.retain(|_, failures| now.duration_since(failures.last_failure) < forget_after);
+    }
+
+    let failures = map.entry(key).or_insert(Failures { count: 0, last_failure: now, blocked_until: None, locked: false });
+    if now.duration_since(failures.last_failure) >= forget_after && failures.remaining(now).is_none() {
+        *failures = Failures { count: 0, last_failure: now, blocked_until: None, locked: false };
+    }
+    failures.count += 1;
+    failures.last_failure = now;
+    failures
+}
+
+/// Exponential backoff once `count` is past the free attempts.
+fn backoff(limits: &LoginLimits, count: u32, free_attempts: u32) -> Option<Duration> {
+    let excess = count.checked_sub(free_attempts)?.checked_sub(1)?;
+    let wait = limits.backoff_base.saturating_mul(2u32.saturating_pow(excess.min(31)));
+    Some(wait.min(limits.backoff_max))
+}
@@ -0,0 +1,1023 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use tokio::sync::OwnedMutexGuard;
+use uuid::Uuid;
+
+use crate::models::{
+    ApiScopes, ApiToken, Attachment, Blob, Invite, LoginLockout, MessageModel, NewBlob, OrphanedAttachment, Profile,
+    ProfileFields, RecoveryCode, ScanStatus, StorageUsage, TwoFactor, UploadSession, User, UserFile, UserList,
+    upload_url_sha256,
+};
+use crate::username;
+use super::{
+    ApiTokenRepository, Backend, InviteRepository, InvitedRegistration, MessageRepository, NewAttachment, PoolStats, SessionLock,
+    TwoFactorRepository, UploadRepository, UserRepository,
+};
+
+const DEFAULT_AVATAR_URL: &str = "/images/default-avatar.png";
+
+struct StoredUser {
+    user: User,
+    canonical: String,
+    skeleton: String,
+    profile: ProfileFields,
+    used_bytes: i64,
+    quota_bytes: Option<i64>,
+    two_factor: TwoFactor,
+    invite_id: Option<Uuid>,
+}
+
+impl StoredUser {
+    /// Whether the unique `canonical` name is taken by this user, which those flagged
+    /// for a rename never hold, like the partial index of the SQL stores.
+    fn holds(&self, canonical: &str) -> bool {
+        self.canonical == canonical && !self.user.username_needs_rename
+    }
+
+    fn profile(&self) -> Profile {
+        Profile {
+            id: self.user.id,
+            username: self.user.username.clone(),
+            avatar_url: self.user.avatar_url.clone(),
+            is_bot: self.user.is_bot,
+            fields: self.profile.clone(),
+        }
+    }
+}
+
+struct StoredPasswordReset {
+    user_id: Uuid,
+    token_hash: String,
+    expires_at: DateTime<Utc>,
+    used: bool,
+}
+
+struct StoredInvite {
+    invite: Invite,
+    code_hash: String,
+}
+
+struct StoredApiToken {
+    token: ApiToken,
+    token_hash: String,
+}
+
+struct StoredRecoveryCode {
+    user_id: Uuid,
+    code: RecoveryCode,
+    used: bool,
+}
+
+#[derive(Default)]
+struct Store {
+    users: Vec<StoredUser>,
+    messages: Vec<MessageModel>,
+    blobs: HashMap<String, Blob>,
+    attachments: HashMap<Uuid, Attachment>,
+    sessions: HashMap<Uuid, UploadSession>,
+    lockouts: Vec<LoginLockout>,
+    recovery_codes: Vec<StoredRecoveryCode>,
+    password_resets: Vec<StoredPasswordReset>,
+    invites: Vec<StoredInvite>,
+    api_tokens: Vec<StoredApiToken>,
+}
+
+impl Store {
+    /// Exact spelling first, then the canonical form, preferring the user it belongs
+    /// to over ones flagged for a rename, like the SQL stores.
+    fn user_by_name(&mut self, username: &str) -> Option<&mut StoredUser> {
+        let canonical = username::canonical(username);
+        let index = self.users
+            .iter()
+            .position(|u| u.user.username == username)
+            .or_else(|| self.users.iter().position(|u| u.holds(&canonical)))
+            .or_else(|| self.users.iter().position(|u| u.canonical == canonical))?;
+        self.users.get_mut(index)
+    }
+
+    fn user_by_id(&mut self, id: Uuid) -> Option<&mut StoredUser> {
+        self.users.iter_mut().find(|u| u.user.id == id)
+    }
+
+    /// `None` when the name is taken.
+    fn create_user(&mut self, username: &str, password_hash: &str, is_bot: bool, invite_id: Option<Uuid>) -> Option<User> {
+        let canonical = username::canonical(username);
+        if self.users.iter().any(|u| u.user.username == username || u.holds(&canonical)) {
+            return None;
+        }
+
+        let user = User {
+            id: Uuid::new_v4(),
+            username: username.to_string(),
+            password_hash: password_hash.to_string(),
+            avatar_url: DEFAULT_AVATAR_URL.to_string(),
+            role: "user".to_string(),
+            disabled_at: None,
+            token_version: 0,
+            is_bot,
+            username_needs_rename: false,
+        };
+        self.users.push(StoredUser {
+            user: user.clone(),
+            canonical,
+            skeleton: username::skeleton(username),
+            profile: ProfileFields::default(),
+            used_bytes: 0,
+            quota_bytes: None,
+            two_factor: TwoFactor { totp_secret: None, totp_enabled_at: None, totp_last_step: None },
+            invite_id,
+        });
+        Some(user)
+    }
+
+    fn find_user(&self, id: Uuid) -> Option<&User> {
+        self.users.iter().map(|u| &u.user).find(|u| u.id == id)
+    }
+
+    /// Messages joined with their sender's and recipient's current names and the
+    /// sender's avatar, like the SQL queries do.
+    fn with_users(&self, filter: impl Fn(&MessageModel) -> bool) -> Vec<MessageModel> {
+        let mut messages: Vec<MessageModel> = self.messages
+            .iter()
+            .filter(|m| filter(m))
+            .filter_map(|m| {
+                let sender = self.find_user(m.sender_id)?;
+                let target_username = m.target_user_id
+                    .and_then(|id| self.find_user(id))
+                    .map(|u| u.username.clone());
+                Some(MessageModel {
+                    sender: sender.username.clone(),
+                    avatar_url: Some(sender.avatar_url.clone()),
+                    sender_is_bot: sender.is_bot,
+                    target_username,
+                    ..m.clone()
+                })
+            })
+            .collect();
+        messages.sort_by_key(|m| m.timestamp);
+        messages
+    }
+}
+
+/// Keeps everything in process memory, for tests that exercise handlers and the
+/// WebSocket code without a database. Mirrors the constraints of the Postgres schema
+/// that the server relies on, such as unique usernames and per user quotas.
+#[derive(Clone, Default)]
+pub struct MemoryRepository {
+    store: Arc<Mutex<Store>>,
+    /// One lock per upload session, standing in for `SELECT ... FOR UPDATE`.
+    session_locks: Arc<Mutex<HashMap<Uuid, Arc<tokio::sync::Mutex<()>>>>>,
+}
+
+impl MemoryRepository {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn store(&self) -> MutexGuard<'_, Store> {
+        // A panic while holding the lock can't leave the maps half updated
+        self.store.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+
+#[async_trait]
+impl Backend for MemoryRepository {
+    async fn ping(&self) -> Result<(), sqlx::Error> {
+        Ok(())
+    }
+
+    fn pool_stats(&self) -> Option<PoolStats> {
+        None
+    }
+}
+
+
+#[async_trait]
+impl UserRepository for MemoryRepository {
+    async fn create(&self, username: &str, password_hash: &str) -> Result<Option<User>, sqlx::Error> {
+        Ok(self.store().create_user(username, password_hash, false, None))
+    }
+
+    async fn create_bot(&self, username: &str, password_hash: &str) -> Result<Option<User>, sqlx::Error> {
+        Ok(self.store().create_user(username, password_hash, true, None))
+    }
+
+    async fn find_by_username(&self, username: &str) -> Result<Option<User>, sqlx::Error> {
+        Ok(self.store().user_by_name(username).map(|u| u.user.clone()))
+    }
+
+    async fn find_by_skeleton(&self, skeleton: &str) -> Result<Option<User>, sqlx::Error> {
+        Ok(self.store().users.iter().find(|u| u.skeleton == skeleton).map(|u| u.user.clone()))
+    }
+
+    async fn find_by_id(&self, id: Uuid) -> Result<Option<User>, sqlx::Error> {
+        Ok(self.store().user_by_id(id).map(|u| u.user.clone()))
+    }
+
+    async fn list(&self) -> Result<Vec<UserList>, sqlx::Error> {
+        Ok(self.store().users.iter().map(|u| UserList {
+            username: u.user.username.clone(),
+            display_name: u.profile.display_name.clone(),
+            avatar_url: u.user.avatar_url.clone(),
+        }).collect())
+    }
+
+    async fn profile(&self, id: Uuid) -> Result<Option<Profile>, sqlx::Error> {
+        Ok(self.store().user_by_id(id).map(|u| u.profile()))
+    }
+
+    async fn set_profile(&self, id: Uuid, fields: &ProfileFields) -> Result<Option<Profile>, sqlx::Error> {
+        Ok(self.store().user_by_id(id).map(|u| {
+            u.profile = fields.clone();
+            u.profile()
+        }))
+    }
+
+    async fn rename(&self, id: Uuid, username: &str) -> Result<Option<User>, sqlx::Error> {
+        let canonical = username::canonical(username);
+        let mut store = self.store();
+        if store.users.iter().any(|u| u.user.id != id && (u.user.username == username || u.holds(&canonical))) {
+            return Ok(None);
+        }
+
+        Ok(store.user_by_id(id).map(|u| {
+            u.user.username = username.to_string();
+            u.canonical = canonical;
+            u.skeleton = username::skeleton(username);
+            u.user.username_needs_rename = false;
+            u.user.clone()
+        }))
+    }
+
+    async fn set_avatar(&self, id: Uuid, avatar_url: &str) -> Result<Option<User>, sqlx::Error> {
+        Ok(self.store().user_by_id(id).map(|u| {
+            u.user.avatar_url = avatar_url.to_string();
+            u.user.clone()
+        }))
+    }
+
+    async fn set_password(&self, username: &str, password_hash: &str) -> Result<Option<User>, sqlx::Error> {
+        Ok(self.store().user_by_name(username).map(|u| {
+            u.user.password_hash = password_hash.to_string();
+            u.user.token_version += 1;
+            u.user.clone()
+        }))
+    }
+
+    async fn set_role(&self, username: &str, role: &str) -> Result<Option<User>, sqlx::Error> {
+        Ok(self.store().user_by_name(username).map(|u| {
+            u.user.role = role.to_string();
+            u.user.clone()
+        }))
+    }
+
+    async fn set_disabled(&self, username: &str, disabled: bool) -> Result<Option<User>, sqlx::Error> {
+        Ok(self.store().user_by_name(username).map(|u| {
+            u.user.disabled_at = if disabled { u.user.disabled_at.or(Some(Utc::now())) } else { None };
+            u.user.clone()
+        }))
+    }
+
+    async fn set_storage_quota(&self, username: &str, quota_bytes: Option<i64>) -> Result<Option<User>, sqlx::Error> {
+        Ok(self.store().user_by_name(username).map(|u| {
+            u.quota_bytes = quota_bytes;
+            u.user.clone()
+        }))
+    }
+
+    async fn storage_usage(&self, id: Uuid) -> Result<StorageUsage, sqlx::Error> {
+        self.store()
+            .user_by_id(id)
+            .map(|u| StorageUsage { used_bytes: u.used_bytes, quota_bytes: u.quota_bytes })
+            .ok_or(sqlx::Error::RowNotFound)
+    }
+
+    async fn avatar_urls(&self) -> Result<Vec<String>, sqlx::Error> {
+        Ok(self.store().users.iter().map(|u| u.user.avatar_url.clone()).collect())
+    }
+
+    async fn record_lockout(
+        &self,
+        username: &str,
+        ip_address: Option<&str>,
+        failures: i32,
+        locked_until: DateTime<Utc>
+    ) -> Result<LoginLockout, sqlx::Error> {
+        let lockout = LoginLockout {
+            id: Uuid::new_v4(),
+            username: username.to_string(),
+            ip_address: ip_address.map(str::to_string),
+            failures,
+            locked_until,
+            created_at: Utc::now(),
+        };
+        self.store().lockouts.push(lockout.clone());
+        Ok(lockout)
+    }
+
+    async fn recent_lockouts(&self, limit: i64) -> Result<Vec<LoginLockout>, sqlx::Error> {
+        let store = self.store();
+        Ok(store.lockouts.iter().rev().take(limit.max(0) as usize).cloned().collect())
+    }
+
+    async fn without_canonical_username(&self) -> Result<Vec<(Uuid, String)>, sqlx::Error> {
+        // Every user gets one on creation
+        Ok(Vec::new())
+    }
+
+    async fn set_canonical_username(&self, id: Uuid, canonical: &str, skeleton: &str) -> Result<bool, sqlx::Error> {
+        let mut store = self.store();
+        let taken = store.users.iter().any(|u| u.user.id != id && u.holds(canonical));
+        Ok(store.user_by_id(id).is_some_and(|u| {
+            u.canonical = canonical.to_string();
+            u.skeleton = skeleton.to_string();
+            u.user.username_needs_rename = taken;
+            !taken
+        }))
+    }
+
+    async fn needing_rename(&self) -> Result<Vec<String>, sqlx::Error> {
+        let mut names: Vec<_> = self.store().users.iter()
+            .filter(|u| u.user.username_needs_rename)
+            .map(|u| u.user.username.clone())
+            .collect();
+        names.sort();
+        Ok(names)
+    }
+
+    async fn create_password_reset(
+        &self,
+        user_id: Uuid,
+        token_hash: &str,
+        expires_at: DateTime<Utc>,
+    ) -> Result<(), sqlx::Error> {
+        let mut store = self.store();
+        store.password_resets.retain(|r| r.user_id != user_id || r.used);
+        store.password_resets.push(StoredPasswordReset {
+            user_id,
+            token_hash: token_hash.to_string(),
+            expires_at,
+            used: false,
+        });
+        Ok(())
+    }
+
+    async fn use_password_reset(&self, token_hash: &str) -> Result<Option<Uuid>, sqlx::Error> {
+        let now = Utc::now();
+        let mut store = self.store();
+        Ok(store
+            .password_resets
+            .iter_mut()
+            .find(|r| r.token_hash == token_hash && !r.used && r.expires_at > now)
+            .map(|r| {
+                r.used = true;
+                r.user_id
+            }))
+    }
+}
+
+
+#[async_trait]
+impl MessageRepository for MemoryRepository {
+    async fn save(
+        &self,
+        sender_id: Uuid,
+        message_type: &str,
+        message: &str,
+        timestamp: &DateTime<Utc>,
+        target_user_id: Option<Uuid>,
+        upload_url: Option<String>,
+    ) -> Result<(), sqlx::Error> {
+        let mut store = self.store();
+        // Stands in for the foreign keys
+        if store.find_user(sender_id).is_none() || target_user_id.is_some_and(|id| store.find_user(id).is_none()) {
+            return Err(sqlx::Error::RowNotFound);
+        }
+
+        // Names are filled in when reading, like the joins do
+        store.messages.push(MessageModel {
+            id: Uuid::new_v4(),
+            sender_id,
+            sender: String::new(),
+            target_user_id,
+            target_username: None,
+            message: message.to_string(),
+            message_type: message_type.to_string(),
+            timestamp: *timestamp,
+            avatar_url: None,
+            sender_is_bot: false,
+            upload_url,
+        });
+        Ok(())
+    }
+
+    async fn public(&self, limit: i64) -> Result<Vec<MessageModel>, sqlx::Error> {
+        let mut messages = self.store().with_users(|m| m.message_type == "chat");
+        messages.truncate(limit.max(0) as usize);
+        Ok(messages)
+    }
+
+    async fn direct(&self, current_user: Uuid, target_user: Uuid) -> Result<Vec<MessageModel>, sqlx::Error> {
+        Ok(self.store().with_users(|m| {
+            m.message_type == "dm"
+                && ((m.sender_id == current_user && m.target_user_id == Some(target_user))
+                    || (m.sender_id == target_user && m.target_user_id == Some(current_user)))
+        }))
+    }
+
+    async fn upload_urls(&self) -> Result<Vec<String>, sqlx::Error> {
+        let mut urls: Vec<String> = self.store()
+            .messages
+            .iter()
+            .filter_map(|m| m.upload_url.clone())
+            .filter(|url| !url.is_empty())
+            .collect();
+        urls.sort();
+        urls.dedup();
+        Ok(urls)
+    }
+
+    async fn delete_by_sender(&self, sender_id: Uuid) -> Result<u64, sqlx::Error> {
+        let mut store = self.store();
+        let before = store.messages.len();
+        store.messages.retain(|m| m.sender_id != sender_id);
+        Ok((before - store.messages.len()) as u64)
+    }
+}
+
+
+struct MemorySessionLock {
+    store: Arc<Mutex<Store>>,
+    session: UploadSession,
+    _guard: OwnedMutexGuard<()>,
+}
+
+#[async_trait]
+impl SessionLock for MemorySessionLock {
+    fn session(&self) -> &UploadSession {
+        &self.session
+    }
+
+    async fn set_received(
+        self: Box<Self>,
+        received_bytes: i64,
+        expires_at: DateTime<Utc>,
+    ) -> Result<UploadSession, sqlx::Error> {
+        let mut store = self.store.lock().unwrap_or_else(|e| e.into_inner());
+        let session = store.sessions.get_mut(&self.session.id).ok_or(sqlx::Error::RowNotFound)?;
+        session.received_bytes = received_bytes;
+        session.expires_at = expires_at;
+        Ok(session.clone())
+    }
+
+    async fn finish(
+        self: Box<Self>,
+        blob: &NewBlob,
+        default_quota: Option<i64>,
+        global_quota: Option<i64>,
+    ) -> Result<NewAttachment, sqlx::Error> {
+        let mut store = self.store.lock().unwrap_or_else(|e| e.into_inner());
+        let created = store.create_attachment(
+            blob,
+            Some(self.session.user_id),
+            &self.session.file_name,
+            default_quota,
+            global_quota,
+        );
+
+        if matches!(created, NewAttachment::Created(_)) {
+            store.sessions.remove(&self.session.id);
+        }
+        Ok(created)
+    }
+
+    async fn delete(self: Box<Self>) -> Result<(), sqlx::Error> {
+        self.store.lock().unwrap_or_else(|e| e.into_inner()).sessions.remove(&self.session.id);
+        Ok(())
+    }
+}
+
+
+#[async_trait]
+impl UploadRepository for MemoryRepository {
+    async fn find_blob(&self, sha256: &str) -> Result<Option<Blob>, sqlx::Error> {
+        Ok(self.store().blobs.get(sha256).cloned())
+    }
+
+    async fn find_blob_by_file_name(&self, file_name: &str) -> Result<Option<Blob>, sqlx::Error> {
+        let (sha256, extension) = file_name.split_once('.').unwrap_or((file_name, ""));
+        Ok(self.store().blobs.get(sha256).filter(|b| b.extension == extension).cloned())
+    }
+
+    async fn find_pending_scan(&self, limit: i64) -> Result<Vec<Blob>, sqlx::Error> {
+        let mut blobs: Vec<Blob> = self.store()
+            .blobs
+            .values()
+            .filter(|b| b.scan_status == ScanStatus::Pending)
+            .cloned()
+            .collect();
+        blobs.sort_by_key(|b| b.created_at);
+        blobs.truncate(limit.max(0) as usize);
+        Ok(blobs)
+    }
+
+    async fn set_scan_result(
+        &self,
+        sha256: &str,
+        status: ScanStatus,
+        signature: Option<&str>,
+    ) -> Result<Blob, sqlx::Error> {
+        let mut store = self.store();
+        let blob = store.blobs.get_mut(sha256).ok_or(sqlx::Error::RowNotFound)?;
+        blob.scan_status = status;
+        blob.scan_signature = signature.map(str::to_string);
+        blob.scanned_at = Some(Utc::now());
+        Ok(blob.clone())
+    }
+
+    async fn total_blob_size(&self) -> Result<i64, sqlx::Error> {
+        Ok(self.store().blobs.values().map(|b| b.size_bytes).sum())
+    }
+
+    async fn find_all_blobs(&self) -> Result<Vec<Blob>, sqlx::Error> {
+        Ok(self.store().blobs.values().cloned().collect())
+    }
+
+    async fn find_unreferenced_blobs(&self, cutoff: DateTime<Utc>) -> Result<Vec<Blob>, sqlx::Error> {
+        Ok(self.store()
+            .blobs
+            .values()
+            .filter(|b| b.ref_count == 0 && b.updated_at < cutoff)
+            .cloned()
+            .collect())
+    }
+
+    async fn delete_unreferenced_blobs(&self, cutoff: DateTime<Utc>) -> Result<Vec<Blob>, sqlx::Error> {
+        let mut store = self.store();
+        let unreferenced: Vec<String> = store.blobs
+            .values()
+            .filter(|b| b.ref_count == 0 && b.updated_at < cutoff)
+            .map(|b| b.sha256.clone())
+            .collect();
+
+        Ok(unreferenced.iter().filter_map(|sha256| store.blobs.remove(sha256)).collect())
+    }
+
+    async fn create_attachment(
+        &self,
+        blob: &NewBlob,
+        uploader_id: Option<Uuid>,
+        original_name: &str,
+        default_quota: Option<i64>,
+        global_quota: Option<i64>,
+    ) -> Result<NewAttachment, sqlx::Error> {
+        Ok(self.store().create_attachment(blob, uploader_id, original_name, default_quota, global_quota))
+    }
+
+    async fn find_orphaned_attachments(&self, cutoff: DateTime<Utc>) -> Result<Vec<OrphanedAttachment>, sqlx::Error> {
+        let store = self.store();
+
+        let mut orphaned: Vec<OrphanedAttachment> = store.attachments
+            .values()
+            .filter(|a| a.created_at < cutoff)
+            .filter_map(|a| {
+                let blob = store.blobs.get(&a.blob_sha256)?;
+                let sent = store.messages.iter().any(|m| {
+                    m.upload_url.as_deref().and_then(upload_url_sha256) == Some(blob.sha256.as_str())
+                        && a.uploader_id.is_none_or(|uploader| m.sender_id == uploader)
+                });
+                let suffix = format!("/uploads/{}", blob.file_name());
+                let avatar = store.users.iter().any(|u| {
+                    a.uploader_id == Some(u.user.id) && u.user.avatar_url.ends_with(&suffix)
+                });
+
+                (!sent && !avatar).then(|| OrphanedAttachment {
+                    id: a.id,
+                    original_name: a.original_name.clone(),
+                    sha256: blob.sha256.clone(),
+                    extension: blob.extension.clone(),
+                    size_bytes: blob.size_bytes,
+                    created_at: a.created_at,
+                })
+            })
+            .collect();
+
+        orphaned.sort_by_key(|a| a.created_at);
+        Ok(orphaned)
+    }
+
+    async fn delete_attachment(&self, id: Uuid) -> Result<(), sqlx::Error> {
+        let mut store = self.store();
+        let Some(attachment) = store.attachments.remove(&id) else {
+            return Ok(());
+        };
+
+        let blob = store.blobs.get_mut(&attachment.blob_sha256).ok_or(sqlx::Error::RowNotFound)?;
+        blob.ref_count -= 1;
+        blob.updated_at = Utc::now();
+        let size_bytes = blob.size_bytes;
+
+        if let Some(uploader) = attachment.uploader_id.and_then(|id| store.user_by_id(id)) {
+            uploader.used_bytes = (uploader.used_bytes - size_bytes).max(0);
+        }
+
+        Ok(())
+    }
+
+    async fn largest_attachments(&self, user_id: Uuid, limit: i64) -> Result<Vec<UserFile>, sqlx::Error> {
+        let store = self.store();
+
+        let mut files: Vec<UserFile> = store.attachments
+            .values()
+            .filter(|a| a.uploader_id == Some(user_id))
+            .filter_map(|a| {
+                let blob = store.blobs.get(&a.blob_sha256)?;
+                Some(UserFile {
+                    id: a.id,
+                    original_name: a.original_name.clone(),
+                    sha256: blob.sha256.clone(),
+                    extension: blob.extension.clone(),
+                    size_bytes: blob.size_bytes,
+                    created_at: a.created_at,
+                })
+            })
+            .collect();
+
+        files.sort_by(|a, b| b.size_bytes.cmp(&a.size_bytes).then(b.created_at.cmp(&a.created_at)));
+        files.truncate(limit.max(0) as usize);
+        Ok(files)
+    }
+
+    async fn create_session(
+        &self,
+        user_id: Uuid,
+        file_name: &str,
+        total_size: i64,
+        expected_sha256: Option<&str>,
+        expires_at: DateTime<Utc>,
+    ) -> Result<UploadSession, sqlx::Error> {
+        let session = UploadSession {
+            id: Uuid::new_v4(),
+            user_id,
+            file_name: file_name.to_string(),
+            total_size,
+            received_bytes: 0,
+            expected_sha256: expected_sha256.map(str::to_string),
+            created_at: Utc::now(),
+            expires_at,
+        };
+        self.store().sessions.insert(session.id, session.clone());
+        Ok(session)
+    }
+
+    async fn find_session(&self, id: Uuid, user_id: Uuid) -> Result<Option<UploadSession>, sqlx::Error> {
+        Ok(self.store()
+            .sessions
+            .get(&id)
+            .filter(|s| s.user_id == user_id && s.expires_at > Utc::now())
+            .cloned())
+    }
+
+    async fn lock_session(&self, id: Uuid, user_id: Uuid) -> Result<Option<Box<dyn SessionLock>>, sqlx::Error> {
+        let lock = self.session_locks
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(id)
+            .or_default()
+            .clone();
+        let guard = lock.lock_owned().await;
+
+        let Some(session) = self.find_session(id, user_id).await? else {
+            return Ok(None);
+        };
+
+        Ok(Some(Box::new(MemorySessionLock { store: self.store.clone(), session, _guard: guard })))
+    }
+
+    async fn delete_session(&self, id: Uuid) -> Result<(), sqlx::Error> {
+        self.store().sessions.remove(&id);
+        self.session_locks.lock().unwrap_or_else(|e| e.into_inner()).remove(&id);
+        Ok(())
+    }
+
+    async fn find_expired_sessions(&self) -> Result<Vec<UploadSession>, sqlx::Error> {
+        let now = Utc::now();
+        Ok(self.store().sessions.values().filter(|s| s.expires_at <= now).cloned().collect())
+    }
+
+    async fn delete_expired_sessions(&self) -> Result<Vec<UploadSession>, sqlx::Error> {
+        let expired = self.find_expired_sessions().await?;
+        for session in &expired {
+            self.delete_session(session.id).await?;
+        }
+        Ok(expired)
+    }
+}
+
+
+impl Store {
+    /// Body of [`UploadRepository::create_attachment`], shared with [`SessionLock::finish`].
+    fn create_attachment(
+        &mut self,
+        blob: &NewBlob,
+        uploader_id: Option<Uuid>,
+        original_name: &str,
+        default_quota: Option<i64>,
+        global_quota: Option<i64>,
+    ) -> NewAttachment {
+        if !self.blobs.contains_key(&blob.sha256) {
+            let total: i64 = self.blobs.values().map(|b| b.size_bytes).sum();
+            if global_quota.is_some_and(|limit| total + blob.size_bytes > limit) {
+                return NewAttachment::GlobalQuotaExceeded;
+            }
+        }
+
+        if let Some(uploader_id) = uploader_id {
+            let Some(uploader) = self.user_by_id(uploader_id) else {
+                return NewAttachment::QuotaExceeded;
+            };
+            let limit = uploader.quota_bytes.or(default_quota);
+            if limit.is_some_and(|limit| uploader.used_bytes + blob.size_bytes > limit) {
+                return NewAttachment::QuotaExceeded;
+            }
+            uploader.used_bytes += blob.size_bytes;
+        }
+
+        let now = Utc::now();
+        let stored = self.blobs
+            .entry(blob.sha256.clone())
+            .and_modify(|b| {
+                b.ref_count += 1;
+                b.updated_at = now;
+            })
+            .or_insert_with(|| Blob {
+                sha256: blob.sha256.clone(),
+                size_bytes: blob.size_bytes,
+                extension: blob.extension.clone(),
+                ref_count: 1,
+                created_at: now,
+                updated_at: now,
+                scan_status: blob.scan_status,
+                scan_signature: None,
+                scanned_at: None,
+            })
+            .clone();
+
+        let attachment = Attachment {
+            id: Uuid::new_v4(),
+            blob_sha256: blob.sha256.clone(),
+            uploader_id,
+            original_name: original_name.to_string(),
+            created_at: now,
+        };
+        self.attachments.insert(attachment.id, attachment.clone());
+
+        NewAttachment::Created(Box::new((attachment, stored)))
+    }
+
+    fn replace_recovery_codes(&mut self, user_id: Uuid, code_hashes: &[String]) {
+        self.recovery_codes.retain(|c| c.user_id != user_id);
+        self.recovery_codes.extend(code_hashes.iter().map(|code_hash| StoredRecoveryCode {
+            user_id,
+            code: RecoveryCode { id: Uuid::new_v4(), code_hash: code_hash.clone() },
+            used: false,
+        }));
+    }
+}
+
+#[async_trait]
+impl TwoFactorRepository for MemoryRepository {
+    async fn two_factor(&self, user_id: Uuid) -> Result<Option<TwoFactor>, sqlx::Error> {
+        Ok(self.store().user_by_id(user_id).map(|u| u.two_factor.clone()))
+    }
+
+    async fn set_totp_secret(&self, user_id: Uuid, secret: &str) -> Result<(), sqlx::Error> {
+        if let Some(user) = self.store().user_by_id(user_id) {
+            user.two_factor = TwoFactor { totp_secret: Some(secret.to_string()), totp_enabled_at: None, totp_last_step: None };
+        }
+        Ok(())
+    }
+
+    async fn enable_totp(&self, user_id: Uuid, step: i64, recovery_code_hashes: &[String]) -> Result<bool, sqlx::Error> {
+        let mut store = self.store();
+        let Some(user) = store.user_by_id(user_id) else {
+            return Ok(false);
+        };
+        if user.two_factor.totp_secret.is_none() || user.two_factor.totp_enabled_at.is_some() {
+            return Ok(false);
+        }
+
+        user.two_factor.totp_enabled_at = Some(Utc::now());
+        user.two_factor.totp_last_step = Some(step);
+        store.replace_recovery_codes(user_id, recovery_code_hashes);
+        Ok(true)
+    }
+
+    async fn disable_totp(&self, user_id: Uuid) -> Result<bool, sqlx::Error> {
+        let mut store = self.store();
+        let was_set = match store.user_by_id(user_id) {
+            Some(user) => {
+                let was_set = user.two_factor.totp_secret.is_some();
+                user.two_factor = TwoFactor { totp_secret: None, totp_enabled_at: None, totp_last_step: None };
+                was_set
+            }
+            None => false,
+        };
+        store.recovery_codes.retain(|c| c.user_id != user_id);
+        Ok(was_set)
+    }
+
+    async fn use_totp_step(&self, user_id: Uuid, step: i64) -> Result<bool, sqlx::Error> {
+        let mut store = self.store();
+        let Some(user) = store.user_by_id(user_id) else {
+            return Ok(false);
+        };
+        if user.two_factor.totp_last_step.is_some_and(|last| last >= step) {
+            return Ok(false);
+        }
+        user.two_factor.totp_last_step = Some(step);
+        Ok(true)
+    }
+
+    async fn unused_recovery_codes(&self, user_id: Uuid) -> Result<Vec<RecoveryCode>, sqlx::Error> {
+        Ok(self
+            .store()
+            .recovery_codes
+            .iter()
+            .filter(|c| c.user_id == user_id && !c.used)
+            .map(|c| c.code.clone())
+            .collect())
+    }
+
+    async fn use_recovery_code(&self, id: Uuid) -> Result<bool, sqlx::Error> {
+        let mut store = self.store();
+        match store.recovery_codes.iter_mut().find(|c| c.code.id == id && !c.used) {
+            Some(code) => {
+                code.used = true;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    async fn replace_recovery_codes(&self, user_id: Uuid, code_hashes: &[String]) -> Result<(), sqlx::Error> {
+        self.store().replace_recovery_codes(user_id, code_hashes);
+        Ok(())
+    }
+}
+
+
+#[async_trait]
+impl InviteRepository for MemoryRepository {
+    async fn create_invite(
+        &self,
+        created_by: Uuid,
+        code_hash: &str,
+        max_uses: i32,
+        expires_at: Option<DateTime<Utc>>,
+    ) -> Result<Invite, sqlx::Error> {
+        let mut store = self.store();
+        let invite = Invite {
+            id: Uuid::new_v4(),
+            created_by: Some(created_by),
+            created_by_username: store.find_user(created_by).map(|u| u.username.clone()),
+            max_uses,
+            uses: 0,
+            expires_at,
+            revoked_at: None,
+            created_at: Utc::now(),
+            invitees: Vec::new(),
+        };
+        store.invites.push(StoredInvite { invite: invite.clone(), code_hash: code_hash.to_string() });
+        Ok(invite)
+    }
+
+    async fn invites(&self) -> Result<Vec<Invite>, sqlx::Error> {
+        let store = self.store();
+        let mut invites: Vec<Invite> = store.invites
+            .iter()
+            .map(|stored| {
+                let mut invitees: Vec<String> = store.users
+                    .iter()
+                    .filter(|u| u.invite_id == Some(stored.invite.id))
+                    .map(|u| u.user.username.clone())
+                    .collect();
+                invitees.sort();
+                Invite {
+                    created_by_username: stored.invite.created_by
+                        .and_then(|id| store.find_user(id))
+                        .map(|u| u.username.clone()),
+                    invitees,
+                    ..stored.invite.clone()
+                }
+            })
+            .collect();
+        invites.sort_by_key(|invite| std::cmp::Reverse(invite.created_at));
+        Ok(invites)
+    }
+
+    async fn revoke_invite(&self, id: Uuid) -> Result<bool, sqlx::Error> {
+        let mut store = self.store();
+        match store.invites.iter_mut().find(|i| i.invite.id == id && i.invite.revoked_at.is_none()) {
+            Some(stored) => {
+                stored.invite.revoked_at = Some(Utc::now());
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    async fn register_with_invite(
+        &self,
+        username: &str,
+        password_hash: &str,
+        code_hash: &str,
+    ) -> Result<InvitedRegistration, sqlx::Error> {
+        let mut store = self.store();
+        let now = Utc::now();
+        let Some(index) = store.invites.iter().position(|i| i.code_hash == code_hash && i.invite.is_usable(now)) else {
+            return Ok(InvitedRegistration::InvalidInvite);
+        };
+
+        let invite_id = store.invites[index].invite.id;
+        match store.create_user(username, password_hash, false, Some(invite_id)) {
+            Some(user) => {
+                store.invites[index].invite.uses += 1;
+                Ok(InvitedRegistration::Created(user))
+            }
+            None => Ok(InvitedRegistration::UsernameTaken),
+        }
+    }
+}
+
+
+#[async_trait]
+impl ApiTokenRepository for MemoryRepository {
+    async fn create_api_token(
+        &self,
+        user_id: Uuid,
+        name: &str,
+        token_hash: &str,
+        scopes: &ApiScopes,
+        expires_at: Option<DateTime<Utc>>,
+    ) -> Result<ApiToken, sqlx::Error> {
+        let token = ApiToken {
+            id: Uuid::new_v4(),
+            user_id,
+            name: name.to_string(),
+            scopes: scopes.clone(),
+            expires_at,
+            last_used_at: None,
+            revoked_at: None,
+            created_at: Utc::now(),
+        };
+        self.store().api_tokens.push(StoredApiToken { token: token.clone(), token_hash: token_hash.to_string() });
+        Ok(token)
+    }
+
+    async fn api_tokens(&self, user_id: Uuid) -> Result<Vec<ApiToken>, sqlx::Error> {
+        let mut tokens: Vec<ApiToken> = self.store().api_tokens
+            .iter()
+            .filter(|t| t.token.user_id == user_id)
+            .map(|t| t.token.clone())
+            .collect();
+        tokens.sort_by_key(|token| std::cmp::Reverse(token.created_at));
+        Ok(tokens)
+    }
+
+    async fn find_api_token(&self, token_hash: &str) -> Result<Option<ApiToken>, sqlx::Error> {
+        let now = Utc::now();
+        Ok(self.store().api_tokens
+            .iter()
+            .find(|t| {
+                t.token_hash == token_hash
+                    && t.token.revoked_at.is_none()
+                    && t.token.expires_at.is_none_or(|expires_at| expires_at > now)
+            })
+            .map(|t| t.token.clone()))
+    }
+
+    async fn touch_api_token(&self, id: Uuid, used_at: DateTime<Utc>) -> Result<(), sqlx::Error> {
+        if let Some(stored) = self.store().api_tokens.iter_mut().find(|t| t.token.id == id) {
+            stored.token.last_used_at = Some(used_at);
+        }
+        Ok(())
+    }
+
+    async fn revoke_api_token(&self, id: Uuid, user_id: Option<Uuid>) -> Result<bool, sqlx::Error> {
+        let mut store = self.store();
+        let token = store.api_tokens.iter_mut().find(|t| {
+            t.token.id == id && user_id.is_none_or(|user_id| t.token.user_id == user_id) && t.token.revoked_at.is_none()
+        });
+        match token {
+            Some(stored) => {
+                stored.token.revoked_at = Some(Utc::now());
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+}
@@ -0,0 +1,429 @@
+//! Data access behind traits, so handlers and the WebSocket code don't care whether
+//! they talk to Postgres, SQLite or the in-memory store used in tests.
+
+mod memory;
+mod postgres;
+mod sqlite;
+mod timed;
+
+use std::sync::Arc;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+
+use crate::models::{
+    ApiScopes, ApiToken, Attachment, Blob, Invite, LoginLockout, MessageModel, NewBlob, OrphanedAttachment, Profile,
+    ProfileFields, RecoveryCode, ScanStatus, StorageUsage, TwoFactor, UploadSession, User, UserFile, UserList,
+};
+
+pub use memory::MemoryRepository;
+pub use postgres::PgRepository;
+pub use sqlite::SqliteRepository;
+use timed::Timed;
+
+/// Open and idle connections of a backend's pool.
+#[derive(Debug, Clone, Copy)]
+pub struct PoolStats {
+    pub size: u32,
+    pub idle: usize,
+}
+
+/// Checks on the store itself rather than any one kind of record.
+#[async_trait]
+pub trait Backend: Send + Sync {
+    /// Round trip to the store, used by the readiness probe.
+    async fn ping(&self) -> Result<(), sqlx::Error>;
+
+    /// Connection pool usage, `None` for stores without a pool.
+    fn pool_stats(&self) -> Option<PoolStats>;
+}
+
+#[async_trait]
+pub trait UserRepository: Send + Sync {
+    /// Creates a user with the default role and avatar. Returns `None` when the
+    /// username is taken.
+    async fn create(&self, username: &str, password_hash: &str) -> Result<Option<User>, sqlx::Error>;
+
+    /// Creates a bot, which has no usable password. Returns `None` when the username is taken.
+    async fn create_bot(&self, username: &str, password_hash: &str) -> Result<Option<User>, sqlx::Error>;
+
+    /// Matches the exact spelling first, then the canonical form, see [`crate::username`].
+    async fn find_by_username(&self, username: &str) -> Result<Option<User>, sqlx::Error>;
+
+    /// A user whose name looks like one with this [`crate::username::skeleton`].
+    async fn find_by_skeleton(&self, skeleton: &str) -> Result<Option<User>, sqlx::Error>;
+
+    async fn find_by_id(&self, id: Uuid) -> Result<Option<User>, sqlx::Error>;
+
+    async fn list(&self) -> Result<Vec<UserList>, sqlx::Error>;
+
+    /// `None` for an unknown user. Expired statuses are still in there, see
+    /// [`Profile::without_expired_status`].
+    async fn profile(&self, id: Uuid) -> Result<Option<Profile>, sqlx::Error>;
+
+    /// Replaces every field users edit themselves.
+    async fn set_profile(&self, id: Uuid, fields: &ProfileFields) -> Result<Option<Profile>, sqlx::Error>;
+
+    /// Returns `None` when the new name is taken, and also for an unknown user.
+    async fn rename(&self, id: Uuid, username: &str) -> Result<Option<User>, sqlx::Error>;
+
+    async fn set_avatar(&self, id: Uuid, avatar_url: &str) -> Result<Option<User>, sqlx::Error>;
+
+    /// Also bumps the token version, which signs out every session of the user.
+    async fn set_password(&self, username: &str, password_hash: &str) -> Result<Option<User>, sqlx::Error>;
+
+    async fn set_role(&self, username: &str, role: &str) -> Result<Option<User>, sqlx::Error>;
+
+    /// Disables or re-enables an account. Disabling keeps the original timestamp if
+    /// the account was already disabled.
+    async fn set_disabled(&self, username: &str, disabled: bool) -> Result<Option<User>, sqlx::Error>;
+
+    /// Sets the per user quota override, `None` falls back to the configured default.
+    async fn set_storage_quota(&self, username: &str, quota_bytes: Option<i64>) -> Result<Option<User>, sqlx::Error>;
+
+    /// Fails with `RowNotFound` for an unknown user.
+    async fn storage_usage(&self, id: Uuid) -> Result<StorageUsage, sqlx::Error>;
+
+    /// Every avatar URL currently in use.
+    async fn avatar_urls(&self) -> Result<Vec<String>, sqlx::Error>;
+
+    async fn record_lockout(
+        &self,
+        username: &str,
+        ip_address: Option<&str>,
+        failures: i32,
+        locked_until: DateTime<Utc>,
+    ) -> Result<LoginLockout, sqlx::Error>;
+
+    /// Users from before canonical usernames were stored, oldest first by their first
+    /// message, see [`crate::username::backfill`].
+    async fn without_canonical_username(&self) -> Result<Vec<(Uuid, String)>, sqlx::Error>;
+
+    /// Stores both forms either way. Returns `false` when another user already has this
+    /// canonical name, this one is then flagged with [`User::username_needs_rename`].
+    async fn set_canonical_username(&self, id: Uuid, canonical: &str, skeleton: &str) -> Result<bool, sqlx::Error>;
+
+    /// Names of the users flagged by [`UserRepository::set_canonical_username`].
+    async fn needing_rename(&self) -> Result<Vec<String>, sqlx::Error>;
+
+    /// Newest first.
+    async fn recent_lockouts(&self, limit: i64) -> Result<Vec<LoginLockout>, sqlx::Error>;
+
+    /// Stores a password reset token by its hash, dropping the user's earlier unused ones.
+    async fn create_password_reset(
+        &self,
+        user_id: Uuid,
+        token_hash: &str,
+        expires_at: DateTime<Utc>,
+    ) -> Result<(), sqlx::Error>;
+
+    /// Marks an unused, unexpired reset token as used and returns its user.
+    async fn use_password_reset(&self, token_hash: &str) -> Result<Option<Uuid>, sqlx::Error>;
+}
+
+/// Outcome of [`InviteRepository::register_with_invite`].
+#[derive(Debug)]
+pub enum InvitedRegistration {
+    Created(User),
+    UsernameTaken,
+    /// Unknown, revoked, expired or used up. The invite is left untouched.
+    InvalidInvite,
+}
+
+#[async_trait]
+pub trait InviteRepository: Send + Sync {
+    /// Stores an invite by the hash of its code.
+    async fn create_invite(
+        &self,
+        created_by: Uuid,
+        code_hash: &str,
+        max_uses: i32,
+        expires_at: Option<DateTime<Utc>>,
+    ) -> Result<Invite, sqlx::Error>;
+
+    /// Every invite with the users who registered with it, newest first.
+    async fn invites(&self) -> Result<Vec<Invite>, sqlx::Error>;
+
+    /// Returns `false` for an unknown or already revoked invite.
+    async fn revoke_invite(&self, id: Uuid) -> Result<bool, sqlx::Error>;
+
+    /// Uses up one registration of a usable invite and creates the user in the same
+    /// transaction, remembering who invited them.
+    async fn register_with_invite(
+        &self,
+        username: &str,
+        password_hash: &str,
+        code_hash: &str,
+    ) -> Result<InvitedRegistration, sqlx::Error>;
+}
+
+#[async_trait]
+pub trait ApiTokenRepository: Send + Sync {
+    /// Stores a token by its hash.
+    async fn create_api_token(
+        &self,
+        user_id: Uuid,
+        name: &str,
+        token_hash: &str,
+        scopes: &ApiScopes,
+        expires_at: Option<DateTime<Utc>>,
+    ) -> Result<ApiToken, sqlx::Error>;
+
+    /// The user's tokens including revoked and expired ones, newest first.
+    async fn api_tokens(&self, user_id: Uuid) -> Result<Vec<ApiToken>, sqlx::Error>;
+
+    /// `None` for an unknown, revoked or expired token.
+    async fn find_api_token(&self, token_hash: &str) -> Result<Option<ApiToken>, sqlx::Error>;
+
+    async fn touch_api_token(&self, id: Uuid, used_at: DateTime<Utc>) -> Result<(), sqlx::Error>;
+
+    /// Revokes a token of `user_id`, or of anyone when `None`. Returns `false` for an
+    /// unknown or already revoked token.
+    async fn revoke_api_token(&self, id: Uuid, user_id: Option<Uuid>) -> Result<bool, sqlx::Error>;
+}
+
+#[async_trait]
+pub trait MessageRepository: Send + Sync {
+    async fn save(
+        &self,
+        sender_id: Uuid,
+        message_type: &str,
+        message: &str,
+        timestamp: &DateTime<Utc>,
+        target_user_id: Option<Uuid>,
+        upload_url: Option<String>,
+    ) -> Result<(), sqlx::Error>;
+
+    /// Public messages, oldest first, at most `limit` of them.
+    async fn public(&self, limit: i64) -> Result<Vec<MessageModel>, sqlx::Error>;
+
+    /// Direct messages between two users in either direction, oldest first.
+    async fn direct(&self, current_user: Uuid, target_user: Uuid) -> Result<Vec<MessageModel>, sqlx::Error>;
+
+    /// Every upload URL attached to a saved message.
+    async fn upload_urls(&self) -> Result<Vec<String>, sqlx::Error>;
+
+    /// Deletes every message the user sent, returning how many were removed.
+    async fn delete_by_sender(&self, sender_id: Uuid) -> Result<u64, sqlx::Error>;
+}
+
+#[async_trait]
+pub trait TwoFactorRepository: Send + Sync {
+    /// `None` for an unknown user.
+    async fn two_factor(&self, user_id: Uuid) -> Result<Option<TwoFactor>, sqlx::Error>;
+
+    /// Stores a new secret that is not enforced until [`TwoFactorRepository::enable_totp`].
+    async fn set_totp_secret(&self, user_id: Uuid, secret: &str) -> Result<(), sqlx::Error>;
+
+    /// Enforces the stored secret, marks `step` as used and replaces the recovery
+    /// codes. Returns `false` when there is no pending secret.
+    async fn enable_totp(&self, user_id: Uuid, step: i64, recovery_code_hashes: &[String]) -> Result<bool, sqlx::Error>;
+
+    /// Removes the secret and every recovery code. Returns `false` when 2FA was not set up.
+    async fn disable_totp(&self, user_id: Uuid) -> Result<bool, sqlx::Error>;
+
+    /// Marks `step` as used unless it or a later step already was, so each code
+    /// works once. Returns whether the step was accepted.
+    async fn use_totp_step(&self, user_id: Uuid, step: i64) -> Result<bool, sqlx::Error>;
+
+    async fn unused_recovery_codes(&self, user_id: Uuid) -> Result<Vec<RecoveryCode>, sqlx::Error>;
+
+    /// Returns `false` when the code was used in the meantime.
+    async fn use_recovery_code(&self, id: Uuid) -> Result<bool, sqlx::Error>;
+
+    async fn replace_recovery_codes(&self, user_id: Uuid, code_hashes: &[String]) -> Result<(), sqlx::Error>;
+}
+
+/// Outcome of [`UploadRepository::create_attachment`]. Nothing is changed unless the
+/// attachment was created.
+#[derive(Debug)]
+pub enum NewAttachment {
+    Created(Box<(Attachment, Blob)>),
+    /// The uploader would go past their quota.
+    QuotaExceeded,
+    /// The content is new and would take all blobs together past the global quota.
+    GlobalQuotaExceeded,
+}
+
+/// An upload session held exclusively until one of the consuming methods is called
+/// or the lock is dropped, so concurrent chunks for one session are applied in order
+/// and a session is finalized once.
+#[async_trait]
+pub trait SessionLock: Send {
+    fn session(&self) -> &UploadSession;
+
+    /// Stores the new offset and releases the lock.
+    async fn set_received(
+        self: Box<Self>,
+        received_bytes: i64,
+        expires_at: DateTime<Utc>,
+    ) -> Result<UploadSession, sqlx::Error>;
+
+    /// Records the finished upload as an attachment of the session's user, like
+    /// [`UploadRepository::create_attachment`], and deletes the session in the same
+    /// transaction. A refused upload keeps its session.
+    async fn finish(
+        self: Box<Self>,
+        blob: &NewBlob,
+        default_quota: Option<i64>,
+        global_quota: Option<i64>,
+    ) -> Result<NewAttachment, sqlx::Error>;
+
+    /// Deletes the session and releases the lock.
+    async fn delete(self: Box<Self>) -> Result<(), sqlx::Error>;
+}
+
+#[async_trait]
+pub trait UploadRepository: Send + Sync {
+    async fn find_blob(&self, sha256: &str) -> Result<Option<Blob>, sqlx::Error>;
+
+    /// The blob stored under `file_name` in `/uploads`, if any.
+    async fn find_blob_by_file_name(&self, file_name: &str) -> Result<Option<Blob>, sqlx::Error>;
+
+    /// Blobs still waiting for a malware scan, oldest first.
+    async fn find_pending_scan(&self, limit: i64) -> Result<Vec<Blob>, sqlx::Error>;
+
+    async fn set_scan_result(
+        &self,
+        sha256: &str,
+        status: ScanStatus,
+        signature: Option<&str>,
+    ) -> Result<Blob, sqlx::Error>;
+
+    /// Bytes taken up on disk by all stored blobs, as charged against the global quota.
+    async fn total_blob_size(&self) -> Result<i64, sqlx::Error>;
+
+    async fn find_all_blobs(&self) -> Result<Vec<Blob>, sqlx::Error>;
+
+    /// Blobs that [`UploadRepository::delete_unreferenced_blobs`] would remove.
+    async fn find_unreferenced_blobs(&self, cutoff: DateTime<Utc>) -> Result<Vec<Blob>, sqlx::Error>;
+
+    /// Removes blobs that have had no references since `cutoff` and returns them so
+    /// the caller can delete the files.
+    async fn delete_unreferenced_blobs(&self, cutoff: DateTime<Utc>) -> Result<Vec<Blob>, sqlx::Error>;
+
+    /// Charges the uploader and records a new reference to the blob, creating it the
+    /// first time this content is seen, all or nothing. The uploader's limit is their
+    /// own override, else `default_quota`. Only new content counts against
+    /// `global_quota`, a reference to a stored blob takes no more space.
+    async fn create_attachment(
+        &self,
+        blob: &NewBlob,
+        uploader_id: Option<Uuid>,
+        original_name: &str,
+        default_quota: Option<i64>,
+        global_quota: Option<i64>,
+    ) -> Result<NewAttachment, sqlx::Error>;
+
+    /// Attachments created before `cutoff` whose file never made it into a message
+    /// sent by the uploader and isn't the uploader's avatar.
+    async fn find_orphaned_attachments(&self, cutoff: DateTime<Utc>) -> Result<Vec<OrphanedAttachment>, sqlx::Error>;
+
+    /// Deletes an attachment, releasing its reference on the blob and giving the bytes
+    /// back to the uploader's quota.
+    async fn delete_attachment(&self, id: Uuid) -> Result<(), sqlx::Error>;
+
+    /// The user's attachments, biggest first.
+    async fn largest_attachments(&self, user_id: Uuid, limit: i64) -> Result<Vec<UserFile>, sqlx::Error>;
+
+    async fn create_session(
+        &self,
+        user_id: Uuid,
+        file_name: &str,
+        total_size: i64,
+        expected_sha256: Option<&str>,
+        expires_at: DateTime<Utc>,
+    ) -> Result<UploadSession, sqlx::Error>;
+
+    /// The user's session `id` unless it has expired.
+    async fn find_session(&self, id: Uuid, user_id: Uuid) -> Result<Option<UploadSession>, sqlx::Error>;
+
+    /// Same as [`UploadRepository::find_session`] but holds the session until the
+    /// returned lock is released.
+    async fn lock_session(&self, id: Uuid, user_id: Uuid) -> Result<Option<Box<dyn SessionLock>>, sqlx::Error>;
+
+    async fn delete_session(&self, id: Uuid) -> Result<(), sqlx::Error>;
+
+    async fn find_expired_sessions(&self) -> Result<Vec<UploadSession>, sqlx::Error>;
+
+    /// Removes sessions past their expiry and returns them so their partial files can be deleted.
+    async fn delete_expired_sessions(&self) -> Result<Vec<UploadSession>, sqlx::Error>;
+}
+
+/// The repositories the server works with, kept in [`crate::ws::ChatState`].
+#[derive(Clone)]
+pub struct Repositories {
+    pub backend: Arc<dyn Backend>,
+    pub users: Arc<dyn UserRepository>,
+    pub messages: Arc<dyn MessageRepository>,
+    pub uploads: Arc<dyn UploadRepository>,
+    pub two_factor: Arc<dyn TwoFactorRepository>,
+    pub invites: Arc<dyn InviteRepository>,
+    pub api_tokens: Arc<dyn ApiTokenRepository>,
+}
+
+impl Repositories {
+    fn from_store<T>(store: T) -> Self
+    where
+        T: Backend + UserRepository + MessageRepository + UploadRepository + TwoFactorRepository + InviteRepository
+            + ApiTokenRepository + 'static,
+    {
+        let store = Arc::new(Timed(store));
+        Self {
+            backend: store.clone(),
+            users: store.clone(),
+            messages: store.clone(),
+            uploads: store.clone(),
+            two_factor: store.clone(),
+            invites: store.clone(),
+            api_tokens: store,
+        }
+    }
+
+    pub fn postgres(pool: sqlx::PgPool) -> Self {
+        Self::from_store(PgRepository::new(pool))
+    }
+
+    pub fn sqlite(pool: sqlx::SqlitePool) -> Self {
+        Self::from_store(SqliteRepository::new(pool))
+    }
+
+    /// Everything kept in process memory and lost on exit, for tests.
+    pub fn in_memory() -> Self {
+        Self::from_store(MemoryRepository::new())
+    }
+}
+
+
+// SQL shared by the Postgres and SQLite stores
+
+/// Id of the user a typed name refers to, with `$1` the name and `$2` its canonical
+/// form. The exact spelling wins, for old accounts whose canonical name collides
+/// with another one.
+const USER_ID_BY_NAME: &str =
+    "SELECT id FROM users WHERE username = $1 OR username_canonical = $2 \
+     ORDER BY username = $1 DESC, username_needs_rename LIMIT 1";
+
+/// Columns of [`Profile`].
+const PROFILE_COLUMNS: &str = "id, username, avatar_url, is_bot, display_name, bio, pronouns, status_text, status_emoji, \
+    status_expires_at, timezone";
+
+/// Selects [`Invite`]s without their invitees, filtered by appending a `WHERE`.
+const INVITE_SELECT: &str = "SELECT invites.id, invites.created_by, creators.username AS created_by_username, \
+    invites.max_uses, invites.uses, invites.expires_at, invites.revoked_at, invites.created_at \
+    FROM invites LEFT JOIN users creators ON creators.id = invites.created_by";
+
+/// Selects [`ApiToken`]s, filtered by appending a `WHERE`.
+const API_TOKEN_SELECT: &str = "SELECT id, user_id, name, scopes, expires_at, last_used_at, revoked_at, created_at \
+    FROM api_tokens";
+
+/// Users who registered with an invite, as `(invite_id, username)`.
+const INVITEES: &str = "SELECT invite_id, username FROM users WHERE invite_id IS NOT NULL ORDER BY username";
+
+fn attach_invitees(invites: &mut [Invite], invitees: Vec<(Uuid, String)>) {
+    for (invite_id, username) in invitees {
+        if let Some(invite) = invites.iter_mut().find(|i| i.id == invite_id) {
+            invite.invitees.push(username);
+        }
+    }
+}
@@ -0,0 +1,1394 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use sqlx::{PgConnection, PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+use crate::models::{
+    ApiScopes, ApiToken, Attachment, Blob, Export, ExportedMessage, ExportedUser, ImportSummary, Invite, LoginLockout,
+    MessageModel, NewBlob, OrphanedAttachment, Profile, ProfileFields, RecoveryCode, ScanStatus, ServerStats,
+    StorageUsage, TwoFactor, UploadSession, User, UserFile, UserList, upload_url_sha256,
+};
+use crate::username;
+use super::{
+    attach_invitees, ApiTokenRepository, Backend, InviteRepository, InvitedRegistration, MessageRepository, NewAttachment,
+    PoolStats, SessionLock, TwoFactorRepository, UploadRepository, UserRepository, API_TOKEN_SELECT, INVITEES, INVITE_SELECT,
+    PROFILE_COLUMNS, USER_ID_BY_NAME,
+};
+
+/// The production store.
+#[derive(Clone)]
+pub struct PgRepository {
+    pool: PgPool,
+}
+
+impl PgRepository {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+
+    pub fn pool(&self) -> &PgPool {
+        &self.pool
+    }
+
+    /// `None` when the username is taken.
+    async fn insert_user(&self, username: &str, password_hash: &str, is_bot: bool) -> Result<Option<User>, sqlx::Error> {
+        let id = Uuid::new_v4();
+        let query = sqlx::query_as::<_, User>(
+            r#"
+            INSERT INTO users (id, username, password_hash, username_canonical, username_skeleton, is_bot)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            RETURNING *
+            "#
+        )
+        .bind(id)
+        .bind(username)
+        .bind(password_hash)
+        .bind(username::canonical(username))
+        .bind(username::skeleton(username))
+        .bind(is_bot)
+        .fetch_one(&self.pool);
+
+        match query.await {
+            Ok(user) => Ok(Some(user)),
+            Err(sqlx::Error::Database(e)) if e.is_unique_violation() => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Row counts and sizes printed by `brochat-admin stats`.
+    pub async fn stats(&self) -> Result<ServerStats, sqlx::Error> {
+        sqlx::query_as::<_, ServerStats>(
+            r#"
+            SELECT
+                (SELECT COUNT(*) FROM users) AS users,
+                (SELECT COUNT(*) FROM users WHERE role = 'admin') AS admins,
+                (SELECT COUNT(*) FROM users WHERE disabled_at IS NOT NULL) AS disabled_users,
+                (SELECT COUNT(*) FROM messages WHERE message_type = 'chat') AS public_messages,
+                (SELECT COUNT(*) FROM messages WHERE message_type = 'dm') AS direct_messages,
+                (SELECT COUNT(*) FROM attachments) AS attachments,
+                (SELECT COUNT(*) FROM blobs) AS blobs,
+                (SELECT COALESCE(SUM(size_bytes), 0)::BIGINT FROM blobs) AS blob_bytes,
+                (SELECT COUNT(*) FROM blobs WHERE scan_status = 'pending') AS pending_scans,
+                (SELECT COUNT(*) FROM upload_sessions) AS upload_sessions
+            "#
+        )
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    pub async fn export(&self, schema_version: i64) -> Result<Export, sqlx::Error> {
+        let users = sqlx::query_as::<_, ExportedUser>(
+            r#"
+            SELECT id, username, password_hash, avatar_url, role, storage_quota_bytes, disabled_at, is_bot
+            FROM users
+            ORDER BY username
+            "#
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        let messages = sqlx::query_as::<_, ExportedMessage>(
+            r#"
+            SELECT
+                messages.id,
+                COALESCE(sender.username, messages.legacy_sender) AS sender,
+                COALESCE(target.username, messages.legacy_target_username) AS target_username,
+                messages.message_type,
+                messages.message,
+                messages.upload_url,
+                messages.timestamp
+            FROM messages
+            LEFT JOIN users AS sender ON sender.id = messages.sender_id
+            LEFT JOIN users AS target ON target.id = messages.target_user_id
+            ORDER BY messages.timestamp
+            "#
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(Export { exported_at: Utc::now(), schema_version, users, messages })
+    }
+
+    /// Inserts everything in one transaction. Users and messages whose id or username
+    /// already exists are left alone, so importing the same file twice is harmless.
+    /// Messages from or to a username that is in neither the file nor the database
+    /// are skipped.
+    pub async fn import(&self, export: &Export) -> Result<ImportSummary, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        let mut summary = ImportSummary::default();
+
+        for user in &export.users {
+            let result = sqlx::query(
+                r#"
+                INSERT INTO users (
+                    id, username, password_hash, avatar_url, role, storage_quota_bytes, disabled_at,
+                    username_canonical, username_skeleton, is_bot
+                )
+                VALUES ($1, $2, $3, COALESCE($4, '/images/default-avatar.png'), $5, $6, $7, $8, $9, $10)
+                ON CONFLICT DO NOTHING
+                "#
+            )
+            .bind(user.id)
+            .bind(&user.username)
+            .bind(&user.password_hash)
+            .bind(&user.avatar_url)
+            .bind(&user.role)
+            .bind(user.storage_quota_bytes)
+            .bind(user.disabled_at)
+            .bind(username::canonical(&user.username))
+            .bind(username::skeleton(&user.username))
+            .bind(user.is_bot)
+            .execute(&mut *tx)
+            .await?;
+            summary.users += result.rows_affected();
+        }
+
+        for message in &export.messages {
+            let result = sqlx::query(
+                r#"
+                INSERT INTO messages (id, sender_id, target_user_id, message_type, message, upload_url, upload_sha256, timestamp)
+                SELECT $1, sender.id, CASE WHEN $4 = 'dm' THEN target.id END, $4, $5, $6, $8, $7
+                FROM users AS sender
+                LEFT JOIN users AS target ON target.username = $3
+                WHERE sender.username = $2 AND ($4 = 'chat' OR target.id IS NOT NULL)
+                ON CONFLICT DO NOTHING
+                "#
+            )
+            .bind(message.id)
+            .bind(&message.sender)
+            .bind(&message.target_username)
+            .bind(&message.message_type)
+            .bind(&message.message)
+            .bind(&message.upload_url)
+            .bind(message.timestamp)
+            .bind(message.upload_url.as_deref().and_then(upload_url_sha256))
+            .execute(&mut *tx)
+            .await?;
+            summary.messages += result.rows_affected();
+        }
+
+        tx.commit().await?;
+        Ok(summary)
+    }
+}
+
+
+#[async_trait]
+impl Backend for PgRepository {
+    async fn ping(&self) -> Result<(), sqlx::Error> {
+        sqlx::query("SELECT 1").execute(&self.pool).await?;
+        Ok(())
+    }
+
+    fn pool_stats(&self) -> Option<PoolStats> {
+        Some(PoolStats { size: self.pool.size(), idle: self.pool.num_idle() })
+    }
+}
+
+
+#[async_trait]
+impl UserRepository for PgRepository {
+    async fn create(&self, username: &str, password_hash: &str) -> Result<Option<User>, sqlx::Error> {
+        self.insert_user(username, password_hash, false).await
+    }
+
+
+    async fn create_bot(&self, username: &str, password_hash: &str) -> Result<Option<User>, sqlx::Error> {
+        self.insert_user(username, password_hash, true).await
+    }
+
+
+    async fn find_by_username(&self, username: &str) -> Result<Option<User>, sqlx::Error> {
+        sqlx::query_as(
+            "SELECT * FROM users WHERE username = $1 OR username_canonical = $2 \
+             ORDER BY username = $1 DESC, username_needs_rename LIMIT 1"
+        )
+        .bind(username)
+        .bind(username::canonical(username))
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+
+    async fn find_by_skeleton(&self, skeleton: &str) -> Result<Option<User>, sqlx::Error> {
+        sqlx::query_as::<_, User>(
+            "SELECT * FROM users WHERE username_skeleton = $1 LIMIT 1"
+        )
+        .bind(skeleton)
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+
+    async fn find_by_id(&self, id: Uuid) -> Result<Option<User>, sqlx::Error> {
+        sqlx::query_as(
+            "SELECT id, username, password_hash, avatar_url, role, disabled_at, token_version, is_bot, username_needs_rename \
+             FROM users WHERE id = $1"
+        )
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+
+    async fn list(&self) -> Result<Vec<UserList>, sqlx::Error> {
+        sqlx::query_as::<_, UserList>(
+            "SELECT username, display_name, avatar_url FROM users"
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+
+
+    async fn profile(&self, id: Uuid) -> Result<Option<Profile>, sqlx::Error> {
+        sqlx::query_as::<_, Profile>(&format!("SELECT {} FROM users WHERE id = $1", PROFILE_COLUMNS))
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await
+    }
+
+
+    async fn set_profile(&self, id: Uuid, fields: &ProfileFields) -> Result<Option<Profile>, sqlx::Error> {
+        sqlx::query_as::<_, Profile>(&format!(
+            r#"
+            UPDATE users SET
+                display_name = $2,
+                bio = $3,
+                pronouns = $4,
+                status_text = $5,
+                status_emoji = $6,
+                status_expires_at = $7,
+                timezone = $8
+            WHERE id = $1
+            RETURNING {}
+            "#,
+            PROFILE_COLUMNS
+        ))
+        .bind(id)
+        .bind(&fields.display_name)
+        .bind(&fields.bio)
+        .bind(&fields.pronouns)
+        .bind(&fields.status_text)
+        .bind(&fields.status_emoji)
+        .bind(fields.status_expires_at)
+        .bind(&fields.timezone)
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+
+    async fn rename(&self, id: Uuid, username: &str) -> Result<Option<User>, sqlx::Error> {
+        let query = sqlx::query_as::<_, User>(
+            r#"
+            UPDATE users SET username = $2, username_canonical = $3, username_skeleton = $4, username_needs_rename = FALSE
+            WHERE id = $1
+            RETURNING *
+            "#
+        )
+        .bind(id)
+        .bind(username)
+        .bind(username::canonical(username))
+        .bind(username::skeleton(username))
+        .fetch_optional(&self.pool);
+
+        match query.await {
+            Err(sqlx::Error::Database(e)) if e.is_unique_violation() => Ok(None),
+            result => result,
+        }
+    }
+
+
+    async fn set_avatar(&self, id: Uuid, avatar_url: &str) -> Result<Option<User>, sqlx::Error> {
+        sqlx::query_as::<_, User>(
+            "UPDATE users SET avatar_url = $1 WHERE id = $2 RETURNING *"
+        )
+        .bind(avatar_url)
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+
+    async fn set_password(&self, username: &str, password_hash: &str) -> Result<Option<User>, sqlx::Error> {
+        sqlx::query_as::<_, User>(&format!(
+            "UPDATE users SET password_hash = $3, token_version = token_version + 1 WHERE id = ({}) RETURNING *",
+            USER_ID_BY_NAME
+        ))
+        .bind(username)
+        .bind(username::canonical(username))
+        .bind(password_hash)
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+
+    async fn set_role(&self, username: &str, role: &str) -> Result<Option<User>, sqlx::Error> {
+        sqlx::query_as::<_, User>(&format!(
+            "UPDATE users SET role = $3 WHERE id = ({}) RETURNING *",
+            USER_ID_BY_NAME
+        ))
+        .bind(username)
+        .bind(username::canonical(username))
+        .bind(role)
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+
+    async fn set_disabled(&self, username: &str, disabled: bool) -> Result<Option<User>, sqlx::Error> {
+        sqlx::query_as::<_, User>(&format!(
+            r#"
+            UPDATE users
+            SET disabled_at = CASE WHEN $3 THEN COALESCE(disabled_at, NOW()) ELSE NULL END
+            WHERE id = ({})
+            RETURNING *
+            "#,
+            USER_ID_BY_NAME
+        ))
+        .bind(username)
+        .bind(username::canonical(username))
+        .bind(disabled)
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+
+    async fn set_storage_quota(&self, username: &str, quota_bytes: Option<i64>) -> Result<Option<User>, sqlx::Error> {
+        sqlx::query_as::<_, User>(&format!(
+            "UPDATE users SET storage_quota_bytes = $3 WHERE id = ({}) RETURNING *",
+            USER_ID_BY_NAME
+        ))
+        .bind(username)
+        .bind(username::canonical(username))
+        .bind(quota_bytes)
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+
+    async fn storage_usage(&self, id: Uuid) -> Result<StorageUsage, sqlx::Error> {
+        sqlx::query_as::<_, StorageUsage>(
+            "SELECT storage_used_bytes AS used_bytes, storage_quota_bytes AS quota_bytes FROM users WHERE id = $1"
+        )
+        .bind(id)
+        .fetch_one(&self.pool)
+        .await
+    }
+
+
+    async fn avatar_urls(&self) -> Result<Vec<String>, sqlx::Error> {
+        sqlx::query_scalar::<_, String>(
+            "SELECT avatar_url FROM users WHERE avatar_url IS NOT NULL"
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+
+
+    async fn record_lockout(
+        &self,
+        username: &str,
+        ip_address: Option<&str>,
+        failures: i32,
+        locked_until: DateTime<Utc>
+    ) -> Result<LoginLockout, sqlx::Error> {
+        sqlx::query_as::<_, LoginLockout>(
+            r#"
+            INSERT INTO login_lockouts (id, username, ip_address, failures, locked_until)
+            VALUES ($1, $2, $3, $4, $5)
+            RETURNING *
+            "#
+        )
+        .bind(Uuid::new_v4())
+        .bind(username)
+        .bind(ip_address)
+        .bind(failures)
+        .bind(locked_until)
+        .fetch_one(&self.pool)
+        .await
+    }
+
+
+    async fn recent_lockouts(&self, limit: i64) -> Result<Vec<LoginLockout>, sqlx::Error> {
+        sqlx::query_as::<_, LoginLockout>(
+            "SELECT * FROM login_lockouts ORDER BY created_at DESC LIMIT $1"
+        )
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+
+    async fn without_canonical_username(&self) -> Result<Vec<(Uuid, String)>, sqlx::Error> {
+        sqlx::query_as::<_, (Uuid, String)>(
+            r#"
+            SELECT users.id, users.username
+            FROM users
+            LEFT JOIN (
+                SELECT sender_id, MIN(timestamp) AS first_message FROM messages GROUP BY sender_id
+            ) AS activity ON activity.sender_id = users.id
+            WHERE users.username_canonical IS NULL
+            ORDER BY activity.first_message IS NULL, activity.first_message, users.username
+            "#
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+
+
+    async fn set_canonical_username(&self, id: Uuid, canonical: &str, skeleton: &str) -> Result<bool, sqlx::Error> {
+        let needs_rename = sqlx::query_scalar::<_, bool>(
+            r#"
+            UPDATE users SET username_canonical = $2, username_skeleton = $3, username_needs_rename = EXISTS (
+                SELECT 1 FROM users AS other
+                WHERE other.id <> $1 AND other.username_canonical = $2 AND NOT other.username_needs_rename
+            )
+            WHERE id = $1
+            RETURNING username_needs_rename
+            "#
+        )
+        .bind(id)
+        .bind(canonical)
+        .bind(skeleton)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(needs_rename == Some(false))
+    }
+
+
+    async fn needing_rename(&self) -> Result<Vec<String>, sqlx::Error> {
+        sqlx::query_scalar::<_, String>(
+            "SELECT username FROM users WHERE username_needs_rename ORDER BY username"
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+
+
+    async fn create_password_reset(
+        &self,
+        user_id: Uuid,
+        token_hash: &str,
+        expires_at: DateTime<Utc>
+    ) -> Result<(), sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+
+        sqlx::query("DELETE FROM password_reset_tokens WHERE user_id = $1 AND used_at IS NULL")
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+
+        sqlx::query(
+            "INSERT INTO password_reset_tokens (id, user_id, token_hash, expires_at) VALUES ($1, $2, $3, $4)"
+        )
+        .bind(Uuid::new_v4())
+        .bind(user_id)
+        .bind(token_hash)
+        .bind(expires_at)
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await
+    }
+
+
+    async fn use_password_reset(&self, token_hash: &str) -> Result<Option<Uuid>, sqlx::Error> {
+        sqlx::query_scalar::<_, Uuid>(
+            r#"
+            UPDATE password_reset_tokens SET used_at = NOW()
+            WHERE token_hash = $1 AND used_at IS NULL AND expires_at > NOW()
+            RETURNING user_id
+            "#
+        )
+        .bind(token_hash)
+        .fetch_optional(&self.pool)
+        .await
+    }
+}
+
+
+#[async_trait]
+impl MessageRepository for PgRepository {
+    async fn save(
+        &self,
+        sender_id: Uuid,
+        message_type: &str,
+        message: &str,
+        timestamp: &DateTime<Utc>,
+        target_user_id: Option<Uuid>,
+        upload_url: Option<String>
+    ) -> Result<(), sqlx::Error> {
+        let id = Uuid::new_v4();
+        let upload_sha256 = upload_url.as_deref().and_then(upload_url_sha256).map(str::to_string);
+
+        sqlx::query!(
+            r#"
+            INSERT INTO messages (id, sender_id, target_user_id, message_type, message, upload_url, upload_sha256, timestamp)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+            "#,
+            id,
+            sender_id,
+            target_user_id,
+            message_type,
+            message,
+            upload_url,
+            upload_sha256,
+            timestamp
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+
+    async fn public(&self, limit: i64) -> Result<Vec<MessageModel>, sqlx::Error> {
+        sqlx::query_as::<_, MessageModel>(
+            r#"
+                SELECT
+                    messages.id,
+                    messages.sender_id,
+                    sender.username AS sender,
+                    messages.message,
+                    messages.target_user_id,
+                    target.username AS target_username,
+                    messages.message_type,
+                    messages.timestamp,
+                    messages.upload_url,
+                    sender.avatar_url,
+                    sender.is_bot AS sender_is_bot
+                FROM messages
+                JOIN users AS sender ON sender.id = messages.sender_id
+                LEFT JOIN users AS target ON target.id = messages.target_user_id
+                WHERE messages.message_type = 'chat'
+                ORDER BY messages.timestamp ASC
+                LIMIT $1;
+            "#
+        )
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+
+    async fn direct(&self, current_user: Uuid, target_user: Uuid) -> Result<Vec<MessageModel>, sqlx::Error> {
+        sqlx::query_as::<_, MessageModel>(
+            r#"
+            SELECT
+                messages.id,
+                messages.sender_id,
+                sender.username AS sender,
+                messages.message,
+                messages.target_user_id,
+                target.username AS target_username,
+                messages.message_type,
+                messages.timestamp,
+                messages.upload_url,
+                sender.avatar_url,
+                sender.is_bot AS sender_is_bot
+            FROM messages
+            JOIN users AS sender ON sender.id = messages.sender_id
+            LEFT JOIN users AS target ON target.id = messages.target_user_id
+            WHERE message_type = 'dm'
+            AND (
+                (sender_id = $1 AND target_user_id = $2)
+                OR
+                (sender_id = $2 AND target_user_id = $1)
+            )
+            ORDER BY timestamp ASC
+            "#
+        )
+        .bind(current_user)
+        .bind(target_user)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+
+    async fn upload_urls(&self) -> Result<Vec<String>, sqlx::Error> {
+        sqlx::query_scalar::<_, String>(
+            "SELECT DISTINCT upload_url FROM messages WHERE upload_url IS NOT NULL AND upload_url <> ''"
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+
+
+    async fn delete_by_sender(&self, sender_id: Uuid) -> Result<u64, sqlx::Error> {
+        let result = sqlx::query("DELETE FROM messages WHERE sender_id = $1")
+            .bind(sender_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected())
+    }
+}
+
+
+/// Body of [`UploadRepository::create_attachment`], shared with
+/// [`SessionLock::finish`]. The caller commits only when the attachment was created.
+async fn insert_attachment(
+    conn: &mut PgConnection,
+    blob: &NewBlob,
+    uploader_id: Option<Uuid>,
+    original_name: &str,
+    default_quota: Option<i64>,
+    global_quota: Option<i64>,
+) -> Result<NewAttachment, sqlx::Error> {
+
+    if let Some(uploader_id) = uploader_id {
+        let charged = sqlx::query(
+            r#"
+            UPDATE users SET storage_used_bytes = storage_used_bytes + $1
+            WHERE id = $2
+            AND ($3::BIGINT IS NULL AND storage_quota_bytes IS NULL
+                OR storage_used_bytes + $1 <= COALESCE(storage_quota_bytes, $3))
+            "#
+        )
+        .bind(blob.size_bytes)
+        .bind(uploader_id)
+        .bind(default_quota)
+        .execute(&mut *conn)
+        .await?;
+
+        if charged.rows_affected() != 1 {
+            return Ok(NewAttachment::QuotaExceeded);
+        }
+    }
+
+    // The janitor can delete an unreferenced blob between the insert finding it and
+    // the update, then it is inserted again
+    let stored = loop {
+        let inserted = sqlx::query_as::<_, Blob>(
+            r#"
+            INSERT INTO blobs (sha256, size_bytes, extension, ref_count, scan_status)
+            VALUES ($1, $2, $3, 1, $4)
+            ON CONFLICT (sha256) DO NOTHING
+            RETURNING *
+            "#
+        )
+        .bind(&blob.sha256)
+        .bind(blob.size_bytes)
+        .bind(&blob.extension)
+        .bind(blob.scan_status)
+        .fetch_optional(&mut *conn)
+        .await?;
+
+        if let Some(stored) = inserted {
+            // Holding the row lock until commit keeps concurrent uploads of new
+            // content from all fitting into the same free space
+            let charged = sqlx::query(
+                "UPDATE storage_usage SET blob_bytes = blob_bytes + $1 WHERE $2::BIGINT IS NULL OR blob_bytes + $1 <= $2"
+            )
+            .bind(blob.size_bytes)
+            .bind(global_quota)
+            .execute(&mut *conn)
+            .await?;
+
+            if charged.rows_affected() != 1 {
+                return Ok(NewAttachment::GlobalQuotaExceeded);
+            }
+            break stored;
+        }
+
+        let referenced = sqlx::query_as::<_, Blob>(
+            "UPDATE blobs SET ref_count = ref_count + 1, updated_at = NOW() WHERE sha256 = $1 RETURNING *"
+        )
+        .bind(&blob.sha256)
+        .fetch_optional(&mut *conn)
+        .await?;
+
+        if let Some(stored) = referenced {
+            break stored;
+        }
+    };
+
+    let attachment = sqlx::query_as::<_, Attachment>(
+        r#"
+        INSERT INTO attachments (id, blob_sha256, uploader_id, original_name)
+        VALUES ($1, $2, $3, $4)
+        RETURNING *
+        "#
+    )
+    .bind(Uuid::new_v4())
+    .bind(&blob.sha256)
+    .bind(uploader_id)
+    .bind(original_name)
+    .fetch_one(&mut *conn)
+    .await?;
+
+    Ok(NewAttachment::Created(Box::new((attachment, stored))))
+}
+
+
+/// Keeps the session row locked with `FOR UPDATE` until the transaction ends.
+struct PgSessionLock {
+    tx: Transaction<'static, Postgres>,
+    session: UploadSession,
+}
+
+#[async_trait]
+impl SessionLock for PgSessionLock {
+    fn session(&self) -> &UploadSession {
+        &self.session
+    }
+
+    async fn set_received(
+        mut self: Box<Self>,
+        received_bytes: i64,
+        expires_at: DateTime<Utc>
+    ) -> Result<UploadSession, sqlx::Error> {
+        let session = sqlx::query_as::<_, UploadSession>(
+            "UPDATE upload_sessions SET received_bytes = $1, expires_at = $2 WHERE id = $3 RETURNING *"
+        )
+        .bind(received_bytes)
+        .bind(expires_at)
+        .bind(self.session.id)
+        .fetch_one(&mut *self.tx)
+        .await?;
+
+        self.tx.commit().await?;
+        Ok(session)
+    }
+
+    async fn finish(
+        mut self: Box<Self>,
+        blob: &NewBlob,
+        default_quota: Option<i64>,
+        global_quota: Option<i64>,
+    ) -> Result<NewAttachment, sqlx::Error> {
+        let created = insert_attachment(
+            &mut self.tx,
+            blob,
+            Some(self.session.user_id),
+            &self.session.file_name,
+            default_quota,
+            global_quota,
+        ).await?;
+
+        if matches!(created, NewAttachment::Created(_)) {
+            sqlx::query("DELETE FROM upload_sessions WHERE id = $1")
+                .bind(self.session.id)
+                .execute(&mut *self.tx)
+                .await?;
+            self.tx.commit().await?;
+        }
+
+        Ok(created)
+    }
+
+    async fn delete(mut self: Box<Self>) -> Result<(), sqlx::Error> {
+        sqlx::query("DELETE FROM upload_sessions WHERE id = $1")
+            .bind(self.session.id)
+            .execute(&mut *self.tx)
+            .await?;
+        self.tx.commit().await
+    }
+}
+
+
+#[async_trait]
+impl UploadRepository for PgRepository {
+    async fn find_blob(&self, sha256: &str) -> Result<Option<Blob>, sqlx::Error> {
+        sqlx::query_as::<_, Blob>(
+            "SELECT * FROM blobs WHERE sha256 = $1"
+        )
+        .bind(sha256)
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+
+    async fn find_blob_by_file_name(&self, file_name: &str) -> Result<Option<Blob>, sqlx::Error> {
+        let (sha256, extension) = file_name.split_once('.').unwrap_or((file_name, ""));
+        sqlx::query_as::<_, Blob>(
+            "SELECT * FROM blobs WHERE sha256 = $1 AND extension = $2"
+        )
+        .bind(sha256)
+        .bind(extension)
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+
+    async fn find_pending_scan(&self, limit: i64) -> Result<Vec<Blob>, sqlx::Error> {
+        sqlx::query_as::<_, Blob>(
+            "SELECT * FROM blobs WHERE scan_status = 'pending' ORDER BY created_at ASC LIMIT $1"
+        )
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+
+    async fn set_scan_result(
+        &self,
+        sha256: &str,
+        status: ScanStatus,
+        signature: Option<&str>
+    ) -> Result<Blob, sqlx::Error> {
+        sqlx::query_as::<_, Blob>(
+            r#"
+            UPDATE blobs SET scan_status = $1, scan_signature = $2, scanned_at = NOW()
+            WHERE sha256 = $3
+            RETURNING *
+            "#
+        )
+        .bind(status)
+        .bind(signature)
+        .bind(sha256)
+        .fetch_one(&self.pool)
+        .await
+    }
+
+
+    async fn total_blob_size(&self) -> Result<i64, sqlx::Error> {
+        sqlx::query_scalar::<_, i64>(
+            "SELECT blob_bytes FROM storage_usage"
+        )
+        .fetch_one(&self.pool)
+        .await
+    }
+
+
+    async fn find_all_blobs(&self) -> Result<Vec<Blob>, sqlx::Error> {
+        sqlx::query_as::<_, Blob>("SELECT * FROM blobs")
+            .fetch_all(&self.pool)
+            .await
+    }
+
+
+    async fn find_unreferenced_blobs(&self, cutoff: DateTime<Utc>) -> Result<Vec<Blob>, sqlx::Error> {
+        sqlx::query_as::<_, Blob>(
+            "SELECT * FROM blobs WHERE ref_count = 0 AND updated_at < $1"
+        )
+        .bind(cutoff)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+
+    async fn delete_unreferenced_blobs(&self, cutoff: DateTime<Utc>) -> Result<Vec<Blob>, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+
+        let blobs = sqlx::query_as::<_, Blob>(
+            "DELETE FROM blobs WHERE ref_count = 0 AND updated_at < $1 RETURNING *"
+        )
+        .bind(cutoff)
+        .fetch_all(&mut *tx)
+        .await?;
+
+        sqlx::query("UPDATE storage_usage SET blob_bytes = GREATEST(blob_bytes - $1, 0)")
+            .bind(blobs.iter().map(|b| b.size_bytes).sum::<i64>())
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await?;
+        Ok(blobs)
+    }
+
+
+    async fn create_attachment(
+        &self,
+        blob: &NewBlob,
+        uploader_id: Option<Uuid>,
+        original_name: &str,
+        default_quota: Option<i64>,
+        global_quota: Option<i64>,
+    ) -> Result<NewAttachment, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+
+        let created = insert_attachment(&mut tx, blob, uploader_id, original_name, default_quota, global_quota).await?;
+        if matches!(created, NewAttachment::Created(_)) {
+            tx.commit().await?;
+        }
+
+        Ok(created)
+    }
+
+
+    async fn find_orphaned_attachments(&self, cutoff: DateTime<Utc>) -> Result<Vec<OrphanedAttachment>, sqlx::Error> {
+        sqlx::query_as::<_, OrphanedAttachment>(
+            r#"
+            SELECT
+                attachments.id,
+                attachments.original_name,
+                blobs.sha256,
+                blobs.extension,
+                blobs.size_bytes,
+                attachments.created_at
+            FROM attachments
+            JOIN blobs ON blobs.sha256 = attachments.blob_sha256
+            WHERE attachments.created_at < $1
+            AND NOT EXISTS (
+                SELECT 1 FROM messages
+                WHERE messages.upload_sha256 = attachments.blob_sha256
+                AND (attachments.uploader_id IS NULL OR messages.sender_id = attachments.uploader_id)
+            )
+            AND NOT EXISTS (
+                SELECT 1 FROM users
+                WHERE users.id = attachments.uploader_id
+                AND users.avatar_url LIKE '%/uploads/' || CASE
+                    WHEN blobs.extension = '' THEN blobs.sha256
+                    ELSE blobs.sha256 || '.' || blobs.extension
+                END
+            )
+            ORDER BY attachments.created_at ASC
+            "#
+        )
+        .bind(cutoff)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+
+    async fn delete_attachment(&self, id: Uuid) -> Result<(), sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+
+        let deleted = sqlx::query_as::<_, (String, Option<Uuid>)>(
+            "DELETE FROM attachments WHERE id = $1 RETURNING blob_sha256, uploader_id"
+        )
+        .bind(id)
+        .fetch_optional(&mut *tx)
+        .await?;
+
+        if let Some((sha256, uploader_id)) = deleted {
+            let size_bytes = sqlx::query_scalar::<_, i64>(
+                r#"
+                UPDATE blobs SET ref_count = ref_count - 1, updated_at = NOW()
+                WHERE sha256 = $1
+                RETURNING size_bytes
+                "#
+            )
+            .bind(&sha256)
+            .fetch_one(&mut *tx)
+            .await?;
+
+            if let Some(uploader_id) = uploader_id {
+                sqlx::query(
+                    "UPDATE users SET storage_used_bytes = GREATEST(storage_used_bytes - $1, 0) WHERE id = $2"
+                )
+                .bind(size_bytes)
+                .bind(uploader_id)
+                .execute(&mut *tx)
+                .await?;
+            }
+        }
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+
+    async fn largest_attachments(&self, user_id: Uuid, limit: i64) -> Result<Vec<UserFile>, sqlx::Error> {
+        sqlx::query_as::<_, UserFile>(
+            r#"
+            SELECT
+                attachments.id,
+                attachments.original_name,
+                blobs.sha256,
+                blobs.extension,
+                blobs.size_bytes,
+                attachments.created_at
+            FROM attachments
+            JOIN blobs ON blobs.sha256 = attachments.blob_sha256
+            WHERE attachments.uploader_id = $1
+            ORDER BY blobs.size_bytes DESC, attachments.created_at DESC
+            LIMIT $2
+            "#
+        )
+        .bind(user_id)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+
+    async fn create_session(
+        &self,
+        user_id: Uuid,
+        file_name: &str,
+        total_size: i64,
+        expected_sha256: Option<&str>,
+        expires_at: DateTime<Utc>
+    ) -> Result<UploadSession, sqlx::Error> {
+        sqlx::query_as::<_, UploadSession>(
+            r#"
+            INSERT INTO upload_sessions (id, user_id, file_name, total_size, expected_sha256, expires_at)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            RETURNING *
+            "#
+        )
+        .bind(Uuid::new_v4())
+        .bind(user_id)
+        .bind(file_name)
+        .bind(total_size)
+        .bind(expected_sha256)
+        .bind(expires_at)
+        .fetch_one(&self.pool)
+        .await
+    }
+
+
+    async fn find_session(&self, id: Uuid, user_id: Uuid) -> Result<Option<UploadSession>, sqlx::Error> {
+        sqlx::query_as::<_, UploadSession>(
+            "SELECT * FROM upload_sessions WHERE id = $1 AND user_id = $2 AND expires_at > NOW()"
+        )
+        .bind(id)
+        .bind(user_id)
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+
+    async fn lock_session(&self, id: Uuid, user_id: Uuid) -> Result<Option<Box<dyn SessionLock>>, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+
+        let session = sqlx::query_as::<_, UploadSession>(
+            "SELECT * FROM upload_sessions WHERE id = $1 AND user_id = $2 AND expires_at > NOW() FOR UPDATE"
+        )
+        .bind(id)
+        .bind(user_id)
+        .fetch_optional(&mut *tx)
+        .await?;
+
+        Ok(session.map(|session| Box::new(PgSessionLock { tx, session }) as Box<dyn SessionLock>))
+    }
+
+
+    async fn delete_session(&self, id: Uuid) -> Result<(), sqlx::Error> {
+        sqlx::query("DELETE FROM upload_sessions WHERE id = $1")
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+
+    async fn find_expired_sessions(&self) -> Result<Vec<UploadSession>, sqlx::Error> {
+        sqlx::query_as::<_, UploadSession>(
+            "SELECT * FROM upload_sessions WHERE expires_at <= NOW()"
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+
+
+    async fn delete_expired_sessions(&self) -> Result<Vec<UploadSession>, sqlx::Error> {
+        sqlx::query_as::<_, UploadSession>(
+            "DELETE FROM upload_sessions WHERE expires_at <= NOW() RETURNING *"
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+}
+
+
+#[async_trait]
+impl TwoFactorRepository for PgRepository {
+    async fn two_factor(&self, user_id: Uuid) -> Result<Option<TwoFactor>, sqlx::Error> {
+        sqlx::query_as::<_, TwoFactor>(
+            "SELECT totp_secret, totp_enabled_at, totp_last_step FROM users WHERE id = $1"
+        )
+        .bind(user_id)
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+
+    async fn set_totp_secret(&self, user_id: Uuid, secret: &str) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "UPDATE users SET totp_secret = $2, totp_enabled_at = NULL, totp_last_step = NULL WHERE id = $1"
+        )
+        .bind(user_id)
+        .bind(secret)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+
+    async fn enable_totp(&self, user_id: Uuid, step: i64, recovery_code_hashes: &[String]) -> Result<bool, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+
+        let enabled = sqlx::query(
+            r#"
+            UPDATE users SET totp_enabled_at = NOW(), totp_last_step = $2
+            WHERE id = $1 AND totp_secret IS NOT NULL AND totp_enabled_at IS NULL
+            "#
+        )
+        .bind(user_id)
+        .bind(step)
+        .execute(&mut *tx)
+        .await?;
+
+        if enabled.rows_affected() != 1 {
+            return Ok(false);
+        }
+
+        insert_recovery_codes(&mut tx, user_id, recovery_code_hashes).await?;
+        tx.commit().await?;
+        Ok(true)
+    }
+
+
+    async fn disable_totp(&self, user_id: Uuid) -> Result<bool, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+
+        let disabled = sqlx::query(
+            r#"
+            UPDATE users SET totp_secret = NULL, totp_enabled_at = NULL, totp_last_step = NULL
+            WHERE id = $1 AND totp_secret IS NOT NULL
+            "#
+        )
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query("DELETE FROM recovery_codes WHERE user_id = $1")
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await?;
+        Ok(disabled.rows_affected() == 1)
+    }
+
+
+    async fn use_totp_step(&self, user_id: Uuid, step: i64) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query(
+            r#"
+            UPDATE users SET totp_last_step = $2
+            WHERE id = $1 AND (totp_last_step IS NULL OR totp_last_step < $2)
+            "#
+        )
+        .bind(user_id)
+        .bind(step)
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected() == 1)
+    }
+
+
+    async fn unused_recovery_codes(&self, user_id: Uuid) -> Result<Vec<RecoveryCode>, sqlx::Error> {
+        sqlx::query_as::<_, RecoveryCode>(
+            "SELECT id, code_hash FROM recovery_codes WHERE user_id = $1 AND used_at IS NULL"
+        )
+        .bind(user_id)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+
+    async fn use_recovery_code(&self, id: Uuid) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query("UPDATE recovery_codes SET used_at = NOW() WHERE id = $1 AND used_at IS NULL")
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() == 1)
+    }
+
+
+    async fn replace_recovery_codes(&self, user_id: Uuid, code_hashes: &[String]) -> Result<(), sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        insert_recovery_codes(&mut tx, user_id, code_hashes).await?;
+        tx.commit().await
+    }
+}
+
+#[async_trait]
+impl InviteRepository for PgRepository {
+    async fn create_invite(
+        &self,
+        created_by: Uuid,
+        code_hash: &str,
+        max_uses: i32,
+        expires_at: Option<DateTime<Utc>>,
+    ) -> Result<Invite, sqlx::Error> {
+        let id = Uuid::new_v4();
+        sqlx::query(
+            "INSERT INTO invites (id, code_hash, created_by, max_uses, expires_at) VALUES ($1, $2, $3, $4, $5)"
+        )
+        .bind(id)
+        .bind(code_hash)
+        .bind(created_by)
+        .bind(max_uses)
+        .bind(expires_at)
+        .execute(&self.pool)
+        .await?;
+
+        sqlx::query_as::<_, Invite>(&format!("{} WHERE invites.id = $1", INVITE_SELECT))
+            .bind(id)
+            .fetch_one(&self.pool)
+            .await
+    }
+
+
+    async fn invites(&self) -> Result<Vec<Invite>, sqlx::Error> {
+        let mut invites = sqlx::query_as::<_, Invite>(&format!("{} ORDER BY invites.created_at DESC", INVITE_SELECT))
+            .fetch_all(&self.pool)
+            .await?;
+
+        let invitees = sqlx::query_as::<_, (Uuid, String)>(INVITEES)
+            .fetch_all(&self.pool)
+            .await?;
+        attach_invitees(&mut invites, invitees);
+        Ok(invites)
+    }
+
+
+    async fn revoke_invite(&self, id: Uuid) -> Result<bool, sqlx::Error> {
+        let revoked = sqlx::query("UPDATE invites SET revoked_at = NOW() WHERE id = $1 AND revoked_at IS NULL")
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(revoked.rows_affected() == 1)
+    }
+
+
+    async fn register_with_invite(
+        &self,
+        username: &str,
+        password_hash: &str,
+        code_hash: &str,
+    ) -> Result<InvitedRegistration, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+
+        // Row locked until commit, so concurrent registrations can't overuse it
+        let invite = sqlx::query_as::<_, (Uuid, Option<Uuid>)>(
+            r#"
+            UPDATE invites SET uses = uses + 1
+            WHERE code_hash = $1 AND revoked_at IS NULL AND uses < max_uses
+                AND (expires_at IS NULL OR expires_at > NOW())
+            RETURNING id, created_by
+            "#
+        )
+        .bind(code_hash)
+        .fetch_optional(&mut *tx)
+        .await?;
+
+        let Some((invite_id, invited_by)) = invite else {
+            return Ok(InvitedRegistration::InvalidInvite);
+        };
+
+        let user = sqlx::query_as::<_, User>(
+            r#"
+            INSERT INTO users (id, username, password_hash, username_canonical, username_skeleton, invited_by, invite_id)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            RETURNING *
+            "#
+        )
+        .bind(Uuid::new_v4())
+        .bind(username)
+        .bind(password_hash)
+        .bind(username::canonical(username))
+        .bind(username::skeleton(username))
+        .bind(invited_by)
+        .bind(invite_id)
+        .fetch_one(&mut *tx)
+        .await;
+
+        // Dropping the transaction gives the use back
+        match user {
+            Ok(user) => {
+                tx.commit().await?;
+                Ok(InvitedRegistration::Created(user))
+            }
+            Err(sqlx::Error::Database(e)) if e.is_unique_violation() => Ok(InvitedRegistration::UsernameTaken),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[async_trait]
+impl ApiTokenRepository for PgRepository {
+    async fn create_api_token(
+        &self,
+        user_id: Uuid,
+        name: &str,
+        token_hash: &str,
+        scopes: &ApiScopes,
+        expires_at: Option<DateTime<Utc>>,
+    ) -> Result<ApiToken, sqlx::Error> {
+        sqlx::query_as::<_, ApiToken>(
+            r#"
+            INSERT INTO api_tokens (id, user_id, name, token_hash, scopes, expires_at)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            RETURNING id, user_id, name, scopes, expires_at, last_used_at, revoked_at, created_at
+            "#
+        )
+        .bind(Uuid::new_v4())
+        .bind(user_id)
+        .bind(name)
+        .bind(token_hash)
+        .bind(scopes.to_db())
+        .bind(expires_at)
+        .fetch_one(&self.pool)
+        .await
+    }
+
+
+    async fn api_tokens(&self, user_id: Uuid) -> Result<Vec<ApiToken>, sqlx::Error> {
+        sqlx::query_as::<_, ApiToken>(&format!("{} WHERE user_id = $1 ORDER BY created_at DESC", API_TOKEN_SELECT))
+            .bind(user_id)
+            .fetch_all(&self.pool)
+            .await
+    }
+
+
+    async fn find_api_token(&self, token_hash: &str) -> Result<Option<ApiToken>, sqlx::Error> {
+        let query = format!(
+            "{} WHERE token_hash = $1 AND revoked_at IS NULL AND (expires_at IS NULL OR expires_at > NOW())",
+            API_TOKEN_SELECT
+        );
+        sqlx::query_as::<_, ApiToken>(&query)
+            .bind(token_hash)
+            .fetch_optional(&self.pool)
+            .await
+    }
+
+
+    async fn touch_api_token(&self, id: Uuid, used_at: DateTime<Utc>) -> Result<(), sqlx::Error> {
+        sqlx::query("UPDATE api_tokens SET last_used_at = $2 WHERE id = $1")
+            .bind(id)
+            .bind(used_at)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+
+    async fn revoke_api_token(&self, id: Uuid, user_id: Option<Uuid>) -> Result<bool, sqlx::Error> {
+        let revoked = sqlx::query(
+            "UPDATE api_tokens SET revoked_at = NOW() WHERE id = $1 AND ($2 IS NULL OR user_id = $2) AND revoked_at IS NULL"
+        )
+        .bind(id)
+        .bind(user_id)
+        .execute(&self.pool)
+        .await?;
+        Ok(revoked.rows_affected() == 1)
+    }
+}
+
+/// Replaces the user's recovery codes inside `tx`.
+async fn insert_recovery_codes(
+    tx: &mut Transaction<'_, Postgres>,
+    user_id: Uuid,
+    code_hashes: &[String]
+) -> Result<(), sqlx::Error> {
+    sqlx::query("DELETE FROM recovery_codes WHERE user_id = $1")
+        .bind(user_id)
+        .execute(&mut **tx)
+        .await?;
+
+    for code_hash in code_hashes {
+        sqlx::query("INSERT INTO recovery_codes (id, user_id, code_hash) VALUES ($1, $2, $3)")
+            .bind(Uuid::new_v4())
+            .bind(user_id)
+            .bind(code_hash)
+            .execute(&mut **tx)
+            .await?;
+    }
+
+    Ok(())
+}
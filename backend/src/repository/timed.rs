@@ -0,0 +1,393 @@
+//! Wraps a store so every call shows up in the `brochat_db_query_duration_seconds`
+//! histogram, labelled `<repository>.<method>`, whichever store is behind it.
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+
+use super::{
+    ApiTokenRepository, Backend, InviteRepository, InvitedRegistration, MessageRepository, NewAttachment, PoolStats,
+    SessionLock, TwoFactorRepository, UploadRepository, UserRepository,
+};
+use crate::metrics::timed;
+use crate::models::{
+    ApiScopes, ApiToken, Blob, Invite, LoginLockout, MessageModel, NewBlob, OrphanedAttachment, Profile, ProfileFields,
+    RecoveryCode, ScanStatus, StorageUsage, TwoFactor, UploadSession, User, UserFile, UserList,
+};
+
+/// A store whose calls are timed, see [`timed`].
+pub struct Timed<T>(pub T);
+
+/// A [`SessionLock`] from a [`Timed`] store, timed as well.
+struct TimedLock(Box<dyn SessionLock>);
+
+#[async_trait]
+impl<T: Backend> Backend for Timed<T> {
+    async fn ping(&self) -> Result<(), sqlx::Error> {
+        timed("backend.ping", self.0.ping()).await
+    }
+
+    fn pool_stats(&self) -> Option<PoolStats> {
+        self.0.pool_stats()
+    }
+}
+
+#[async_trait]
+impl<T: UserRepository> UserRepository for Timed<T> {
+    async fn create(&self, username: &str, password_hash: &str) -> Result<Option<User>, sqlx::Error> {
+        timed("user.create", self.0.create(username, password_hash)).await
+    }
+
+    async fn create_bot(&self, username: &str, password_hash: &str) -> Result<Option<User>, sqlx::Error> {
+        timed("user.create_bot", self.0.create_bot(username, password_hash)).await
+    }
+
+    async fn find_by_username(&self, username: &str) -> Result<Option<User>, sqlx::Error> {
+        timed("user.find_by_username", self.0.find_by_username(username)).await
+    }
+
+    async fn find_by_skeleton(&self, skeleton: &str) -> Result<Option<User>, sqlx::Error> {
+        timed("user.find_by_skeleton", self.0.find_by_skeleton(skeleton)).await
+    }
+
+    async fn find_by_id(&self, id: Uuid) -> Result<Option<User>, sqlx::Error> {
+        timed("user.find_by_id", self.0.find_by_id(id)).await
+    }
+
+    async fn list(&self) -> Result<Vec<UserList>, sqlx::Error> {
+        timed("user.list", self.0.list()).await
+    }
+
+    async fn profile(&self, id: Uuid) -> Result<Option<Profile>, sqlx::Error> {
+        timed("user.profile", self.0.profile(id)).await
+    }
+
+    async fn set_profile(&self, id: Uuid, fields: &ProfileFields) -> Result<Option<Profile>, sqlx::Error> {
+        timed("user.set_profile", self.0.set_profile(id, fields)).await
+    }
+
+    async fn rename(&self, id: Uuid, username: &str) -> Result<Option<User>, sqlx::Error> {
+        timed("user.rename", self.0.rename(id, username)).await
+    }
+
+    async fn set_avatar(&self, id: Uuid, avatar_url: &str) -> Result<Option<User>, sqlx::Error> {
+        timed("user.set_avatar", self.0.set_avatar(id, avatar_url)).await
+    }
+
+    async fn set_password(&self, username: &str, password_hash: &str) -> Result<Option<User>, sqlx::Error> {
+        timed("user.set_password", self.0.set_password(username, password_hash)).await
+    }
+
+    async fn set_role(&self, username: &str, role: &str) -> Result<Option<User>, sqlx::Error> {
+        timed("user.set_role", self.0.set_role(username, role)).await
+    }
+
+    async fn set_disabled(&self, username: &str, disabled: bool) -> Result<Option<User>, sqlx::Error> {
+        timed("user.set_disabled", self.0.set_disabled(username, disabled)).await
+    }
+
+    async fn set_storage_quota(&self, username: &str, quota_bytes: Option<i64>) -> Result<Option<User>, sqlx::Error> {
+        timed("user.set_storage_quota", self.0.set_storage_quota(username, quota_bytes)).await
+    }
+
+    async fn storage_usage(&self, id: Uuid) -> Result<StorageUsage, sqlx::Error> {
+        timed("user.storage_usage", self.0.storage_usage(id)).await
+    }
+
+    async fn avatar_urls(&self) -> Result<Vec<String>, sqlx::Error> {
+        timed("user.avatar_urls", self.0.avatar_urls()).await
+    }
+
+    async fn record_lockout(
+        &self,
+        username: &str,
+        ip_address: Option<&str>,
+        failures: i32,
+        locked_until: DateTime<Utc>,
+    ) -> Result<LoginLockout, sqlx::Error> {
+        timed("user.record_lockout", self.0.record_lockout(username, ip_address, failures, locked_until)).await
+    }
+
+    async fn without_canonical_username(&self) -> Result<Vec<(Uuid, String)>, sqlx::Error> {
+        timed("user.without_canonical_username", self.0.without_canonical_username()).await
+    }
+
+    async fn set_canonical_username(&self, id: Uuid, canonical: &str, skeleton: &str) -> Result<bool, sqlx::Error> {
+        timed("user.set_canonical_username", self.0.set_canonical_username(id, canonical, skeleton)).await
+    }
+
+    async fn needing_rename(&self) -> Result<Vec<String>, sqlx::Error> {
+        timed("user.needing_rename", self.0.needing_rename()).await
+    }
+
+    async fn recent_lockouts(&self, limit: i64) -> Result<Vec<LoginLockout>, sqlx::Error> {
+        timed("user.recent_lockouts", self.0.recent_lockouts(limit)).await
+    }
+
+    async fn create_password_reset(
+        &self,
+        user_id: Uuid,
+        token_hash: &str,
+        expires_at: DateTime<Utc>,
+    ) -> Result<(), sqlx::Error> {
+        timed("user.create_password_reset", self.0.create_password_reset(user_id, token_hash, expires_at)).await
+    }
+
+    async fn use_password_reset(&self, token_hash: &str) -> Result<Option<Uuid>, sqlx::Error> {
+        timed("user.use_password_reset", self.0.use_password_reset(token_hash)).await
+    }
+}
+
+#[async_trait]
+impl<T: InviteRepository> InviteRepository for Timed<T> {
+    async fn create_invite(
+        &self,
+        created_by: Uuid,
+        code_hash: &str,
+        max_uses: i32,
+        expires_at: Option<DateTime<Utc>>,
+    ) -> Result<Invite, sqlx::Error> {
+        timed("invite.create_invite", self.0.create_invite(created_by, code_hash, max_uses, expires_at)).await
+    }
+
+    async fn invites(&self) -> Result<Vec<Invite>, sqlx::Error> {
+        timed("invite.invites", self.0.invites()).await
+    }
+
+    async fn revoke_invite(&self, id: Uuid) -> Result<bool, sqlx::Error> {
+        timed("invite.revoke_invite", self.0.revoke_invite(id)).await
+    }
+
+    async fn register_with_invite(
+        &self,
+        username: &str,
+        password_hash: &str,
+        code_hash: &str,
+    ) -> Result<InvitedRegistration, sqlx::Error> {
+        timed("invite.register_with_invite", self.0.register_with_invite(username, password_hash, code_hash)).await
+    }
+}
+
+#[async_trait]
+impl<T: ApiTokenRepository> ApiTokenRepository for Timed<T> {
+    async fn create_api_token(
+        &self,
+        user_id: Uuid,
+        name: &str,
+        token_hash: &str,
+        scopes: &ApiScopes,
+        expires_at: Option<DateTime<Utc>>,
+    ) -> Result<ApiToken, sqlx::Error> {
+        timed("api_token.create_api_token", self.0.create_api_token(user_id, name, token_hash, scopes, expires_at)).await
+    }
+
+    async fn api_tokens(&self, user_id: Uuid) -> Result<Vec<ApiToken>, sqlx::Error> {
+        timed("api_token.api_tokens", self.0.api_tokens(user_id)).await
+    }
+
+    async fn find_api_token(&self, token_hash: &str) -> Result<Option<ApiToken>, sqlx::Error> {
+        timed("api_token.find_api_token", self.0.find_api_token(token_hash)).await
+    }
+
+    async fn touch_api_token(&self, id: Uuid, used_at: DateTime<Utc>) -> Result<(), sqlx::Error> {
+        timed("api_token.touch_api_token", self.0.touch_api_token(id, used_at)).await
+    }
+
+    async fn revoke_api_token(&self, id: Uuid, user_id: Option<Uuid>) -> Result<bool, sqlx::Error> {
+        timed("api_token.revoke_api_token", self.0.revoke_api_token(id, user_id)).await
+    }
+}
+
+#[async_trait]
+impl<T: MessageRepository> MessageRepository for Timed<T> {
+    async fn save(
+        &self,
+        sender_id: Uuid,
+        message_type: &str,
+        message: &str,
+        timestamp: &DateTime<Utc>,
+        target_user_id: Option<Uuid>,
+        upload_url: Option<String>,
+    ) -> Result<(), sqlx::Error> {
+        timed("message.save", self.0.save(sender_id, message_type, message, timestamp, target_user_id, upload_url)).await
+    }
+
+    async fn public(&self, limit: i64) -> Result<Vec<MessageModel>, sqlx::Error> {
+        timed("message.public", self.0.public(limit)).await
+    }
+
+    async fn direct(&self, current_user: Uuid, target_user: Uuid) -> Result<Vec<MessageModel>, sqlx::Error> {
+        timed("message.direct", self.0.direct(current_user, target_user)).await
+    }
+
+    async fn upload_urls(&self) -> Result<Vec<String>, sqlx::Error> {
+        timed("message.upload_urls", self.0.upload_urls()).await
+    }
+
+    async fn delete_by_sender(&self, sender_id: Uuid) -> Result<u64, sqlx::Error> {
+        timed("message.delete_by_sender", self.0.delete_by_sender(sender_id)).await
+    }
+}
+
+#[async_trait]
+impl<T: TwoFactorRepository> TwoFactorRepository for Timed<T> {
+    async fn two_factor(&self, user_id: Uuid) -> Result<Option<TwoFactor>, sqlx::Error> {
+        timed("two_factor.two_factor", self.0.two_factor(user_id)).await
+    }
+
+    async fn set_totp_secret(&self, user_id: Uuid, secret: &str) -> Result<(), sqlx::Error> {
+        timed("two_factor.set_totp_secret", self.0.set_totp_secret(user_id, secret)).await
+    }
+
+    async fn enable_totp(
+        &self,
+        user_id: Uuid,
+        step: i64,
+        recovery_code_hashes: &[String],
+    ) -> Result<bool, sqlx::Error> {
+        timed("two_factor.enable_totp", self.0.enable_totp(user_id, step, recovery_code_hashes)).await
+    }
+
+    async fn disable_totp(&self, user_id: Uuid) -> Result<bool, sqlx::Error> {
+        timed("two_factor.disable_totp", self.0.disable_totp(user_id)).await
+    }
+
+    async fn use_totp_step(&self, user_id: Uuid, step: i64) -> Result<bool, sqlx::Error> {
+        timed("two_factor.use_totp_step", self.0.use_totp_step(user_id, step)).await
+    }
+
+    async fn unused_recovery_codes(&self, user_id: Uuid) -> Result<Vec<RecoveryCode>, sqlx::Error> {
+        timed("two_factor.unused_recovery_codes", self.0.unused_recovery_codes(user_id)).await
+    }
+
+    async fn use_recovery_code(&self, id: Uuid) -> Result<bool, sqlx::Error> {
+        timed("two_factor.use_recovery_code", self.0.use_recovery_code(id)).await
+    }
+
+    async fn replace_recovery_codes(&self, user_id: Uuid, code_hashes: &[String]) -> Result<(), sqlx::Error> {
+        timed("two_factor.replace_recovery_codes", self.0.replace_recovery_codes(user_id, code_hashes)).await
+    }
+}
+
+#[async_trait]
+impl SessionLock for TimedLock {
+    fn session(&self) -> &UploadSession {
+        self.0.session()
+    }
+
+    async fn set_received(
+        self: Box<Self>,
+        received_bytes: i64,
+        expires_at: DateTime<Utc>,
+    ) -> Result<UploadSession, sqlx::Error> {
+        timed("upload_session.set_received", self.0.set_received(received_bytes, expires_at)).await
+    }
+
+    async fn finish(
+        self: Box<Self>,
+        blob: &NewBlob,
+        default_quota: Option<i64>,
+        global_quota: Option<i64>,
+    ) -> Result<NewAttachment, sqlx::Error> {
+        timed("upload_session.finish", self.0.finish(blob, default_quota, global_quota)).await
+    }
+
+    async fn delete(self: Box<Self>) -> Result<(), sqlx::Error> {
+        timed("upload_session.delete", self.0.delete()).await
+    }
+}
+
+#[async_trait]
+impl<T: UploadRepository> UploadRepository for Timed<T> {
+    async fn find_blob(&self, sha256: &str) -> Result<Option<Blob>, sqlx::Error> {
+        timed("upload.find_blob", self.0.find_blob(sha256)).await
+    }
+
+    async fn find_blob_by_file_name(&self, file_name: &str) -> Result<Option<Blob>, sqlx::Error> {
+        timed("upload.find_blob_by_file_name", self.0.find_blob_by_file_name(file_name)).await
+    }
+
+    async fn find_pending_scan(&self, limit: i64) -> Result<Vec<Blob>, sqlx::Error> {
+        timed("upload.find_pending_scan", self.0.find_pending_scan(limit)).await
+    }
+
+    async fn set_scan_result(
+        &self,
+        sha256: &str,
+        status: ScanStatus,
+        signature: Option<&str>,
+    ) -> Result<Blob, sqlx::Error> {
+        timed("upload.set_scan_result", self.0.set_scan_result(sha256, status, signature)).await
+    }
+
+    async fn total_blob_size(&self) -> Result<i64, sqlx::Error> {
+        timed("upload.total_blob_size", self.0.total_blob_size()).await
+    }
+
+    async fn find_all_blobs(&self) -> Result<Vec<Blob>, sqlx::Error> {
+        timed("upload.find_all_blobs", self.0.find_all_blobs()).await
+    }
+
+    async fn find_unreferenced_blobs(&self, cutoff: DateTime<Utc>) -> Result<Vec<Blob>, sqlx::Error> {
+        timed("upload.find_unreferenced_blobs", self.0.find_unreferenced_blobs(cutoff)).await
+    }
+
+    async fn delete_unreferenced_blobs(&self, cutoff: DateTime<Utc>) -> Result<Vec<Blob>, sqlx::Error> {
+        timed("upload.delete_unreferenced_blobs", self.0.delete_unreferenced_blobs(cutoff)).await
+    }
+
+    async fn create_attachment(
+        &self,
+        blob: &NewBlob,
+        uploader_id: Option<Uuid>,
+        original_name: &str,
+        default_quota: Option<i64>,
+        global_quota: Option<i64>,
+    ) -> Result<NewAttachment, sqlx::Error> {
+        timed("upload.create_attachment", self.0.create_attachment(blob, uploader_id, original_name, default_quota, global_quota)).await
+    }
+
+    async fn find_orphaned_attachments(&self, cutoff: DateTime<Utc>) -> Result<Vec<OrphanedAttachment>, sqlx::Error> {
+        timed("upload.find_orphaned_attachments", self.0.find_orphaned_attachments(cutoff)).await
+    }
+
+    async fn delete_attachment(&self, id: Uuid) -> Result<(), sqlx::Error> {
+        timed("upload.delete_attachment", self.0.delete_attachment(id)).await
+    }
+
+    async fn largest_attachments(&self, user_id: Uuid, limit: i64) -> Result<Vec<UserFile>, sqlx::Error> {
+        timed("upload.largest_attachments", self.0.largest_attachments(user_id, limit)).await
+    }
+
+    async fn create_session(
+        &self,
+        user_id: Uuid,
+        file_name: &str,
+        total_size: i64,
+        expected_sha256: Option<&str>,
+        expires_at: DateTime<Utc>,
+    ) -> Result<UploadSession, sqlx::Error> {
+        timed("upload.create_session", self.0.create_session(user_id, file_name, total_size, expected_sha256, expires_at)).await
+    }
+
+    async fn find_session(&self, id: Uuid, user_id: Uuid) -> Result<Option<UploadSession>, sqlx::Error> {
+        timed("upload.find_session", self.0.find_session(id, user_id)).await
+    }
+
+    async fn lock_session(&self, id: Uuid, user_id: Uuid) -> Result<Option<Box<dyn SessionLock>>, sqlx::Error> {
+        let lock = timed("upload.lock_session", self.0.lock_session(id, user_id)).await?;
+        Ok(lock.map(|lock| Box::new(TimedLock(lock)) as Box<dyn SessionLock>))
+    }
+
+    async fn delete_session(&self, id: Uuid) -> Result<(), sqlx::Error> {
+        timed("upload.delete_session", self.0.delete_session(id)).await
+    }
+
+    async fn find_expired_sessions(&self) -> Result<Vec<UploadSession>, sqlx::Error> {
+        timed("upload.find_expired_sessions", self.0.find_expired_sessions()).await
+    }
+
+    async fn delete_expired_sessions(&self) -> Result<Vec<UploadSession>, sqlx::Error> {
+        timed("upload.delete_expired_sessions", self.0.delete_expired_sessions()).await
+    }
+}
@@ -0,0 +1,123 @@
+//! Every HTTP route the server answers, shared by `main` and the integration tests.
+
+use std::path::Path;
+use axum::extract::DefaultBodyLimit;
+use axum::http::{header, HeaderName, Method};
+use axum::routing::{delete, get, post, put};
+use axum::{middleware, Extension, Router};
+use tower_http::cors::CorsLayer;
+use tower_http::services::ServeDir;
+use tower_http::trace::{DefaultOnResponse, TraceLayer};
+use tracing::Level;
+
+use crate::config::Config;
+use crate::handlers::{self, auth_middleware};
+use crate::models::ApiScope;
+use crate::ws::{self, SharedChatState};
+use crate::{metrics, storage, telemetry};
+
+/// The whole app, with uploads and legacy avatars served from under `upload_root`.
+pub fn router(shared_state: SharedChatState, config: &Config, upload_root: &Path) -> Router {
+    let public_routes = Router::new()
+        .route("/register", post(handlers::register))
+        .route("/register/challenge", get(handlers::registration_challenge))
+        .route("/login", post(handlers::login))
+        .route("/login/2fa", post(handlers::login_two_factor))
+        .route("/password-reset", post(handlers::request_password_reset))
+        .route("/password-reset/confirm", post(handlers::reset_password))
+        .route("/ws/{username}", get(ws::handle_socket))
+        .route("/users", get(handlers::list_users))
+        .route("/public", get(handlers::get_public_messages))
+        .route("/metrics", get(metrics::handle_metrics))
+        .route("/healthz", get(handlers::healthz))
+        .route("/readyz", get(handlers::readyz))
+        .with_state(shared_state.clone());
+
+    let admin_routes = Router::new()
+        .route("/users/{username}/storage", get(handlers::admin_get_user_storage))
+        .route("/users/{username}/quota", put(handlers::admin_set_user_quota))
+        .route("/janitor", post(handlers::admin_run_janitor))
+        .route("/log-level", get(handlers::admin_get_log_level).put(handlers::admin_set_log_level))
+        .route("/lockouts", get(handlers::admin_get_lockouts))
+        .route("/lockouts/{username}", delete(handlers::admin_unlock_user))
+        .route("/users/{username}/password-reset", post(handlers::admin_create_password_reset))
+        .route("/invites", get(handlers::admin_get_invites).post(handlers::admin_create_invite))
+        .route("/invites/{id}", delete(handlers::admin_revoke_invite))
+        .route("/bots", post(handlers::admin_create_bot))
+        .route("/users/{username}/tokens", get(handlers::admin_get_api_tokens).post(handlers::admin_create_api_token))
+        .route("/tokens/{id}", delete(handlers::admin_revoke_api_token))
+        .layer(middleware::from_fn(handlers::admin_middleware));
+
+    // Account settings and administration, not for API tokens
+    let session_routes = Router::new()
+        .nest("/admin", admin_routes)
+        .route("/me/password", put(handlers::change_password))
+        .route("/me/username", put(handlers::change_username))
+        .route("/me/2fa", get(handlers::get_my_two_factor))
+        .route("/me/2fa/enroll", post(handlers::enroll_two_factor))
+        .route("/me/2fa/enable", post(handlers::enable_two_factor))
+        .route("/me/2fa/disable", post(handlers::disable_two_factor))
+        .route("/me/2fa/recovery-codes", post(handlers::regenerate_recovery_codes))
+        .route("/me/tokens", get(handlers::get_my_api_tokens).post(handlers::create_my_api_token))
+        .route("/me/tokens/{id}", delete(handlers::revoke_my_api_token))
+        .route_layer(middleware::from_fn(handlers::session_middleware));
+
+    let message_routes = Router::new()
+        .route("/dms", get(handlers::get_dms))
+        .route("/dm/{target_user}", get(handlers::get_dm_messages))
+        .route_layer(middleware::from_fn_with_state(ApiScope::MessagesRead, handlers::require_scope));
+
+    let upload_routes = Router::new()
+        .route("/upload", post(handlers::handle_uploads))
+        .route("/uploads", post(handlers::create_resumable_upload))
+        .route(
+            "/uploads/{id}",
+            get(handlers::get_resumable_upload)
+                .patch(handlers::patch_resumable_upload)
+                .delete(handlers::delete_resumable_upload)
+                .layer(DefaultBodyLimit::max(storage::MAX_CHUNK_SIZE)),
+        )
+        .route("/uploads/{id}/finalize", post(handlers::finalize_resumable_upload))
+        .route("/avatar-upload", post(handlers::handle_avatar))
+        .route_layer(middleware::from_fn_with_state(ApiScope::Uploads, handlers::require_scope));
+
+    let protected_routes = Router::new()
+        .merge(session_routes)
+        .merge(message_routes)
+        .merge(upload_routes)
+        .route("/users/{username}", get(handlers::get_user_profile))
+        .route("/me", get(handlers::get_meapi).patch(handlers::update_me))
+        .route("/me/storage", get(handlers::get_my_storage))
+        .layer(middleware::from_fn_with_state(shared_state.clone(), auth_middleware));
+
+    Router::new()
+        .merge(public_routes)
+        .nest("/api", protected_routes)
+        .nest_service("/avatars", ServeDir::new(upload_root.join("avatars")))
+        .nest(
+            "/uploads",
+            Router::new()
+                .fallback_service(ServeDir::new(upload_root.join("uploads")))
+                .layer(middleware::from_fn_with_state(shared_state.clone(), handlers::upload_scan_gate)),
+        )
+        .route_layer(middleware::from_fn(metrics::track_http))
+        .with_state(shared_state.clone())
+        .layer(Extension(shared_state))
+        .layer(
+            CorsLayer::new()
+                .allow_origin(config.cors_origins())
+                .allow_credentials(true)
+                .allow_methods([Method::GET, Method::POST, Method::PUT, Method::PATCH, Method::DELETE, Method::HEAD])
+                .allow_headers([
+                    header::AUTHORIZATION,
+                    header::CONTENT_TYPE,
+                    HeaderName::from_static(handlers::UPLOAD_OFFSET_HEADER),
+                ])
+                .expose_headers([HeaderName::from_static(handlers::UPLOAD_OFFSET_HEADER)]),
+        )
+        .layer(
+            TraceLayer::new_for_http()
+                .make_span_with(telemetry::request_span)
+                .on_response(DefaultOnResponse::new().level(Level::INFO)),
+        )
+}
@@ -0,0 +1,311 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::models::{Blob, ScanStatus};
+use crate::repository::UploadRepository;
+
+/// Size of the chunks streamed to clamd, well below its default `StreamMaxLength`.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Pending blobs picked up per rescan pass.
+const RESCAN_BATCH: i64 = 50;
+
+#[derive(Debug, Clone)]
+pub enum ClamdAddress {
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+impl ClamdAddress {
+    /// Accepts `tcp://host:port`, `host:port`, `unix:///path/clamd.sock` or an absolute
+    /// socket path.
+    pub fn parse(value: &str) -> Option<Self> {
+        let value = value.trim();
+        if let Some(path) = value.strip_prefix("unix://") {
+            Some(ClamdAddress::Unix(PathBuf::from(path)))
+        } else if value.starts_with('/') {
+            Some(ClamdAddress::Unix(PathBuf::from(value)))
+        } else if let Some(addr) = value.strip_prefix("tcp://") {
+            Some(ClamdAddress::Tcp(addr.to_string()))
+        } else if value.contains(':') {
+            Some(ClamdAddress::Tcp(value.to_string()))
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ScanError {
+    Io(std::io::Error),
+    Timeout,
+    /// clamd answered with something other than OK or FOUND.
+    Daemon(String),
+}
+
+impl fmt::Display for ScanError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScanError::Io(e) => write!(f, "clamd I/O error: {}", e),
+            ScanError::Timeout => write!(f, "clamd did not answer in time"),
+            ScanError::Daemon(reply) => write!(f, "clamd error: {}", reply),
+        }
+    }
+}
+
+impl From<std::io::Error> for ScanError {
+    fn from(e: std::io::Error) -> Self {
+        ScanError::Io(e)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScanVerdict {
+    Clean,
+    Infected(String),
+}
+
+/// Client for a clamd compatible daemon using the INSTREAM command.
+#[derive(Debug, Clone)]
+pub struct ClamdScanner {
+    pub address: ClamdAddress,
+    pub timeout: Duration,
+}
+
+impl ClamdScanner {
+    pub async fn scan_file(&self, path: &Path) -> Result<ScanVerdict, ScanError> {
+        let file = tokio::fs::File::open(path).await?;
+
+        let scan = async {
+            match &self.address {
+                ClamdAddress::Tcp(addr) => instream(TcpStream::connect(addr).await?, file).await,
+                #[cfg(unix)]
+                ClamdAddress::Unix(path) => instream(tokio::net::UnixStream::connect(path).await?, file).await,
+                #[cfg(not(unix))]
+                ClamdAddress::Unix(_) => Err(ScanError::Daemon("unix sockets are not supported here".into())),
+            }
+        };
+
+        tokio::time::timeout(self.timeout, scan)
+            .await
+            .map_err(|_| ScanError::Timeout)?
+    }
+}
+
+/// Streams `file` to clamd: `zINSTREAM\0`, then length prefixed chunks, then a zero
+/// length chunk, and parses the single reply line.
+async fn instream<S, R>(mut stream: S, mut file: R) -> Result<ScanVerdict, ScanError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    R: AsyncRead + Unpin,
+{
+    stream.write_all(b"zINSTREAM\0").await?;
+
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        stream.write_all(&(n as u32).to_be_bytes()).await?;
+        stream.write_all(&buf[..n]).await?;
+    }
+    stream.write_all(&0u32.to_be_bytes()).await?;
+    stream.flush().await?;
+
+    let mut reply = Vec::new();
+    stream.read_to_end(&mut reply).await?;
+
+    parse_reply(&String::from_utf8_lossy(&reply))
+}
+
+/// Parses `stream: OK`, `stream: <signature> FOUND` or an error reply.
+fn parse_reply(reply: &str) -> Result<ScanVerdict, ScanError> {
+    let reply = reply.trim_end_matches(['\0', '\n']).trim();
+    let body = reply.strip_prefix("stream:").map(str::trim).unwrap_or(reply);
+
+    if body == "OK" {
+        Ok(ScanVerdict::Clean)
+    } else if let Some(signature) = body.strip_suffix("FOUND") {
+        Ok(ScanVerdict::Infected(signature.trim().to_string()))
+    } else {
+        Err(ScanError::Daemon(reply.to_string()))
+    }
+}
+
+/// Scan status given to newly stored blobs.
+pub fn initial_status(scanner: Option<&ClamdScanner>) -> ScanStatus {
+    if scanner.is_some() {
+        ScanStatus::Pending
+    } else {
+        ScanStatus::Clean
+    }
+}
+
+/// Scans a stored blob and records the verdict. Infected files are moved from
+/// `uploads/` into `quarantine/` below `root`. When clamd cannot be reached the blob
+/// stays pending and is retried by [`spawn_rescans`].
+pub async fn scan_blob(uploads: &dyn UploadRepository, scanner: &ClamdScanner, root: &Path, blob: &Blob) -> Result<ScanStatus, ScanError> {
+    let path = root.join("uploads").join(blob.file_name());
+    let verdict = scanner.scan_file(&path).await?;
+
+    let status = match &verdict {
+        ScanVerdict::Clean => ScanStatus::Clean,
+        ScanVerdict::Infected(signature) => {
+            tracing::warn!(sha256 = %blob.sha256, signature = %signature, "blob is infected, quarantining");
+            let quarantine = root.join("quarantine");
+            tokio::fs::create_dir_all(&quarantine).await?;
+            tokio::fs::rename(&path, quarantine.join(blob.file_name())).await?;
+            ScanStatus::Infected
+        }
+    };
+
+    let signature = match &verdict {
+        ScanVerdict::Infected(signature) => Some(signature.as_str()),
+        ScanVerdict::Clean => None,
+    };
+
+    if let Err(e) = uploads.set_scan_result(&blob.sha256, status, signature).await {
+        tracing::error!(sha256 = %blob.sha256, error = %e, "failed to record scan result");
+    }
+
+    Ok(status)
+}
+
+/// Scans a freshly stored blob in the background.
+pub fn spawn_scan(uploads: Arc<dyn UploadRepository>, scanner: ClamdScanner, root: PathBuf, blob: Blob) {
+    tokio::spawn(async move {
+        if let Err(e) = scan_blob(uploads.as_ref(), &scanner, &root, &blob).await {
+            tracing::warn!(sha256 = %blob.sha256, error = %e, "scan failed, will retry");
+        }
+    });
+}
+
+/// Periodically retries blobs that are still pending, e.g. because clamd was down or
+/// the server restarted mid scan.
+pub fn spawn_rescans(uploads: Arc<dyn UploadRepository>, scanner: ClamdScanner, root: PathBuf) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(5 * 60));
+
+        loop {
+            interval.tick().await;
+            let pending = match uploads.find_pending_scan(RESCAN_BATCH).await {
+                Ok(pending) => pending,
+                Err(e) => {
+                    tracing::error!(error = %e, "failed to load pending blobs");
+                    continue;
+                }
+            };
+
+            for blob in pending {
+                if let Err(e) = scan_blob(uploads.as_ref(), &scanner, &root, &blob).await {
+                    tracing::warn!(sha256 = %blob.sha256, error = %e, "rescan failed");
+                    // clamd is most likely down, try the rest next time
+                    break;
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    /// Answers one INSTREAM request with `reply`, or never answers when `reply` is
+    /// `None`. Resolves to the chunks it received.
+    async fn fake_clamd(reply: Option<&'static str>) -> (String, tokio::task::JoinHandle<Vec<Vec<u8>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+
+        let handle = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+
+            let mut command = [0u8; 10];
+            stream.read_exact(&mut command).await.unwrap();
+            assert_eq!(&command, b"zINSTREAM\0");
+
+            let mut chunks = Vec::new();
+            loop {
+                let len = stream.read_u32().await.unwrap() as usize;
+                if len == 0 {
+                    break;
+                }
+                let mut chunk = vec![0u8; len];
+                stream.read_exact(&mut chunk).await.unwrap();
+                chunks.push(chunk);
+            }
+
+            match reply {
+                Some(reply) => stream.write_all(reply.as_bytes()).await.unwrap(),
+                None => tokio::time::sleep(Duration::from_secs(10)).await,
+            }
+            chunks
+        });
+
+        (address, handle)
+    }
+
+    async fn scan(reply: Option<&'static str>, data: &[u8]) -> (Result<ScanVerdict, ScanError>, Vec<Vec<u8>>) {
+        let (address, clamd) = fake_clamd(reply).await;
+        let path = std::env::temp_dir().join(format!("brochat-scan-{}", uuid::Uuid::new_v4()));
+        tokio::fs::write(&path, data).await.unwrap();
+
+        let scanner = ClamdScanner {
+            address: ClamdAddress::Tcp(address),
+            timeout: Duration::from_millis(500),
+        };
+        let verdict = scanner.scan_file(&path).await;
+        tokio::fs::remove_file(&path).await.unwrap();
+
+        if reply.is_none() {
+            clamd.abort();
+            return (verdict, Vec::new());
+        }
+        (verdict, clamd.await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn streams_the_file_in_length_prefixed_chunks() {
+        let data: Vec<u8> = (0..CHUNK_SIZE * 2 + 100).map(|i| i as u8).collect();
+        let (verdict, chunks) = scan(Some("stream: OK\0"), &data).await;
+
+        assert_eq!(verdict.unwrap(), ScanVerdict::Clean);
+        assert_eq!(chunks.iter().map(Vec::len).collect::<Vec<_>>(), [CHUNK_SIZE, CHUNK_SIZE, 100]);
+        assert_eq!(chunks.concat(), data);
+    }
+
+    #[tokio::test]
+    async fn reports_found_signatures() {
+        let (verdict, _) = scan(Some("stream: Eicar-Test-Signature FOUND\0"), b"X5O!P%@AP").await;
+        assert_eq!(verdict.unwrap(), ScanVerdict::Infected("Eicar-Test-Signature".to_string()));
+    }
+
+    #[tokio::test]
+    async fn reports_daemon_errors() {
+        let (verdict, _) = scan(Some("INSTREAM size limit exceeded. ERROR\0"), b"data").await;
+        assert!(matches!(verdict, Err(ScanError::Daemon(reply)) if reply == "INSTREAM size limit exceeded. ERROR"));
+    }
+
+    #[tokio::test]
+    async fn times_out_when_clamd_does_not_answer() {
+        let (verdict, _) = scan(None, b"data").await;
+        assert!(matches!(verdict, Err(ScanError::Timeout)));
+    }
+
+    #[test]
+    fn parses_replies() {
+        assert_eq!(parse_reply("stream: OK\n").unwrap(), ScanVerdict::Clean);
+        assert_eq!(parse_reply("OK").unwrap(), ScanVerdict::Clean);
+        assert_eq!(
+            parse_reply("stream: Win.Test.EICAR_HDB-1 FOUND\0").unwrap(),
+            ScanVerdict::Infected("Win.Test.EICAR_HDB-1".to_string()),
+        );
+        assert!(matches!(parse_reply(""), Err(ScanError::Daemon(_))));
+    }
+}
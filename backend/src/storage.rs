@@ -0,0 +1,490 @@
+use std::fmt;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use chrono::{Duration, Utc};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use uuid::Uuid;
+
+use crate::models::{blob_file_name, Attachment, Blob, NewBlob, ScanStatus, UploadSession};
+use crate::repository::{NewAttachment, Repositories, SessionLock};
+
+/// Blobs that lost their last reference are kept this long before being collected,
+/// so an upload racing with the collector never loses its file.
+pub const GC_GRACE_PERIOD: Duration = Duration::hours(1);
+
+/// Resumable uploads that receive no data for this long are discarded.
+pub const UPLOAD_SESSION_TTL: Duration = Duration::hours(24);
+
+/// Largest file accepted through the resumable upload API.
+pub const MAX_RESUMABLE_UPLOAD_SIZE: i64 = 2 * 1024 * 1024 * 1024;
+
+/// Largest single chunk accepted by `PATCH /api/uploads/{id}`.
+pub const MAX_CHUNK_SIZE: usize = 8 * 1024 * 1024;
+
+/// Storage limits, `None` meaning unlimited.
+#[derive(Debug, Clone, Copy)]
+pub struct StorageQuota {
+    /// Default limit for each user, admins can override it per user.
+    pub per_user_bytes: Option<i64>,
+    /// Limit on the bytes taken by all blobs together.
+    pub global_bytes: Option<i64>,
+}
+
+impl StorageQuota {
+    pub const DEFAULT_PER_USER_BYTES: i64 = 1024 * 1024 * 1024;
+}
+
+#[derive(Debug)]
+pub enum StorageError {
+    Io(std::io::Error),
+    Database(sqlx::Error),
+    /// The upload session does not exist, has expired or belongs to someone else.
+    SessionNotFound,
+    /// The client sent a chunk for a different offset than the server has stored.
+    OffsetMismatch { expected: i64 },
+    /// The chunk would grow the file past the length declared at creation.
+    ExceedsDeclaredSize,
+    /// Finalize was called before all bytes were received.
+    Incomplete { received: i64, total: i64 },
+    /// The assembled file does not match the checksum declared at creation.
+    ChecksumMismatch,
+    /// Storing the file would take the user past their quota.
+    QuotaExceeded { used: i64, quota: Option<i64>, requested: i64 },
+    /// Storing the file would take the server past its global quota.
+    GlobalQuotaExceeded,
+    /// The same content was uploaded before and found to be malware.
+    Infected { signature: Option<String> },
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageError::Io(e) => write!(f, "storage I/O error: {}", e),
+            StorageError::Database(e) => write!(f, "storage database error: {}", e),
+            StorageError::SessionNotFound => write!(f, "upload session not found"),
+            StorageError::OffsetMismatch { expected } => write!(f, "upload offset mismatch, expected {}", expected),
+            StorageError::ExceedsDeclaredSize => write!(f, "chunk exceeds the declared upload size"),
+            StorageError::Incomplete { received, total } => write!(f, "upload incomplete: {} of {} bytes received", received, total),
+            StorageError::ChecksumMismatch => write!(f, "upload checksum mismatch"),
+            StorageError::QuotaExceeded { used, quota, requested } => write!(
+                f, "storage quota exceeded: {} bytes used of {:?}, {} requested", used, quota, requested
+            ),
+            StorageError::GlobalQuotaExceeded => write!(f, "global storage quota exceeded"),
+            StorageError::Infected { signature } => write!(f, "upload is infected: {:?}", signature),
+        }
+    }
+}
+
+impl From<std::io::Error> for StorageError {
+    fn from(e: std::io::Error) -> Self {
+        StorageError::Io(e)
+    }
+}
+
+impl From<sqlx::Error> for StorageError {
+    fn from(e: sqlx::Error) -> Self {
+        StorageError::Database(e)
+    }
+}
+
+pub struct StoredUpload {
+    pub attachment: Attachment,
+    pub blob: Blob,
+    /// True when the content was already on disk and no new file was written.
+    pub deduplicated: bool,
+}
+
+impl StoredUpload {
+    pub fn upload_url(&self) -> String {
+        format!("/uploads/{}", self.blob.file_name())
+    }
+}
+
+pub fn sha256_hex(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+/// Extension used for the stored blob, taken from the client supplied file name.
+pub fn file_extension(original_name: &str) -> String {
+    PathBuf::from(original_name)
+        .extension()
+        .and_then(|e| e.to_str())
+        .filter(|e| e.chars().all(|c| c.is_ascii_alphanumeric()))
+        .map(|e| e.to_ascii_lowercase())
+        .unwrap_or_default()
+}
+
+/// Describes the blob for `sha256` and works out its file name. A blob keeps the
+/// extension of its first upload, so an existing row takes precedence over `extension`.
+/// Content that was found infected before is refused outright.
+async fn prepare_blob(
+    repos: &Repositories,
+    sha256: String,
+    size_bytes: i64,
+    extension: String,
+    scan_status: ScanStatus,
+) -> Result<(String, NewBlob), StorageError> {
+    match repos.uploads.find_blob(&sha256).await? {
+        Some(blob) if blob.scan_status == ScanStatus::Infected => {
+            Err(StorageError::Infected { signature: blob.scan_signature })
+        }
+        Some(blob) => Ok((
+            blob.file_name(),
+            NewBlob { sha256, size_bytes, extension: blob.extension, scan_status },
+        )),
+        None => Ok((
+            blob_file_name(&sha256, &extension),
+            NewBlob { sha256, size_bytes, extension, scan_status },
+        )),
+    }
+}
+
+/// Whether the file of a just recorded blob is already in place. The blob row
+/// decides, not the file system: when this upload holds the only reference, the
+/// file may be gone, so it is written again.
+async fn is_stored(path: &Path, blob: &Blob) -> Result<bool, std::io::Error> {
+    Ok(blob.ref_count > 1 && tokio::fs::try_exists(path).await?)
+}
+
+/// Takes back an attachment whose file could not be put in place.
+async fn discard_attachment(repos: &Repositories, attachment: &Attachment) {
+    if let Err(e) = repos.uploads.delete_attachment(attachment.id).await {
+        tracing::error!(attachment_id = %attachment.id, error = %e, "failed to discard attachment");
+    }
+}
+
+/// Stores `data` under its content hash in `dir`, reusing the existing file when the
+/// same content was uploaded before, and records an attachment referencing it.
+/// New blobs get `scan_status`, which is pending when a malware scanner is configured.
+pub async fn store_upload(
+    repos: &Repositories,
+    dir: &Path,
+    quota: &StorageQuota,
+    scan_status: ScanStatus,
+    uploader_id: Option<Uuid>,
+    original_name: &str,
+    data: &[u8],
+) -> Result<StoredUpload, StorageError> {
+    let (file_name, new_blob) = prepare_blob(
+        repos,
+        sha256_hex(data),
+        data.len() as i64,
+        file_extension(original_name),
+        scan_status,
+    ).await?;
+
+    let (attachment, blob) = record_attachment(repos, quota, &new_blob, uploader_id, original_name).await?;
+
+    let path = dir.join(&file_name);
+    let deduplicated = is_stored(&path, &blob).await?;
+    if !deduplicated && let Err(e) = write_file(dir, &file_name, data).await {
+        discard_attachment(repos, &attachment).await;
+        return Err(e.into());
+    }
+
+    Ok(StoredUpload { attachment, blob, deduplicated })
+}
+
+/// Writes to a temporary name first so a half written file is never served.
+async fn write_file(dir: &Path, file_name: &str, data: &[u8]) -> Result<(), std::io::Error> {
+    tokio::fs::create_dir_all(dir).await?;
+    let tmp_path = dir.join(format!(".{}.{}.tmp", file_name, Uuid::new_v4()));
+    tokio::fs::write(&tmp_path, data).await?;
+    tokio::fs::rename(&tmp_path, dir.join(file_name)).await
+}
+
+/// Fails early when `size_bytes` more would not fit the user's or the global quota.
+/// Content that is already stored takes no space, but that is only known when the
+/// client declares its `sha256`. Both quotas are enforced again atomically when the
+/// attachment is recorded.
+pub async fn check_quota(
+    repos: &Repositories,
+    quota: &StorageQuota,
+    uploader_id: Option<Uuid>,
+    size_bytes: i64,
+    sha256: Option<&str>,
+) -> Result<(), StorageError> {
+    if let Some(global) = quota.global_bytes {
+        let stored = match sha256 {
+            Some(sha256) => repos.uploads.find_blob(&sha256.to_ascii_lowercase()).await?.is_some(),
+            None => false,
+        };
+        if !stored && repos.uploads.total_blob_size().await? + size_bytes > global {
+            return Err(StorageError::GlobalQuotaExceeded);
+        }
+    }
+
+    if let Some(user_id) = uploader_id {
+        let usage = repos.users.storage_usage(user_id).await?;
+        let limit = usage.quota_bytes.or(quota.per_user_bytes);
+        if limit.is_some_and(|limit| usage.used_bytes + size_bytes > limit) {
+            return Err(StorageError::QuotaExceeded {
+                used: usage.used_bytes,
+                quota: limit,
+                requested: size_bytes,
+            });
+        }
+    }
+
+    Ok(())
+}
+
+/// Charges the uploader and the global quota and records the attachment, atomically.
+async fn record_attachment(
+    repos: &Repositories,
+    quota: &StorageQuota,
+    new_blob: &NewBlob,
+    uploader_id: Option<Uuid>,
+    original_name: &str,
+) -> Result<(Attachment, Blob), StorageError> {
+    let created = repos.uploads
+        .create_attachment(new_blob, uploader_id, original_name, quota.per_user_bytes, quota.global_bytes)
+        .await?;
+    created_attachment(repos, quota, created, uploader_id, new_blob.size_bytes).await
+}
+
+/// Turns a refused attachment into the matching error.
+async fn created_attachment(
+    repos: &Repositories,
+    quota: &StorageQuota,
+    created: NewAttachment,
+    uploader_id: Option<Uuid>,
+    requested: i64,
+) -> Result<(Attachment, Blob), StorageError> {
+    match created {
+        NewAttachment::Created(created) => Ok(*created),
+        NewAttachment::GlobalQuotaExceeded => Err(StorageError::GlobalQuotaExceeded),
+        NewAttachment::QuotaExceeded => {
+            // Only uploads with an uploader are charged, so only those get refused
+            let user_id = uploader_id.ok_or(sqlx::Error::RowNotFound)?;
+            let usage = repos.users.storage_usage(user_id).await?;
+            Err(StorageError::QuotaExceeded {
+                used: usage.used_bytes,
+                quota: usage.quota_bytes.or(quota.per_user_bytes),
+                requested,
+            })
+        }
+    }
+}
+
+
+/// Moves the assembled file of a finished upload session into blob storage, or drops
+/// it when the same content is already stored. The session is deleted along with
+/// recording the attachment, which releases the lock.
+async fn store_file(
+    repos: &Repositories,
+    dir: &Path,
+    quota: &StorageQuota,
+    scan_status: ScanStatus,
+    lock: Box<dyn SessionLock>,
+    source: &Path,
+    sha256: String,
+) -> Result<StoredUpload, StorageError> {
+    let session = lock.session().clone();
+    let (file_name, new_blob) = prepare_blob(
+        repos,
+        sha256,
+        session.total_size,
+        file_extension(&session.file_name),
+        scan_status,
+    ).await?;
+
+    // The partial file stays where it is until this succeeds, so a refused upload
+    // can be finalized again later
+    let created = lock.finish(&new_blob, quota.per_user_bytes, quota.global_bytes).await?;
+    let (attachment, blob) = created_attachment(repos, quota, created, Some(session.user_id), session.total_size).await?;
+
+    let path = dir.join(&file_name);
+    let deduplicated = is_stored(&path, &blob).await?;
+    if deduplicated {
+        if let Err(e) = remove_if_exists(source).await {
+            tracing::error!(path = %source.display(), error = %e, "failed to remove partial upload");
+        }
+    } else if let Err(e) = move_file(source, dir, &path).await {
+        discard_attachment(repos, &attachment).await;
+        return Err(e.into());
+    }
+
+    Ok(StoredUpload { attachment, blob, deduplicated })
+}
+
+async fn move_file(source: &Path, dir: &Path, path: &Path) -> Result<(), std::io::Error> {
+    tokio::fs::create_dir_all(dir).await?;
+    tokio::fs::rename(source, path).await
+}
+
+async fn sha256_file(path: &Path) -> Result<String, std::io::Error> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 64 * 1024];
+
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+
+    Ok(hex::encode(hasher.finalize()))
+}
+
+fn partial_path(partial_dir: &Path, id: Uuid) -> PathBuf {
+    partial_dir.join(id.to_string())
+}
+
+/// Opens a resumable upload and creates its (empty) partial file.
+pub async fn create_upload_session(
+    repos: &Repositories,
+    partial_dir: &Path,
+    quota: &StorageQuota,
+    user_id: Uuid,
+    file_name: &str,
+    total_size: i64,
+    expected_sha256: Option<&str>,
+) -> Result<UploadSession, StorageError> {
+    check_quota(repos, quota, Some(user_id), total_size, expected_sha256).await?;
+
+    tokio::fs::create_dir_all(partial_dir).await?;
+
+    let session = repos.uploads.create_session(
+        user_id,
+        file_name,
+        total_size,
+        expected_sha256,
+        Utc::now() + UPLOAD_SESSION_TTL,
+    )
+    .await?;
+
+    tokio::fs::File::create(partial_path(partial_dir, session.id)).await?;
+
+    Ok(session)
+}
+
+/// Appends `data` at `offset` to the partial file of an upload session. The session row
+/// stays locked while writing, so the stored offset always matches the file length.
+pub async fn append_chunk(
+    repos: &Repositories,
+    partial_dir: &Path,
+    id: Uuid,
+    user_id: Uuid,
+    offset: i64,
+    data: &[u8],
+) -> Result<UploadSession, StorageError> {
+    let lock = repos.uploads
+        .lock_session(id, user_id)
+        .await?
+        .ok_or(StorageError::SessionNotFound)?;
+    let session = lock.session().clone();
+
+    if offset != session.received_bytes {
+        return Err(StorageError::OffsetMismatch { expected: session.received_bytes });
+    }
+    if session.received_bytes + data.len() as i64 > session.total_size {
+        return Err(StorageError::ExceedsDeclaredSize);
+    }
+
+    let path = partial_path(partial_dir, session.id);
+    let mut file = tokio::fs::OpenOptions::new().write(true).open(&path).await?;
+    // Drop anything left over from a chunk whose transaction did not commit.
+    file.set_len(session.received_bytes as u64).await?;
+    file.seek(SeekFrom::Start(session.received_bytes as u64)).await?;
+    file.write_all(data).await?;
+    file.sync_data().await?;
+
+    let session = lock
+        .set_received(session.received_bytes + data.len() as i64, Utc::now() + UPLOAD_SESSION_TTL)
+        .await?;
+
+    Ok(session)
+}
+
+/// Verifies a fully received upload and moves it into blob storage. The session
+/// stays locked throughout, so it can't be finalized twice or changed meanwhile.
+pub async fn finalize_upload(
+    repos: &Repositories,
+    partial_dir: &Path,
+    upload_dir: &Path,
+    quota: &StorageQuota,
+    scan_status: ScanStatus,
+    id: Uuid,
+    user_id: Uuid,
+) -> Result<StoredUpload, StorageError> {
+    let lock = repos.uploads
+        .lock_session(id, user_id)
+        .await?
+        .ok_or(StorageError::SessionNotFound)?;
+    let session = lock.session().clone();
+
+    if session.received_bytes != session.total_size {
+        return Err(StorageError::Incomplete {
+            received: session.received_bytes,
+            total: session.total_size,
+        });
+    }
+
+    let path = partial_path(partial_dir, session.id);
+    let sha256 = sha256_file(&path).await?;
+
+    if let Some(expected) = &session.expected_sha256
+        && !expected.eq_ignore_ascii_case(&sha256)
+    {
+        lock.delete().await?;
+        remove_if_exists(&path).await?;
+        return Err(StorageError::ChecksumMismatch);
+    }
+
+    store_file(repos, upload_dir, quota, scan_status, lock, &path, sha256).await
+}
+
+/// Deletes an upload session and whatever has been received for it.
+pub async fn abort_upload(repos: &Repositories, partial_dir: &Path, id: Uuid) -> Result<(), StorageError> {
+    repos.uploads.delete_session(id).await?;
+    remove_if_exists(&partial_path(partial_dir, id)).await?;
+    Ok(())
+}
+
+/// Drops upload sessions that went past their expiry along with their partial files.
+pub async fn expire_upload_sessions(repos: &Repositories, partial_dir: &Path) -> Result<usize, StorageError> {
+    let sessions = repos.uploads.delete_expired_sessions().await?;
+
+    for session in &sessions {
+        if let Err(e) = remove_if_exists(&partial_path(partial_dir, session.id)).await {
+            tracing::error!(session_id = %session.id, error = %e, "failed to remove partial upload");
+        }
+    }
+
+    Ok(sessions.len())
+}
+
+pub async fn remove_if_exists(path: &Path) -> Result<(), std::io::Error> {
+    match tokio::fs::remove_file(path).await {
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Deletes blobs whose reference count dropped to zero more than [`GC_GRACE_PERIOD`] ago,
+/// together with their files in `dir`. Returns the blobs removed.
+pub async fn collect_garbage(repos: &Repositories, dir: &Path) -> Result<Vec<Blob>, StorageError> {
+    let blobs = repos.uploads.delete_unreferenced_blobs(Utc::now() - GC_GRACE_PERIOD).await?;
+
+    for blob in &blobs {
+        // An upload of the same content may have recorded the blob again since
+        match repos.uploads.find_blob(&blob.sha256).await {
+            Ok(None) => {}
+            Ok(Some(_)) => continue,
+            Err(e) => {
+                tracing::error!(sha256 = %blob.sha256, error = %e, "failed to check blob before removing it");
+                continue;
+            }
+        }
+        if let Err(e) = remove_if_exists(&dir.join(blob.file_name())).await {
+            tracing::error!(sha256 = %blob.sha256, error = %e, "failed to remove blob");
+        }
+    }
+
+    Ok(blobs)
+}
@@ -0,0 +1,65 @@
+use std::sync::OnceLock;
+use axum::body::Body;
+use axum::http::Request;
+use tracing::Span;
+use tracing_subscriber::filter::ParseError;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, reload, EnvFilter, Registry};
+use uuid::Uuid;
+
+use crate::config::{LogFormat, LoggingConfig};
+
+static FILTER: OnceLock<reload::Handle<EnvFilter, Registry>> = OnceLock::new();
+
+pub fn parse_filter(directives: &str) -> Result<EnvFilter, ParseError> {
+    EnvFilter::builder().parse(directives)
+}
+
+/// Installs the global subscriber. The filter sits behind a reload layer so
+/// [`set_filter`] can change it while the server runs.
+pub fn init(config: &LoggingConfig) -> Result<(), String> {
+    let filter = parse_filter(&config.level).map_err(|e| e.to_string())?;
+    let (filter, handle) = reload::Layer::new(filter);
+
+    let json = (config.format == LogFormat::Json).then(|| {
+        fmt::layer()
+            .json()
+            .with_current_span(true)
+            .with_span_list(false)
+    });
+    let pretty = (config.format == LogFormat::Pretty).then(fmt::layer);
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(json)
+        .with(pretty)
+        .try_init()
+        .map_err(|e| e.to_string())?;
+
+    let _ = FILTER.set(handle);
+    Ok(())
+}
+
+/// The filter directives currently in effect.
+pub fn current_filter() -> Option<String> {
+    FILTER.get()?.with_current(|filter| filter.to_string()).ok()
+}
+
+pub fn set_filter(directives: &str) -> Result<(), String> {
+    let filter = parse_filter(directives).map_err(|e| e.to_string())?;
+    let handle = FILTER.get().ok_or("logging is not initialised")?;
+    handle.reload(filter).map_err(|e| e.to_string())
+}
+
+/// Span wrapping every HTTP request. Only the path is recorded, query strings and
+/// headers can carry tokens. `user_id` is filled in by the auth middleware.
+pub fn request_span(req: &Request<Body>) -> Span {
+    tracing::info_span!(
+        "request",
+        request_id = %Uuid::new_v4(),
+        method = %req.method(),
+        path = %req.uri().path(),
+        user_id = tracing::field::Empty,
+    )
+}
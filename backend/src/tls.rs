@@ -0,0 +1,85 @@
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use axum::Router;
+use axum::extract::Request;
+use axum::http::{header, StatusCode, Uri};
+use axum::response::{IntoResponse, Redirect, Response};
+use axum_server::tls_rustls::RustlsConfig;
+
+/// Reads the PEM certificate chain and private key.
+pub async fn load(cert_path: &Path, key_path: &Path) -> std::io::Result<RustlsConfig> {
+    RustlsConfig::from_pem_file(cert_path, key_path).await
+}
+
+/// Last modification of either file, used to notice renewed certificates.
+async fn modified(cert_path: &Path, key_path: &Path) -> std::io::Result<(SystemTime, SystemTime)> {
+    let cert = tokio::fs::metadata(cert_path).await?.modified()?;
+    let key = tokio::fs::metadata(key_path).await?.modified()?;
+    Ok((cert, key))
+}
+
+/// Checks the certificate and key every `interval` and swaps them into `tls` when
+/// either file changed. Connections that are already open keep their certificate.
+pub fn spawn_reload(tls: RustlsConfig, cert_path: PathBuf, key_path: PathBuf, interval: Duration) {
+    tokio::spawn(async move {
+        let mut last = modified(&cert_path, &key_path).await.ok();
+        let mut interval = tokio::time::interval(interval);
+        interval.tick().await;
+
+        loop {
+            interval.tick().await;
+
+            let current = match modified(&cert_path, &key_path).await {
+                Ok(current) => current,
+                Err(e) => {
+                    tracing::warn!(error = %e, "cannot check TLS certificate for changes");
+                    continue;
+                }
+            };
+            if last == Some(current) {
+                continue;
+            }
+
+            // A renewal tool may write the certificate and the key one after the other,
+            // a mismatched pair fails here and is retried on the next tick
+            match tls.reload_from_pem_file(&cert_path, &key_path).await {
+                Ok(()) => {
+                    last = Some(current);
+                    tracing::info!(cert = %cert_path.display(), "reloaded TLS certificate");
+                }
+                Err(e) => tracing::error!(error = %e, "failed to reload TLS certificate, keeping the old one"),
+            }
+        }
+    });
+}
+
+/// Sends every request to the same host and path on the HTTPS port.
+async fn redirect_to_https(https_port: u16, request: Request) -> Response {
+    let Some(host) = request.headers().get(header::HOST).and_then(|h| h.to_str().ok()) else {
+        return (StatusCode::BAD_REQUEST, "missing Host header").into_response();
+    };
+    let host = match host.parse::<Uri>().ok().and_then(|uri| uri.host().map(str::to_string)) {
+        Some(host) => host,
+        None => return (StatusCode::BAD_REQUEST, "invalid Host header").into_response(),
+    };
+
+    let authority = if https_port == 443 { host } else { format!("{}:{}", host, https_port) };
+    let path = request.uri().path_and_query().map(|p| p.as_str()).unwrap_or("/");
+
+    Redirect::permanent(&format!("https://{}{}", authority, path)).into_response()
+}
+
+/// Serves the plain HTTP redirect on `addr` until the process exits.
+pub async fn spawn_redirect(addr: SocketAddr, https_port: u16) -> std::io::Result<()> {
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    let app = Router::new().fallback(move |request: Request| redirect_to_https(https_port, request));
+
+    tokio::spawn(async move {
+        if let Err(e) = axum::serve(listener, app).await {
+            tracing::error!(error = %e, "HTTP redirect server failed");
+        }
+    });
+
+    Ok(())
+}
@@ -0,0 +1,85 @@
+use rand::Rng;
+use totp_rs::{Algorithm, Secret, TOTP};
+
+use crate::utils::constant_time_eq;
+
+/// Shown next to the account in authenticator apps.
+const ISSUER: &str = "BroChat";
+
+const DIGITS: usize = 6;
+const STEP_SECS: u64 = 30;
+/// Codes from one step before or after the current one are accepted, for clock drift.
+const SKEW_STEPS: i64 = 1;
+
+pub const RECOVERY_CODE_COUNT: usize = 10;
+const RECOVERY_CODE_ALPHABET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+const RECOVERY_CODE_GROUP_LEN: usize = 5;
+
+fn totp(secret: &str, username: &str) -> Option<TOTP> {
+    let secret = Secret::Encoded(secret.to_string()).to_bytes().ok()?;
+    // Unchecked because usernames may contain characters an otpauth label can't,
+    // the URI escapes them anyway
+    Some(TOTP::new_unchecked(
+        Algorithm::SHA1,
+        DIGITS,
+        SKEW_STEPS as u8,
+        STEP_SECS,
+        secret,
+        Some(ISSUER.to_string()),
+        username.to_string(),
+    ))
+}
+
+/// A new random 160 bit secret, base32 encoded as authenticator apps expect.
+pub fn generate_secret() -> String {
+    let bytes: [u8; 20] = rand::random();
+    Secret::Raw(bytes.to_vec()).to_encoded().to_string()
+}
+
+/// `otpauth://totp/...` URI for QR codes and manual entry.
+pub fn otpauth_uri(secret: &str, username: &str) -> Option<String> {
+    Some(totp(secret, username)?.get_url())
+}
+
+/// The time step `code` is valid for at `unix_time`, if any. Callers still have to
+/// make sure the step was not used before.
+pub fn matching_step(secret: &str, code: &str, unix_time: u64) -> Option<i64> {
+    if !is_totp_code(code) {
+        return None;
+    }
+    let code = code.trim();
+
+    let totp = totp(secret, "")?;
+    let current = (unix_time / STEP_SECS) as i64;
+
+    (current - SKEW_STEPS..=current + SKEW_STEPS)
+        .filter(|step| *step >= 0)
+        .find(|step| constant_time_eq(totp.generate(*step as u64 * STEP_SECS).as_bytes(), code.as_bytes()))
+}
+
+/// Whether `code` looks like a TOTP code rather than a recovery code.
+pub fn is_totp_code(code: &str) -> bool {
+    let code = code.trim();
+    code.len() == DIGITS && code.bytes().all(|b| b.is_ascii_digit())
+}
+
+/// Fresh recovery codes such as `k7m2p-x9qrt`, shown to the user once.
+pub fn generate_recovery_codes() -> Vec<String> {
+    let mut rng = rand::thread_rng();
+    let mut group = || -> String {
+        (0..RECOVERY_CODE_GROUP_LEN)
+            .map(|_| RECOVERY_CODE_ALPHABET[rng.gen_range(0..RECOVERY_CODE_ALPHABET.len())] as char)
+            .collect()
+    };
+
+    (0..RECOVERY_CODE_COUNT).map(|_| format!("{}-{}", group(), group())).collect()
+}
+
+/// What gets hashed and compared: lowercase, without separators or spaces, so
+/// `K7M2P X9QRT` matches `k7m2p-x9qrt`.
+pub fn normalize_recovery_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
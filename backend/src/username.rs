@@ -0,0 +1,89 @@
+use std::collections::{BTreeMap, HashSet};
+use unicode_normalization::UnicodeNormalization;
+use unicode_security::{GeneralSecurityProfile, MixedScript};
+
+use crate::repository::UserRepository;
+
+/// The form a new username is stored and shown in: NFKC, so fullwidth and other
+/// compatibility characters become their plain equivalents, without surrounding
+/// whitespace. Case is kept.
+pub fn normalize(name: &str) -> String {
+    name.trim().nfkc().collect()
+}
+
+/// Unique key of a username, NFKC with full case folding, so `Bob`, `BOB` and
+/// `ｂｏｂ` are the same user. Lookups by name go through this.
+pub fn canonical(name: &str) -> String {
+    caseless::default_case_fold_str(&normalize(name)).nfkc().collect()
+}
+
+/// UTS #39 skeleton of the canonical form. Names with the same skeleton look alike,
+/// such as `alice` with a Cyrillic `а`, or `rn` and `m`.
+pub fn skeleton(name: &str) -> String {
+    unicode_security::skeleton(&canonical(name)).collect()
+}
+
+/// Whether the letters and digits are ones UTS #39 allows in identifiers and all
+/// come from one script, which rules out most lookalike tricks a skeleton misses.
+pub fn has_safe_characters(name: &str) -> bool {
+    name.chars().filter(|c| c.is_alphanumeric()).all(GeneralSecurityProfile::identifier_allowed)
+        && name.is_single_script()
+}
+
+/// Fills in the canonical form and skeleton of users from before they existed. When
+/// old accounts share a canonical name, the oldest by first message keeps it, then the
+/// first by name. The others store it too, so nobody can register a lookalike, but
+/// are flagged with [`User::username_needs_rename`](crate::models::User) until they
+/// are renamed. `brochat-admin username-collisions` lists them.
+pub async fn backfill(users: &dyn UserRepository) -> Result<(), sqlx::Error> {
+    let missing = users.without_canonical_username().await?;
+    if missing.is_empty() {
+        return Ok(());
+    }
+
+    let mut collisions = 0;
+    for (id, username) in &missing {
+        if !users.set_canonical_username(*id, &canonical(username), &skeleton(username)).await? {
+            tracing::warn!(
+                user_id = %id,
+                username = %username,
+                "username collides with an older account after normalization, flagged for a rename"
+            );
+            collisions += 1;
+        }
+    }
+
+    tracing::info!(users = missing.len() - collisions, collisions, "stored canonical usernames");
+    if collisions > 0 {
+        tracing::warn!(
+            collisions,
+            "some usernames differ only in case or form, list them with `brochat-admin username-collisions` \
+             and rename the flagged ones with `brochat-admin rename-user`"
+        );
+    }
+    Ok(())
+}
+
+/// Groups of existing usernames that are the same name once normalized, sorted by
+/// name, with whether each is flagged for a rename. Only accounts from before
+/// normalization can end up like this, and a group stays listed while one of them is
+/// flagged, even once the others are renamed.
+pub async fn collisions(users: &dyn UserRepository) -> Result<Vec<Vec<(String, bool)>>, sqlx::Error> {
+    let flagged: HashSet<String> = users.needing_rename().await?.into_iter().collect();
+    let mut by_canonical: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for user in users.list().await? {
+        by_canonical.entry(canonical(&user.username)).or_default().push(user.username);
+    }
+
+    Ok(by_canonical
+        .into_values()
+        .filter(|names| names.len() > 1 || names.iter().any(|name| flagged.contains(name)))
+        .map(|mut names| {
+            names.sort();
+            names.into_iter().map(|name| {
+                let needs_rename = flagged.contains(&name);
+                (name, needs_rename)
+            }).collect()
+        })
+        .collect())
+}
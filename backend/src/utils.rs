@@ -1,5 +1,8 @@
+use std::net::{IpAddr, SocketAddr};
+use std::sync::OnceLock;
 use argon2::{self, Argon2, PasswordHasher, PasswordVerifier};
 use argon2::password_hash::{PasswordHash, SaltString, Error as PasswordHashError};
+use axum::http::HeaderMap;
 use rand::rngs::OsRng;
 
 pub fn hash_password(password: &str) -> Result<String, PasswordHashError> {
@@ -20,3 +23,34 @@ pub fn verify_password(password: &str, hash: &str) -> Result<bool, PasswordHashE
     }
 }
 
+
+/// Hash of a random password, verified against when a login names an unknown user so
+/// the response takes as long as for a real one.
+pub fn dummy_password_hash() -> &'static str {
+    static HASH: OnceLock<String> = OnceLock::new();
+    HASH.get_or_init(|| {
+        let password: [u8; 16] = rand::random();
+        hash_password(&hex::encode(password)).expect("hashing a random password")
+    })
+}
+
+/// Address of the client, from `X-Forwarded-For` when the server is told to trust it.
+pub fn client_ip(headers: &HeaderMap, peer: SocketAddr, trust_forwarded_for: bool) -> IpAddr {
+    if trust_forwarded_for
+        && let Some(ip) = headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .next_back()
+            .and_then(|ip| ip.trim().parse().ok())
+    {
+        return ip;
+    }
+    peer.ip()
+}
+
+/// Compares secrets without leaking through timing how much of them matched.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
@@ -1,62 +1,240 @@
 use futures_util::{StreamExt, SinkExt};
 use axum::{
-    extract::{Path, State},
-    extract::ws::{Message, WebSocket, WebSocketUpgrade},
-    response::IntoResponse
+    extract::{Path, Query, State},
+    extract::ws::{CloseFrame, Message, WebSocket, WebSocketUpgrade},
+    http::HeaderMap,
+    response::{IntoResponse, Response}
 };
+use serde::Deserialize;
 use serde_json::{json, Value};
 use std::{
     collections::HashMap, path::PathBuf, sync::Arc
 };
 use tokio::sync::{broadcast, mpsc, RwLock};
+use tracing::{Instrument, Span};
 use uuid::Uuid;
 
-use crate::{db::get_pool, models::MessageModel};
+use crate::{config::Config, error::AppError, metrics::metrics, janitor::JanitorOptions, models::{ApiScope, AuthenticatedUser, MessageModel, Profile, User}, notify::ResetNotifier, policy::{PasswordPolicy, UsernamePolicy}, pow::ProofOfWork, ratelimit::LoginLimiter, repository::Repositories, scanner::ClamdScanner, storage::StorageQuota};
 
 pub struct ChatState {
     pub tx: broadcast::Sender<String>,
     pub users: HashMap<String, mpsc::UnboundedSender<Message>>, // uuid -> tx
     pub user_map: HashMap<String, String>,                      // uuid -> username
-    pub upload_dir: PathBuf
+    pub credentials: HashMap<String, SocketCredential>,         // uuid -> what it logged in with
+    pub upload_dir: PathBuf,
+    pub storage_quota: StorageQuota,
+    pub password_policy: PasswordPolicy,
+    pub username_policy: UsernamePolicy,
+    pub janitor: JanitorOptions,
+    pub scanner: Option<ClamdScanner>,
+    pub config: Arc<Config>,
+    pub repos: Repositories,
+    pub login_limiter: Arc<LoginLimiter>,
+    /// Throttles `POST /password-reset`, kept apart so requests don't lock logins.
+    pub reset_limiter: Arc<LoginLimiter>,
+    /// Delivers password reset links, `None` when only admins can hand them out.
+    pub reset_notifier: Option<Arc<dyn ResetNotifier>>,
+    /// Puzzle `/register` asks for, `None` when turned off.
+    pub proof_of_work: Option<Arc<ProofOfWork>>,
+    /// Set once shutdown starts, new sockets are refused from then on.
+    pub shutting_down: bool
     // pub rooms: HashMap<String, HashSet<String>>,                // room -> set of uuid
 }
 
 pub type SharedChatState = Arc<RwLock<ChatState>>;
 
+/// What an open socket was authenticated with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SocketCredential {
+    /// A session token of this user.
+    Session(Uuid),
+    /// The API token with this id.
+    ApiToken(Uuid),
+}
+
 impl ChatState {
-    pub fn new() -> (Self, broadcast::Receiver<String>) {
+    pub fn new(config: Arc<Config>, repos: Repositories) -> (Self, broadcast::Receiver<String>) {
         let (tx, rx) = broadcast::channel(100);
         (
             Self {
                 tx,
                 users: HashMap::new(),
                 user_map: HashMap::new(),
-                upload_dir: PathBuf::new()
+                credentials: HashMap::new(),
+                upload_dir: config.storage.root.clone(),
+                storage_quota: config.storage_quota(),
+                password_policy: config.password_policy(),
+                username_policy: config.username_policy(),
+                janitor: config.janitor_options(),
+                scanner: config.scanner(),
+                login_limiter: Arc::new(LoginLimiter::new(config.login_limits())),
+                reset_limiter: Arc::new(LoginLimiter::new(config.password_reset_limits())),
+                reset_notifier: config.reset_notifier(),
+                proof_of_work: config.proof_of_work(),
+                config,
+                repos,
+                shutting_down: false
                 // rooms: HashMap::new(),
             },
             rx,
         )
     }
+
+    /// Sends a changed profile to every client, so names, avatars and statuses update
+    /// without a reload.
+    pub fn broadcast_profile(&self, profile: &Profile) {
+        let profile = profile.clone().without_expired_status(chrono::Utc::now());
+        let _ = self.tx.send(json!({
+            "type": "profile",
+            "profile": profile
+        }).to_string());
+    }
+
+    /// The name the user behind socket `uuid` currently goes by, which changes when
+    /// they rename themselves while connected.
+    pub fn socket_username<'a>(&'a self, uuid: &str, fallback: &'a str) -> &'a str {
+        self.user_map.get(uuid).map_or(fallback, String::as_str)
+    }
+
+    /// Closes the sockets opened with `credential`, once the API token is revoked or
+    /// the password changed.
+    pub fn close_sockets(&self, credential: SocketCredential) {
+        let sockets = self.credentials.iter().filter(|(_, c)| **c == credential);
+        for tx in sockets.filter_map(|(uuid, _)| self.users.get(uuid)) {
+            let _ = tx.send(Message::Text(AppError::InvalidToken.ws_frame().into()));
+            let _ = tx.send(Message::Close(None));
+        }
+    }
+
+    /// Points open sockets of a renamed user at the new name, so DMs keep reaching
+    /// them, and tells everyone.
+    pub fn rename_user(&mut self, old_username: &str, new_username: &str) {
+        let mut renamed = false;
+        for name in self.user_map.values_mut().filter(|name| *name == old_username) {
+            *name = new_username.to_string();
+            renamed = true;
+        }
+
+        if renamed && old_username != new_username {
+            let _ = self.tx.send(json!({
+                "type": "system",
+                "message": format!("{} is now {}", old_username, new_username)
+            }).to_string());
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct SocketQuery {
+    /// For browsers, which can't set headers on a WebSocket.
+    token: Option<String>,
 }
 
 pub async fn handle_socket(
     Path(username): Path<String>,
+    Query(query): Query<SocketQuery>,
+    headers: HeaderMap,
     ws: WebSocketUpgrade,
     State(state): State<SharedChatState>,
-) -> impl IntoResponse {
-    ws.on_upgrade(move |socket| handle_connection(socket, username, state))
+) -> Response {
+    let (repos, config) = {
+        let state = state.read().await;
+        if state.shutting_down {
+            return AppError::ShuttingDown.into_response();
+        }
+        (state.repos.clone(), state.config.clone())
+    };
+
+    let auth_user = match authenticate_socket(&headers, query.token.as_deref(), &username, &repos, &config).await {
+        Ok(auth_user) => auth_user,
+        Err(e) => return e.into_response(),
+    };
+
+    ws.on_upgrade(move |socket| {
+        let uuid = Uuid::new_v4().to_string();
+        let span = tracing::info_span!(
+            "ws_connection",
+            connection_id = %uuid,
+            username = %username,
+            user_id = tracing::field::Empty,
+        );
+        handle_connection(socket, auth_user, uuid, state).instrument(span)
+    })
+    .into_response()
+}
+
+/// Every socket needs a session or API token for the user in the path, in the
+/// `Authorization` header or the `token` query parameter. API tokens also need
+/// `messages:read`.
+async fn authenticate_socket(
+    headers: &HeaderMap,
+    query_token: Option<&str>,
+    username: &str,
+    repos: &Repositories,
+    config: &Config,
+) -> Result<AuthenticatedUser, AppError> {
+    let auth_user = match query_token {
+        Some(token) if !headers.contains_key("Authorization") => {
+            AuthenticatedUser::from_token(token.trim(), repos, &config.auth).await?
+        }
+        _ => AuthenticatedUser::from_auth_header(headers, repos, &config.auth).await?,
+    };
+
+    let user = repos.users.find_by_username(username).await?;
+    if user.is_none_or(|user| user.id != auth_user.id) {
+        return Err(AppError::Forbidden);
+    }
+    if !auth_user.allows(ApiScope::MessagesRead) {
+        return Err(AppError::InsufficientScope(ApiScope::MessagesRead));
+    }
+
+    Ok(auth_user)
+}
+
+/// Close code for "Service Restart" from RFC 6455's registry.
+const CLOSE_SERVICE_RESTART: u16 = 1012;
+
+/// Tells every connected client the server is going away and closes their sockets.
+/// Clients get an error frame with the `shutting_down` code first, then a close frame
+/// with code 1012 so they know to reconnect.
+pub async fn close_all(state: &SharedChatState) {
+    let mut state = state.write().await;
+    state.shutting_down = true;
+
+    let frame = AppError::ShuttingDown.ws_frame();
+    for tx in state.users.values() {
+        let _ = tx.send(Message::Text(frame.clone().into()));
+        let _ = tx.send(Message::Close(Some(CloseFrame {
+            code: CLOSE_SERVICE_RESTART,
+            reason: "server restarting".into(),
+        })));
+    }
+
+    tracing::info!(connections = state.users.len(), "closed websockets for shutdown");
 }
 
-async fn handle_connection(socket: WebSocket, username: String, state: SharedChatState) {
+async fn handle_connection(socket: WebSocket, auth_user: AuthenticatedUser, uuid: String, state: SharedChatState) {
     let (mut ws_sender, mut ws_receiver) = socket.split();
-    let uuid = Uuid::new_v4().to_string();
     let (tx, mut rx) = mpsc::unbounded_channel::<Message>();
+    let repos = state.read().await.repos.clone();
+
+    // Sockets go by the name as registered, so DMs find them however it was typed,
+    // and post as the token's user
+    let (user_id, username, is_bot) = (auth_user.id, auth_user.username.clone(), auth_user.is_bot);
+    Span::current().record("user_id", tracing::field::display(user_id));
+    let can_write = auth_user.allows(ApiScope::MessagesWrite);
 
     {
         let mut state = state.write().await;
         state.users.insert(uuid.clone(), tx.clone());
         state.user_map.insert(uuid.clone(), username.clone());
-        println!("User '{}' connected with UUID {}", username, uuid);
+        let credential = match auth_user.api_token_id {
+            Some(token_id) => SocketCredential::ApiToken(token_id),
+            None => SocketCredential::Session(user_id),
+        };
+        state.credentials.insert(uuid.clone(), credential);
+        metrics().ws_connections.inc();
+        tracing::info!("websocket connected");
 
         let _ = state.tx.send(json!({
             "type": "system",
@@ -66,101 +244,140 @@ async fn handle_connection(socket: WebSocket, username: String, state: SharedCha
 
     let mut send_task = tokio::spawn(async move {
         while let Some(msg) = rx.recv().await {
-            if ws_sender.send(msg).await.is_err() {
+            let closing = matches!(msg, Message::Close(_));
+            if ws_sender.send(msg).await.is_err() || closing {
                 break;
             }
         }
-    });
+    }.instrument(Span::current()));
 
     let state_clone = Arc::clone(&state);
     let username_clone = username.clone();
     let uuid_clone = uuid.clone();
-    let pool = get_pool().await;
+    let socket_id = uuid.clone();
+    let own_tx = tx.clone();
 
     let mut recv_task = tokio::spawn(async move {
 
         while let Some(Ok(msg)) = ws_receiver.next().await
         {
 
-            if let Message::Text(text) = msg 
-            {
-                if let Ok(data) = serde_json::from_str::<Value>(&text) 
-                {
-                    match data["type"].as_str() {
-                        Some("dm") => {
-                            let to_username = data["to"].as_str().unwrap_or("");
-                            let message = data["message"].as_str().unwrap_or("");
-                            let uploadurl = data["upload_url"].as_str().unwrap_or("").to_string();
-                            let timestamp = chrono::Utc::now();
-
-                            let state = state_clone.read().await;
-
-                            let result = MessageModel::save_message(
-                                pool, 
-                                &username_clone, 
-                                "dm",
-                                message,
-                                &timestamp, 
-                                Some(to_username),
-                                Some(uploadurl.clone())
-                            ).await;
-
-                            match result {
-                                Ok(sav) => println!("{:?}", sav),
-                                Err(e) => eprintln!("Error saving message: {:?}", e),
+            if let Message::Text(text) = msg {
+                let data = match serde_json::from_str::<Value>(&text) {
+                    Ok(data) => data,
+                    Err(_) => {
+                        metrics().ws_messages.with_label_values(&["invalid"]).inc();
+                        send_error(&own_tx, &AppError::BadRequest("Messages must be JSON objects".to_string()));
+                        continue;
+                    }
+                };
+
+                tracing::debug!(
+                    message_type = data["type"].as_str().unwrap_or_default(),
+                    message_len = data["message"].as_str().map_or(0, str::len),
+                    "received frame"
+                );
+
+                // Only known types become labels, clients control this field
+                let message_type = data["type"].as_str()
+                    .filter(|t| matches!(*t, "dm" | "chat"))
+                    .unwrap_or("unknown");
+                metrics().ws_messages.with_label_values(&[message_type]).inc();
+
+                match data["type"].as_str() {
+                    Some("dm" | "chat") if !can_write => {
+                        send_error(&own_tx, &AppError::InsufficientScope(ApiScope::MessagesWrite));
+                    }
+                    Some("dm") => {
+                        let recipient = match resolve_recipient(&repos, data["to"].as_str().unwrap_or("")).await {
+                            Ok(recipient) => recipient,
+                            Err(e) => {
+                                send_error(&own_tx, &e);
+                                continue;
                             }
+                        };
+                        let message = data["message"].as_str().unwrap_or("");
+                        let uploadurl = data["upload_url"].as_str().unwrap_or("").to_string();
+                        let timestamp = chrono::Utc::now();
+
+                        let state = state_clone.read().await;
+
+                        let result = save_message(
+                            &repos,
+                            &auth_user,
+                            "dm",
+                            message,
+                            &timestamp,
+                            recipient.as_ref(),
+                            Some(uploadurl.clone())
+                        ).await;
 
-                            if let Some((recipient_uuid, _)) = state.user_map.iter().find(|(_, uname)| *uname == to_username) 
-                            {
-                                if let Some(tx) = state.users.get(recipient_uuid) 
-                                {
-                                    let _ = tx.send(Message::Text(
-                                        json!({
-                                            "type": "dm",
-                                            "from": username_clone,
-                                            "message": message,
-                                            "upload_url": uploadurl
-                                        }).to_string().into()
-                                    ));
-                                }
+                        if let Err(e) = result {
+                            tracing::warn!(code = e.code(), error = %e, "failed to save message");
+                            send_error(&own_tx, &e);
+                            // The send task stops after the close frame, which ends the connection
+                            if matches!(e, AppError::AccountDisabled | AppError::InvalidToken) {
+                                let _ = own_tx.send(Message::Close(None));
                             }
+                            continue;
                         }
-                        Some("chat") => {
-                            let message = data["message"].as_str().unwrap_or("");
-                            let uploadurl = data["upload_url"].as_str().unwrap_or("").to_string();
-                            let to_username = Some(data["to"].as_str().unwrap_or(""));
-                            let timestamp = chrono::Utc::now();
-
-                            let result = MessageModel::save_message(
-                                pool, 
-                                &username_clone, 
-                                "chat", 
-                                message, 
-                                &timestamp, 
-                                to_username,
-                                Some(uploadurl.clone())
-                            ).await;
-
-                            match result {
-                                Ok(sav) => println!("{:?}", sav),
-                                Err(e) => eprintln!("Error saving message: {:?}", e),
-                            }
 
-                            let _ = state_clone.read().await.tx.send(
+                        if let Some(recipient) = &recipient
+                            && let Some((recipient_uuid, _)) = state.user_map.iter().find(|(_, uname)| **uname == recipient.username)
+                            && let Some(tx) = state.users.get(recipient_uuid)
+                        {
+                            let _ = tx.send(Message::Text(
                                 json!({
-                                    "type": "chat",
-                                    "username": username_clone,
+                                    "type": "dm",
+                                    "from": state.socket_username(&socket_id, &username_clone),
+                                    "bot": is_bot,
                                     "message": message,
                                     "upload_url": uploadurl
-                                }).to_string()
-                            );
+                                }).to_string().into()
+                            ));
                         }
-                        _ => {}
                     }
+                    Some("chat") => {
+                        let message = data["message"].as_str().unwrap_or("");
+                        let uploadurl = data["upload_url"].as_str().unwrap_or("").to_string();
+                        let timestamp = chrono::Utc::now();
+
+                        let result = save_message(
+                            &repos,
+                            &auth_user,
+                            "chat",
+                            message,
+                            &timestamp,
+                            None,
+                            Some(uploadurl.clone())
+                        ).await;
+
+                        if let Err(e) = result {
+                            tracing::warn!(code = e.code(), error = %e, "failed to save message");
+                            send_error(&own_tx, &e);
+                            // The send task stops after the close frame, which ends the connection
+                            if matches!(e, AppError::AccountDisabled | AppError::InvalidToken) {
+                                let _ = own_tx.send(Message::Close(None));
+                            }
+                            continue;
+                        }
+
+                        let state = state_clone.read().await;
+                        let _ = state.tx.send(
+                            json!({
+                                "type": "chat",
+                                "username": state.socket_username(&socket_id, &username_clone),
+                                "bot": is_bot,
+                                "message": message,
+                                "upload_url": uploadurl
+                            }).to_string()
+                        );
+                    }
+                    _ => send_error(&own_tx, &AppError::BadRequest("Unknown message type".to_string())),
                 }
             }
         }
-    });
+    }.instrument(Span::current()));
 
     let mut broadcast_task = {
         let state = Arc::clone(&state);
@@ -168,14 +385,25 @@ async fn handle_connection(socket: WebSocket, username: String, state: SharedCha
 
             let mut rx = state.read().await.tx.subscribe();
 
-            while let Ok(msg) = rx.recv().await {
+            loop {
+                let msg = match rx.recv().await {
+                    Ok(msg) => msg,
+                    // A slow client missed some messages, keep it connected
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        metrics().broadcast_lagged.inc_by(skipped);
+                        tracing::warn!(skipped, "broadcast receiver lagged");
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                };
+
                 if let Some(tx) = state.read().await.users.get(&uuid_clone) 
                 {
                     let _ = tx.send(Message::Text(msg.into()));
                 }
             }
 
-        })
+        }.instrument(Span::current()))
     };
 
     tokio::select! {
@@ -194,13 +422,62 @@ async fn handle_connection(socket: WebSocket, username: String, state: SharedCha
     };
 
     let mut state = state.write().await;
+    metrics().ws_connections.dec();
     state.users.remove(&uuid);
-    state.user_map.remove(&uuid);
+    state.credentials.remove(&uuid);
+    let username = state.user_map.remove(&uuid).unwrap_or(username);
 
     let _ = state.tx.send(json!({
         "type": "system",
         "message": format!("{} left", username)
     }).to_string());
     
-    println!("User {} disconnected", uuid);
+    tracing::info!("websocket disconnected");
+}
+
+/// The user a DM goes to, however the name was typed. An empty name is left for
+/// [`MessageModel::check_recipient`] to reject.
+async fn resolve_recipient(repos: &Repositories, to_username: &str) -> Result<Option<User>, AppError> {
+    if to_username.trim().is_empty() {
+        return Ok(None);
+    }
+    let user = repos.users.find_by_username(to_username).await?.ok_or(AppError::NotFound("User"))?;
+    Ok(Some(user))
+}
+
+/// Stores a message sent over the socket, refusing direct messages without a recipient.
+/// The sender and their token are looked up again, so a socket opened before its
+/// account was disabled, its password changed or its API token revoked or expired
+/// can't keep posting.
+async fn save_message(
+    repos: &Repositories,
+    sender: &AuthenticatedUser,
+    message_type: &str,
+    message: &str,
+    timestamp: &chrono::DateTime<chrono::Utc>,
+    recipient: Option<&User>,
+    upload_url: Option<String>,
+) -> Result<(), AppError> {
+    MessageModel::check_recipient(message_type, recipient.map(|user| user.username.as_str()))?;
+    let user = repos.users.find_by_id(sender.id).await?.ok_or(AppError::InvalidToken)?;
+    if user.is_disabled() {
+        return Err(AppError::AccountDisabled);
+    }
+    if sender.token_version.is_some_and(|version| version != user.token_version) {
+        return Err(AppError::InvalidToken);
+    }
+    if let Some(token_id) = sender.api_token_id {
+        let now = chrono::Utc::now();
+        let tokens = repos.api_tokens.api_tokens(sender.id).await?;
+        if !tokens.iter().any(|token| token.id == token_id && token.is_active(now)) {
+            return Err(AppError::InvalidToken);
+        }
+    }
+    repos.messages.save(sender.id, message_type, message, timestamp, recipient.map(|user| user.id), upload_url).await?;
+    Ok(())
+}
+
+/// Reports a failed frame back to the client that sent it.
+fn send_error(tx: &mpsc::UnboundedSender<Message>, error: &AppError) {
+    let _ = tx.send(Message::Text(error.ws_frame().into()));
 }
@@ -0,0 +1,378 @@
+//! Drives the real router on in-memory repositories, the way clients use it.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use axum::Router;
+use axum::body::Body;
+use axum::extract::connect_info::MockConnectInfo;
+use axum::http::{header, Method, Request, StatusCode};
+use futures::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use tempfile::TempDir;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::RwLock;
+use tokio_tungstenite::tungstenite::{self, Message};
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+use tower::ServiceExt;
+
+use backend::config::Config;
+use backend::repository::Repositories;
+use backend::routes;
+use backend::ws::ChatState;
+
+const PASSWORD: &str = "violet-gravel-orbit";
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+struct TestApp {
+    router: Router,
+    repos: Repositories,
+    /// Where the router is also listening, for WebSockets.
+    addr: SocketAddr,
+    _upload_root: TempDir,
+}
+
+impl TestApp {
+    async fn start() -> Self {
+        let upload_root = tempfile::tempdir().unwrap();
+        let mut config = Config::default();
+        config.auth.jwt_secret = "an-integration-test-secret-of-enough-length".to_string();
+        config.registration.pow_difficulty = 0;
+        config.storage.root = upload_root.path().to_path_buf();
+        config.validate().unwrap();
+
+        let repos = Repositories::in_memory();
+        let (chat_state, _rx) = ChatState::new(Arc::new(config.clone()), repos.clone());
+        let router = routes::router(Arc::new(RwLock::new(chat_state)), &config, upload_root.path());
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let service = router.clone().into_make_service_with_connect_info::<SocketAddr>();
+        tokio::spawn(async move { axum::serve(listener, service).await.unwrap() });
+
+        let router = router.layer(MockConnectInfo(SocketAddr::from(([127, 0, 0, 1], 40000))));
+        Self { router, repos, addr, _upload_root: upload_root }
+    }
+
+    async fn send(&self, request: Request<Body>) -> (StatusCode, Value) {
+        let response = self.router.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+    }
+
+    async fn get(&self, path: &str, token: &str) -> (StatusCode, Value) {
+        self.send(request(Method::GET, path, token).body(Body::empty()).unwrap()).await
+    }
+
+    async fn post(&self, path: &str, token: &str, body: Value) -> (StatusCode, Value) {
+        self.send_json(Method::POST, path, token, body).await
+    }
+
+    async fn send_json(&self, method: Method, path: &str, token: &str, body: Value) -> (StatusCode, Value) {
+        let request = request(method, path, token)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        self.send(request).await
+    }
+
+    async fn register(&self, username: &str) -> Value {
+        let (status, body) = self.post("/register", "", json!({ "username": username, "password": PASSWORD })).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        body
+    }
+
+    async fn login(&self, username: &str) -> String {
+        let (status, body) = self.post("/login", "", json!({ "username": username, "password": PASSWORD })).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        body["token"].as_str().unwrap().to_string()
+    }
+
+    async fn upload(&self, token: &str, file_name: &str, data: &[u8]) -> (StatusCode, Value) {
+        let boundary = "brochat-test-boundary";
+        let mut body = format!(
+            "--{boundary}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{file_name}\"\r\n\
+             Content-Type: application/octet-stream\r\n\r\n"
+        )
+        .into_bytes();
+        body.extend_from_slice(data);
+        body.extend_from_slice(format!("\r\n--{boundary}--\r\n").as_bytes());
+
+        let request = request(Method::POST, "/api/upload", token)
+            .header(header::CONTENT_TYPE, format!("multipart/form-data; boundary={boundary}"))
+            .body(Body::from(body))
+            .unwrap();
+        self.send(request).await
+    }
+
+    async fn connect(&self, username: &str, token: &str) -> Result<Socket, tungstenite::Error> {
+        let url = format!("ws://{}/ws/{}?token={}", self.addr, username, token);
+        let (socket, _) = tokio_tungstenite::connect_async(url).await?;
+        Ok(socket)
+    }
+}
+
+fn request(method: Method, path: &str, token: &str) -> axum::http::request::Builder {
+    let builder = Request::builder().method(method).uri(path);
+    if token.is_empty() {
+        builder
+    } else {
+        builder.header(header::AUTHORIZATION, format!("Bearer {token}"))
+    }
+}
+
+/// The next frame of `message_type`, skipping join notices and the like.
+async fn next_frame(socket: &mut Socket, message_type: &str) -> Value {
+    let wait = async {
+        loop {
+            match socket.next().await {
+                Some(Ok(Message::Text(text))) => {
+                    let frame: Value = serde_json::from_str(&text).unwrap();
+                    if frame["type"] == message_type {
+                        return frame;
+                    }
+                }
+                Some(Ok(_)) => {}
+                other => panic!("socket closed while waiting for {message_type}: {other:?}"),
+            }
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(5), wait).await.expect("no frame in time")
+}
+
+async fn send_frame(socket: &mut Socket, frame: Value) {
+    socket.send(Message::Text(frame.to_string().into())).await.unwrap();
+}
+
+#[tokio::test]
+async fn registers_logs_in_and_uploads() {
+    let app = TestApp::start().await;
+
+    let account = app.register("alice").await;
+    assert_eq!(account["username"], "alice");
+    assert_eq!(account["is_bot"], false);
+    assert!(account.get("password_hash").is_none());
+
+    let (status, body) = app.post("/register", "", json!({ "username": "ALICE", "password": PASSWORD })).await;
+    assert_eq!(status, StatusCode::CONFLICT, "{body}");
+
+    let (status, body) = app.post("/login", "", json!({ "username": "alice", "password": "wrong-password" })).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED, "{body}");
+    let token = app.login("alice").await;
+
+    let (status, _) = app.upload("", "notes.txt", b"hello").await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    let (status, first) = app.upload(&token, "notes.txt", b"hello from alice").await;
+    assert_eq!(status, StatusCode::OK, "{first}");
+    assert_eq!(first["size"], 16);
+    assert_eq!(first["deduplicated"], false);
+
+    let (status, second) = app.upload(&token, "copy.txt", b"hello from alice").await;
+    assert_eq!(status, StatusCode::OK, "{second}");
+    assert_eq!(second["deduplicated"], true);
+    assert_eq!(second["upload_url"], first["upload_url"]);
+
+    let response = app
+        .router
+        .clone()
+        .oneshot(request(Method::GET, first["upload_url"].as_str().unwrap(), "").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let served = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    assert_eq!(&served[..], b"hello from alice");
+
+    let (status, storage) = app.get("/api/me/storage", &token).await;
+    assert_eq!(status, StatusCode::OK, "{storage}");
+    assert_eq!(storage["used_bytes"], 32);
+    assert_eq!(storage["largest_files"].as_array().unwrap().len(), 2);
+}
+
+#[tokio::test]
+async fn chats_and_sends_direct_messages_over_the_websocket() {
+    let app = TestApp::start().await;
+    app.register("alice").await;
+    app.register("bob").await;
+    let alice_token = app.login("alice").await;
+    let bob_token = app.login("bob").await;
+
+    match app.connect("bob", &alice_token).await {
+        Err(tungstenite::Error::Http(response)) => assert_eq!(response.status(), StatusCode::FORBIDDEN),
+        other => panic!("expected 403, got {other:?}"),
+    }
+    match app.connect("alice", "not-a-token").await {
+        Err(tungstenite::Error::Http(response)) => assert_eq!(response.status(), StatusCode::UNAUTHORIZED),
+        other => panic!("expected 401, got {other:?}"),
+    }
+
+    let mut alice = app.connect("alice", &alice_token).await.unwrap();
+    let mut bob = app.connect("bob", &bob_token).await.unwrap();
+    next_frame(&mut alice, "system").await;
+
+    send_frame(&mut alice, json!({ "type": "chat", "message": "hi all" })).await;
+    for socket in [&mut alice, &mut bob] {
+        let frame = next_frame(socket, "chat").await;
+        assert_eq!(frame["username"], "alice");
+        assert_eq!(frame["message"], "hi all");
+    }
+
+    send_frame(&mut alice, json!({ "type": "dm", "to": "BOB", "message": "just you" })).await;
+    let frame = next_frame(&mut bob, "dm").await;
+    assert_eq!(frame["from"], "alice");
+    assert_eq!(frame["message"], "just you");
+
+    send_frame(&mut alice, json!({ "type": "dm", "to": "nobody", "message": "lost" })).await;
+    assert_eq!(next_frame(&mut alice, "error").await["code"], "not_found");
+
+    let (status, public) = app.get("/public", "").await;
+    assert_eq!(status, StatusCode::OK, "{public}");
+    assert!(public.as_array().unwrap().iter().any(|m| m["message"] == "hi all"));
+
+    let (status, history) = app.get("/api/dm/alice", &bob_token).await;
+    assert_eq!(status, StatusCode::OK, "{history}");
+    let history = history.as_array().unwrap();
+    assert_eq!(history.len(), 1);
+    assert_eq!(history[0]["sender"], "alice");
+    assert_eq!(history[0]["message"], "just you");
+}
+
+#[tokio::test]
+async fn admin_endpoints_need_an_admin_session() {
+    let app = TestApp::start().await;
+    app.register("alice").await;
+    app.register("carol").await;
+    let alice_token = app.login("alice").await;
+
+    let (status, body) = app.get("/api/admin/invites", &alice_token).await;
+    assert_eq!(status, StatusCode::FORBIDDEN, "{body}");
+    let (status, _) = app.get("/api/admin/invites", "").await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    app.repos.users.set_role("carol", "admin").await.unwrap();
+    let admin_token = app.login("carol").await;
+
+    let (status, invite) = app.post("/api/admin/invites", &admin_token, json!({ "max_uses": 2 })).await;
+    assert_eq!(status, StatusCode::OK, "{invite}");
+    assert!(invite["code"].is_string());
+    let (status, invites) = app.get("/api/admin/invites", &admin_token).await;
+    assert_eq!(status, StatusCode::OK, "{invites}");
+    assert_eq!(invites["invites"].as_array().unwrap().len(), 1);
+
+    let (status, bot) = app.post("/api/admin/bots", &admin_token, json!({ "username": "helper" })).await;
+    assert_eq!(status, StatusCode::OK, "{bot}");
+    assert_eq!(bot["is_bot"], true);
+    let (status, created) = app
+        .post("/api/admin/users/helper/tokens", &admin_token, json!({ "name": "ci", "scopes": ["messages:read"] }))
+        .await;
+    assert_eq!(status, StatusCode::OK, "{created}");
+    let bot_token = created["token"].as_str().unwrap();
+    let (status, me) = app.get("/api/me", bot_token).await;
+    assert_eq!(status, StatusCode::OK, "{me}");
+    assert_eq!(me["username"], "helper");
+    let (status, body) = app.get("/api/admin/invites", bot_token).await;
+    assert_eq!(status, StatusCode::FORBIDDEN, "{body}");
+    let (status, body) = app.post("/api/admin/bots", bot_token, json!({ "username": "other" })).await;
+    assert_eq!(status, StatusCode::FORBIDDEN, "{body}");
+
+    let (status, body) = app
+        .send_json(Method::PUT, "/api/admin/users/alice/quota", &admin_token, json!({ "quota_bytes": 10 }))
+        .await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    let (status, storage) = app.get("/api/admin/users/alice/storage", &admin_token).await;
+    assert_eq!(status, StatusCode::OK, "{storage}");
+    assert_eq!(storage["quota_bytes"], 10);
+    let (status, body) = app.upload(&alice_token, "big.txt", b"more than ten bytes").await;
+    assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE, "{body}");
+}
+
+#[tokio::test]
+async fn open_sockets_follow_a_rename() {
+    let app = TestApp::start().await;
+    app.register("alice").await;
+    app.register("bob").await;
+    let alice_token = app.login("alice").await;
+    let bob_token = app.login("bob").await;
+
+    let mut alice = app.connect("alice", &alice_token).await.unwrap();
+    let mut bob = app.connect("bob", &bob_token).await.unwrap();
+    next_frame(&mut alice, "system").await;
+
+    let (status, body) = app.send_json(Method::PUT, "/api/me/username", &alice_token, json!({ "username": "alicia" })).await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    assert_eq!(next_frame(&mut bob, "system").await["message"], "alice is now alicia");
+
+    send_frame(&mut alice, json!({ "type": "chat", "message": "new name" })).await;
+    assert_eq!(next_frame(&mut bob, "chat").await["username"], "alicia");
+
+    send_frame(&mut alice, json!({ "type": "dm", "to": "bob", "message": "still me" })).await;
+    assert_eq!(next_frame(&mut bob, "dm").await["from"], "alicia");
+
+    alice.close(None).await.unwrap();
+    assert_eq!(next_frame(&mut bob, "system").await["message"], "alicia left");
+}
+
+#[tokio::test]
+async fn revoking_a_token_closes_its_sockets() {
+    let app = TestApp::start().await;
+    app.register("carol").await;
+    app.repos.users.set_role("carol", "admin").await.unwrap();
+    let admin_token = app.login("carol").await;
+
+    app.post("/api/admin/bots", &admin_token, json!({ "username": "helper" })).await;
+    let scopes = json!(["messages:read", "messages:write"]);
+    let (status, created) = app
+        .post("/api/admin/users/helper/tokens", &admin_token, json!({ "name": "ci", "scopes": scopes }))
+        .await;
+    assert_eq!(status, StatusCode::OK, "{created}");
+
+    let mut admin = app.connect("carol", &admin_token).await.unwrap();
+    let mut bot = app.connect("helper", created["token"].as_str().unwrap()).await.unwrap();
+    next_frame(&mut admin, "system").await;
+    send_frame(&mut bot, json!({ "type": "chat", "message": "beep" })).await;
+    let frame = next_frame(&mut admin, "chat").await;
+    assert_eq!(frame["username"], "helper");
+    assert_eq!(frame["bot"], true);
+
+    let path = format!("/api/admin/tokens/{}", created["api_token"]["id"].as_str().unwrap());
+    let (status, body) = app.send_json(Method::DELETE, &path, &admin_token, json!({})).await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    assert_eq!(next_frame(&mut bot, "error").await["code"], "invalid_token");
+    let closed = tokio::time::timeout(Duration::from_secs(5), bot.next()).await.unwrap();
+    assert!(matches!(closed, Some(Ok(Message::Close(_))) | None), "{closed:?}");
+    assert_eq!(next_frame(&mut admin, "system").await["message"], "helper left");
+
+    // A token revoked behind the socket's back is caught on the next message
+    let (_, created) = app
+        .post("/api/admin/users/helper/tokens", &admin_token, json!({ "name": "ci", "scopes": scopes }))
+        .await;
+    let mut bot = app.connect("helper", created["token"].as_str().unwrap()).await.unwrap();
+    let token_id = created["api_token"]["id"].as_str().unwrap().parse().unwrap();
+    assert!(app.repos.api_tokens.revoke_api_token(token_id, None).await.unwrap());
+    send_frame(&mut bot, json!({ "type": "chat", "message": "still here?" })).await;
+    assert_eq!(next_frame(&mut bot, "error").await["code"], "invalid_token");
+}
+
+#[tokio::test]
+async fn changing_the_password_closes_open_sockets() {
+    let app = TestApp::start().await;
+    app.register("alice").await;
+    let token = app.login("alice").await;
+    let mut socket = app.connect("alice", &token).await.unwrap();
+
+    let new_password = json!({ "current_password": PASSWORD, "new_password": "quartz-meadow-lantern-7" });
+    let (status, body) = app.send_json(Method::PUT, "/api/me/password", &token, new_password).await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    assert_eq!(next_frame(&mut socket, "error").await["code"], "invalid_token");
+    let closed = tokio::time::timeout(Duration::from_secs(5), socket.next()).await.unwrap();
+    assert!(matches!(closed, Some(Ok(Message::Close(_))) | None), "{closed:?}");
+
+    // Also when the password is set outside the server, like brochat-admin does
+    let token = body["token"].as_str().unwrap();
+    let mut socket = app.connect("alice", token).await.unwrap();
+    app.repos.users.set_password("alice", "not-a-real-hash").await.unwrap();
+    send_frame(&mut socket, json!({ "type": "chat", "message": "still here?" })).await;
+    assert_eq!(next_frame(&mut socket, "error").await["code"], "invalid_token");
+}